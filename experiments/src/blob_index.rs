@@ -0,0 +1,79 @@
+use std::path::PathBuf;
+
+use anyhow::Error;
+use clap::Parser;
+use planetiler::blob_index::{BlobIndex, EntityKind, IdRange};
+use planetiler::pbf::is_declared_sorted;
+use separator::Separatable;
+
+use crate::utils::{print_osm_header, timed};
+
+#[derive(Debug, Parser)]
+pub struct OptsBlobIndex {
+    /// Input pbf data.
+    pbf_file: PathBuf,
+
+    /// Blob index file, reused if it was built from the same pbf file.
+    /// Defaults to the pbf file name with the `.blobs` suffix.
+    index_file: Option<PathBuf>,
+
+    /// Report and skip blobs that cannot be decoded instead of stopping
+    #[clap(long)]
+    skip_bad_blobs: bool,
+}
+
+pub fn run(args: OptsBlobIndex) -> Result<(), Error> {
+    print_osm_header(&args.pbf_file);
+    let index_file = args.index_file.unwrap_or_else(|| {
+        let mut path = args.pbf_file.as_os_str().to_owned();
+        path.push(".blobs");
+        PathBuf::from(path)
+    });
+    let index = timed("Blob index", || {
+        BlobIndex::load_or_build(&args.pbf_file, &index_file, args.skip_bad_blobs)
+    })?;
+    println!(
+        "{} blobs in {}, index saved to {}",
+        index.entries().len().separated_string(),
+        args.pbf_file.display(),
+        index_file.display()
+    );
+    if index.bad_blobs() > 0 {
+        println!("Skipped {} bad blobs", index.bad_blobs().separated_string());
+    }
+    for kind in [EntityKind::Node, EntityKind::Way, EntityKind::Relation] {
+        let mut count = 0;
+        let mut ids: Option<IdRange> = None;
+        for entry in index.blobs_with(kind) {
+            let range = entry.ids(kind).unwrap();
+            count += 1;
+            ids = Some(match ids {
+                Some(r) => IdRange {
+                    min: r.min.min(range.min),
+                    max: r.max.max(range.max),
+                },
+                None => range,
+            });
+        }
+        match (ids, index.first_offset(kind)) {
+            (Some(ids), Some(offset)) => println!(
+                "{kind:?}: {} blobs starting at offset {}, IDs {}..={}",
+                count.separated_string(),
+                offset.separated_string(),
+                ids.min.separated_string(),
+                ids.max.separated_string()
+            ),
+            _ => println!("{kind:?}: none"),
+        }
+    }
+    let declared = if is_declared_sorted(&args.pbf_file)? {
+        "declared"
+    } else {
+        "not declared"
+    };
+    match index.check_sorted() {
+        Ok(()) => println!("Sorted by type then ID, {declared} in the header"),
+        Err(e) => println!("{e}, {declared} in the header"),
+    }
+    Ok(())
+}
@@ -1,13 +1,10 @@
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
-use std::sync::mpsc::channel;
 
-use crate::utils::{advise_cache, spawn_stats_aggregator, NodeStats, OptAdvice};
-use anyhow::Error;
+use crate::utils::{print_osm_header, NodeCacheKind, OptAdvice};
+use anyhow::{bail, Error};
 use clap::Parser;
-use osmnodecache::{CacheStore, DenseFileCacheOpts};
-use osmpbf::{BlobDecode, BlobReader};
-use rayon::iter::{ParallelBridge, ParallelIterator};
+use planetiler::cache_meta::NodeCacheMeta;
+use planetiler::node_cache::{CacheKind, NodeCacheBuilder, NodeCacheInfo};
 
 #[derive(Debug, Parser)]
 pub struct OptsCacheNodes {
@@ -17,68 +14,88 @@ pub struct OptsCacheNodes {
     /// File for planet-size node cache.
     node_cache: PathBuf,
 
+    /// Dense cache is sized by the max node ID, sparse by the node count,
+    /// compressed is the smallest but slowest. Auto picks dense or sparse based on the node IDs.
+    #[clap(long, arg_enum, default_value = "auto")]
+    cache_kind: NodeCacheKind,
+
     #[clap(flatten)]
     advice: OptAdvice,
+
+    /// Report and skip blobs that cannot be decoded instead of stopping
+    #[clap(long)]
+    skip_bad_blobs: bool,
 }
 
 pub fn run(args: OptsCacheNodes) -> Result<(), Error> {
-    parse_nodes(&args.pbf_file, args.node_cache, &args.advice)?;
+    print_osm_header(&args.pbf_file);
+    parse_nodes(
+        &args.pbf_file,
+        args.node_cache,
+        args.cache_kind.into(),
+        &args.advice,
+        args.skip_bad_blobs,
+    )?;
     Ok(())
 }
 
-/// Create a flat node cache file.
-/// Returns offset of the first block with ways or relations
+/// Create a node cache file, and its metadata file next to it.
 pub fn parse_nodes(
     pbf_file: &Path,
     node_cache_file: PathBuf,
+    kind: CacheKind,
     advice: &OptAdvice,
-) -> Result<u64, Error> {
-    let cache = DenseFileCacheOpts::new(node_cache_file)
-        .page_size(10 * 1024 * 1024 * 1024)
-        .open()?;
-
-    advise_cache(&cache, advice)?;
-
-    let first_way_block = AtomicU64::new(u64::MAX);
-    let (sender, receiver) = channel();
-    let stats_collector = spawn_stats_aggregator("Nodes to cache file", receiver);
-
-    // Read PBF file using multiple threads, and in each thread store node positions into cache
-    BlobReader::from_path(pbf_file)?.par_bridge().for_each_with(
-        (cache, sender),
-        |(dfc, sender), blob| {
-            let mut cache = dfc.get_accessor();
-            let mut stats = NodeStats::default();
-            let blob = blob.unwrap();
-            if let BlobDecode::OsmData(block) = blob.decode().unwrap() {
-                let mut blob_has_ways = false;
-                for group in block.groups() {
-                    for node in group.nodes() {
-                        let lat = node.lat();
-                        let lon = node.lon();
-                        cache.set_lat_lon(node.id() as usize, lat, lon);
-                        stats.add_node(node.id(), lat, lon);
-                    }
-                    for node in group.dense_nodes() {
-                        let lat = node.lat();
-                        let lon = node.lon();
-                        cache.set_lat_lon(node.id() as usize, lat, lon);
-                        stats.add_node(node.id(), lat, lon);
-                    }
-                    // TBD: is this the quickest way to test for empty?
-                    if group.ways().next().is_some() || group.relations().next().is_some() {
-                        blob_has_ways = true;
-                    }
-                }
-                if blob_has_ways {
-                    first_way_block.fetch_min(blob.offset().unwrap().0, Relaxed);
-                }
-            };
-            sender.send(stats).unwrap();
-        },
+    skip_bad_blobs: bool,
+) -> Result<NodeCacheInfo, Error> {
+    let mut builder = NodeCacheBuilder::new(pbf_file, node_cache_file)
+        .kind(kind)
+        .skip_bad_blobs(skip_bad_blobs);
+    for adv in advice.values() {
+        println!("Advising memmap as {adv:?}");
+        builder = builder.advice(adv);
+    }
+    let info = builder.build()?;
+    println!(
+        "Nodes to {:?} cache file results: {:#?}",
+        info.kind, info.stats
     );
+    if info.bad_blobs > 0 {
+        println!("Skipped {} bad blobs", info.bad_blobs);
+    }
+    Ok(info)
+}
 
-    stats_collector.join().unwrap();
-
-    Ok(first_way_block.load(Relaxed))
+/// Reuse the node cache if its metadata shows it was built from the same PBF file
+/// with the requested kind, otherwise create it with [`parse_nodes`].
+/// A cache built from a different PBF file is only replaced if `rebuild` is set.
+pub fn reuse_or_parse_nodes(
+    pbf_file: &Path,
+    node_cache_file: PathBuf,
+    kind: CacheKind,
+    advice: &OptAdvice,
+    skip_bad_blobs: bool,
+    rebuild: bool,
+) -> Result<NodeCacheInfo, Error> {
+    if !rebuild && node_cache_file.exists() {
+        if let Some(meta) = NodeCacheMeta::read(&node_cache_file)? {
+            if !meta.matches(pbf_file)? {
+                bail!(
+                    "Node cache {} was built from a different file {}, \
+                     use --rebuild-cache to replace it",
+                    node_cache_file.display(),
+                    meta.pbf_file.display()
+                );
+            }
+            if kind == CacheKind::Auto || kind == meta.kind {
+                println!(
+                    "Reusing {:?} node cache {} with {} nodes",
+                    meta.kind,
+                    node_cache_file.display(),
+                    meta.stats.node_count
+                );
+                return Ok(meta.info());
+            }
+        }
+    }
+    parse_nodes(pbf_file, node_cache_file, kind, advice, skip_bad_blobs)
 }
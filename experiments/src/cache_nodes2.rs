@@ -6,13 +6,14 @@ use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering::Relaxed};
 use std::sync::mpsc::channel;
 
-use crate::utils::{advise_cache, spawn_stats_aggregator, NodeStats, OptAdvice};
+use crate::utils::{advise_cache, print_osm_header, spawn_stats_aggregator, OptAdvice};
 use anyhow::Error;
-use anyhow::Result;
+use anyhow::{bail, ensure, Context, Result};
 use clap::Parser;
 use osmnodecache::{CacheStore, DenseFileCacheOpts};
 use osmpbf::{Blob, BlobDecode, BlobReader};
 use pariter::{scope, IteratorExt};
+use planetiler::pbf::is_declared_sorted;
 use rayon::iter::{ParallelBridge, ParallelIterator};
 use separator::{usize, Separatable};
 use zerocopy::{insert_vec_zeroed, AsBytes, LittleEndian, U64};
@@ -27,6 +28,7 @@ pub struct OptsCacheNodes2 {
 }
 
 pub fn run(args: OptsCacheNodes2) -> Result<()> {
+    print_osm_header(&args.pbf_file);
     parse_nodes(&args.pbf_file, args.node_cache)?;
     Ok(())
 }
@@ -34,6 +36,7 @@ pub fn run(args: OptsCacheNodes2) -> Result<()> {
 /// Create a flat node cache file using block approach
 /// Returns offset of the first block with ways or relations
 pub fn parse_nodes(pbf_file: &Path, node_cache_file: PathBuf) -> Result<u64> {
+    ensure_sorted(pbf_file)?;
     let mut cache = OpenOptions::new()
         .read(true)
         .write(true)
@@ -46,17 +49,16 @@ pub fn parse_nodes(pbf_file: &Path, node_cache_file: PathBuf) -> Result<u64> {
     scope(|s| {
         let reader = BlobReader::from_path(pbf_file)?;
         reader
-            .parallel_map_scoped(s, |blob| parse_blob(&first_way_block, &blob.unwrap()))
-            .for_each(|v| match v {
-                Some((data, starts_at)) => {
-                    cache.seek(SeekFrom::Start(starts_at as u64)).unwrap();
-                    cache.write(data.as_bytes()).unwrap();
+            .parallel_map_scoped(s, |blob| parse_blob(&first_way_block, &blob?))
+            .try_for_each(|v| -> Result<()> {
+                if let Some((data, starts_at)) = v? {
+                    cache.seek(SeekFrom::Start(starts_at as u64))?;
+                    cache.write_all(data.as_bytes())?;
                     sum_block_size.fetch_add(data.len(), Relaxed);
                     block_count.fetch_add(1, Relaxed);
                 }
-                None => {}
-            });
-        Result::<()>::Ok(())
+                Ok(())
+            })
     })
     .unwrap()?;
 
@@ -72,53 +74,65 @@ pub fn parse_nodes(pbf_file: &Path, node_cache_file: PathBuf) -> Result<u64> {
     Ok(first_way_block.load(Relaxed))
 }
 
+/// Blocks are written at the position of their first node ID, zero filling the gaps,
+/// so they only stay apart if the nodes of the file are sorted by ID.
+pub fn ensure_sorted(pbf_file: &Path) -> Result<()> {
+    ensure!(
+        is_declared_sorted(pbf_file)?,
+        "{} is not declared sorted by type then ID, use cache-nodes to sort its nodes in memory",
+        pbf_file.display()
+    );
+    Ok(())
+}
+
 pub fn parse_blob(
     first_way_block: &AtomicU64,
     blob: &Blob,
-) -> Option<(Vec<U64<LittleEndian>>, usize)> {
-    if let BlobDecode::OsmData(block) = blob.decode().unwrap() {
+) -> Result<Option<(Vec<U64<LittleEndian>>, usize)>> {
+    let offset = blob.offset().context("Unable to get offset of a blob")?.0;
+    let decoded = blob
+        .decode()
+        .with_context(|| format!("Unable to decode the blob at offset {offset}"))?;
+    if let BlobDecode::OsmData(block) = decoded {
         let mut blob_has_ways = false;
-        let mut first_index = 0_usize;
-        let mut last_index = 0_usize;
-        let mut result: Vec<U64<LittleEndian>> = Vec::new(); // 1*1024*1024 ?
-        let mut add_node = |id, lat, lon| {
-            assert!(id > last_index);
-            last_index = id;
-            if first_index == 0 {
-                first_index = id;
-            }
-            let relative_id = id - first_index;
-            let exists = result.len();
-            let needed = relative_id - exists;
-            if needed > 0 {
-                insert_vec_zeroed(&mut result, exists, needed);
-            }
-            result.push(U64::new((lat as u64) << 32 | lon as u64));
-        };
+        let mut nodes: Vec<(usize, u64)> = Vec::new();
         for group in block.groups() {
             for node in group.nodes() {
-                add_node(
-                    node.id() as usize,
-                    node.decimicro_lat(),
-                    node.decimicro_lon(),
-                );
+                let value = (node.decimicro_lat() as u64) << 32 | node.decimicro_lon() as u64;
+                nodes.push((node.id() as usize, value));
             }
             for node in group.dense_nodes() {
-                add_node(
-                    node.id() as usize,
-                    node.decimicro_lat(),
-                    node.decimicro_lon(),
-                );
+                let value = (node.decimicro_lat() as u64) << 32 | node.decimicro_lon() as u64;
+                nodes.push((node.id() as usize, value));
             }
             if group.ways().next().is_some() || group.relations().next().is_some() {
                 blob_has_ways = true;
             }
         }
+        if let Some(w) = nodes.windows(2).find(|w| w[0].0 >= w[1].0) {
+            bail!(
+                "The PBF file is not sorted by type then ID: node {} in the blob at offset \
+                 {offset} comes after node {}",
+                w[1].0,
+                w[0].0
+            );
+        }
+        let first_index = nodes.first().map_or(0, |(id, _)| *id);
+        let mut result: Vec<U64<LittleEndian>> = Vec::new(); // 1*1024*1024 ?
+        for (id, value) in nodes {
+            let relative_id = id - first_index;
+            let exists = result.len();
+            let needed = relative_id - exists;
+            if needed > 0 {
+                insert_vec_zeroed(&mut result, exists, needed);
+            }
+            result.push(U64::new(value));
+        }
         if blob_has_ways {
-            first_way_block.fetch_min(blob.offset().unwrap().0, Relaxed);
+            first_way_block.fetch_min(offset, Relaxed);
         }
-        Some((result, first_index))
+        Ok(Some((result, first_index)))
     } else {
-        None
+        Ok(None)
     }
 }
@@ -6,8 +6,8 @@ use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering::Relaxed};
 use std::sync::mpsc::channel;
 
-use crate::cache_nodes2::parse_blob;
-use crate::utils::{advise_cache, spawn_stats_aggregator, NodeStats, OptAdvice};
+use crate::cache_nodes2::{ensure_sorted, parse_blob};
+use crate::utils::{advise_cache, print_osm_header, spawn_stats_aggregator, OptAdvice};
 use crate::OptsCacheNodes2;
 use anyhow::Error;
 use anyhow::Result;
@@ -20,6 +20,7 @@ use separator::{usize, Separatable};
 use zerocopy::{insert_vec_zeroed, AsBytes, LittleEndian, U64};
 
 pub fn run(args: OptsCacheNodes2) -> Result<()> {
+    print_osm_header(&args.pbf_file);
     parse_nodes(&args.pbf_file, args.node_cache)?;
     Ok(())
 }
@@ -27,25 +28,26 @@ pub fn run(args: OptsCacheNodes2) -> Result<()> {
 /// Create a flat node cache file using block approach
 /// Returns offset of the first block with ways or relations
 pub fn parse_nodes(pbf_file: &Path, node_cache_file: PathBuf) -> Result<u64> {
+    ensure_sorted(pbf_file)?;
     let first_way_block = AtomicU64::new(u64::MAX);
     let sum_block_size = AtomicUsize::default();
     let block_count = AtomicU64::default();
 
     let reader = BlobReader::from_path(pbf_file)?;
-    reader.par_bridge().for_each(|blob| {
-        if let Some((data, starts_at)) = parse_blob(&first_way_block, &blob.unwrap()) {
+    reader.par_bridge().try_for_each(|blob| -> Result<()> {
+        if let Some((data, starts_at)) = parse_blob(&first_way_block, &blob?)? {
             let mut cache = OpenOptions::new()
                 .read(true)
                 .write(true)
                 .create(true)
-                .open(&node_cache_file)
-                .unwrap();
-            cache.seek(SeekFrom::Start(starts_at as u64)).unwrap();
-            cache.write(data.as_bytes()).unwrap();
+                .open(&node_cache_file)?;
+            cache.seek(SeekFrom::Start(starts_at as u64))?;
+            cache.write_all(data.as_bytes())?;
             sum_block_size.fetch_add(data.len(), Relaxed);
             block_count.fetch_add(1, Relaxed);
         }
-    });
+        Ok(())
+    })?;
 
     let sum = sum_block_size.load(Relaxed);
     let count = block_count.load(Relaxed);
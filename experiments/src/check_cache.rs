@@ -0,0 +1,114 @@
+use std::path::PathBuf;
+
+use anyhow::{bail, Error};
+use clap::Parser;
+use planetiler::cache_meta::NodeCacheMeta;
+use planetiler::node_cache::{check_cache, NodeLookup};
+use separator::Separatable;
+
+use crate::utils::{open_node_cache, print_osm_header, timed, OptAdvice};
+
+#[derive(Debug, Parser)]
+pub struct OptsCheckCache {
+    /// Node cache file to check.
+    node_cache: PathBuf,
+
+    /// Input pbf data the cache was created from. Without it, only --dump is done.
+    pbf_file: Option<PathBuf>,
+
+    /// Print cached positions of these comma separated node IDs
+    #[clap(long, use_delimiter = true)]
+    dump: Vec<i64>,
+
+    #[clap(flatten)]
+    advice: OptAdvice,
+
+    /// Report and skip blobs that cannot be decoded instead of stopping
+    #[clap(long)]
+    skip_bad_blobs: bool,
+}
+
+pub fn run(args: OptsCheckCache) -> Result<(), Error> {
+    let cache = open_node_cache(&args.node_cache, &args.advice)?;
+    println!(
+        "{:?} node cache {}",
+        cache.kind(),
+        args.node_cache.display()
+    );
+    match NodeCacheMeta::read(&args.node_cache)? {
+        Some(meta) => println!(
+            "Built from {} ({} bytes), first way block at {}, first relation block at {}",
+            meta.pbf_file.display(),
+            meta.pbf_size.separated_string(),
+            meta.first_way_block,
+            meta.first_relation_block
+        ),
+        None => println!(
+            "Metadata file {} is missing",
+            NodeCacheMeta::path(&args.node_cache).display()
+        ),
+    }
+    if !args.dump.is_empty() {
+        let reader = cache.reader();
+        for id in &args.dump {
+            match reader.get_node(*id) {
+                Some((lat, lng)) => println!("{id:>14}  lat={lat:.7} lng={lng:.7}"),
+                None => println!("{id:>14}  not in the cache"),
+            }
+        }
+    }
+    let pbf_file = match args.pbf_file {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+    drop(cache);
+    print_osm_header(&pbf_file);
+    NodeCacheMeta::read_for(&args.node_cache, &pbf_file)?;
+
+    let (info, check) = timed("Node cache checked", || {
+        check_cache(
+            &pbf_file,
+            &args.node_cache,
+            &args.advice.values(),
+            args.skip_bad_blobs,
+        )
+    })?;
+    let stats = &info.stats;
+    println!(
+        "Checked {} nodes with IDs {}..={}, {:.1}% of the ID range is used",
+        stats.node_count.separated_string(),
+        stats.min_node_id.separated_string(),
+        stats.max_node_id.separated_string(),
+        stats.id_fill_ratio() * 100.0
+    );
+    println!(
+        "Unset: {}, mismatched: {}",
+        check.unset.separated_string(),
+        check.mismatches.separated_string()
+    );
+    if info.bad_blobs > 0 {
+        println!("Skipped {} bad blobs", info.bad_blobs);
+    }
+    if check.truncated {
+        println!("Cache file is too short for the largest node ID, it is likely truncated");
+    }
+    for node in &check.samples {
+        let cached = match node.cached {
+            Some((lat, lng)) => format!("cached lat={lat:.7} lng={lng:.7}"),
+            None => "not cached".to_string(),
+        };
+        println!(
+            "{:>14}  expected lat={:.7} lng={:.7}, {cached}",
+            node.id, node.expected.0, node.expected.1
+        );
+    }
+    if !check.is_ok() {
+        bail!(
+            "Node cache {} does not match {}",
+            args.node_cache.display(),
+            pbf_file.display()
+        );
+    }
+    println!("Node cache is valid");
+    Ok(())
+}
@@ -1,24 +1,32 @@
-use std::ops::AddAssign;
+use std::ops::{AddAssign, Range};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
-use std::sync::mpsc::channel;
+use std::sync::mpsc::{channel, Sender};
+use std::time::{Duration, Instant};
 
-use crate::timed;
-use anyhow::Error;
+use anyhow::{Context, Error};
 use clap::{ArgEnum, Parser};
-use osmnodecache::{CacheStore, DenseFileCache};
-use osmpbf::{BlobDecode, BlobReader, ByteOffset};
-use rayon::iter::{ParallelBridge, ParallelIterator};
+use geos::{CoordSeq, Geometry};
+use planetiler::blob_index::{BlobIndex, EntityKind};
+use planetiler::cache_meta::NodeCacheMeta;
+use planetiler::node_cache::NodeLookup;
+use planetiler::way_parts::{MergedWay, MergedWays, WayPart, WayPartsWriter};
+use planetiler::ways::{MissingNodeStats, MissingNodes, WayCoords, WayReader};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use separator::Separatable;
 
-// use geos::{CoordSeq, GResult, Geom, Geometry};
+use crate::utils::{print_osm_header, spawn_stats_aggregator, BBox, MissingNodePolicy, OptAdvice};
 
-use crate::utils::{advise_cache, spawn_stats_aggregator, OptAdvice};
+/// Number of merged ways whose geometries are built in parallel at a time
+const ASSEMBLE_BATCH: usize = 10_000;
 
 #[derive(Debug, Parser)]
 pub struct OptsChunkedResolver {
+    /// * Skip - resolve each way in the pass of its largest node ID, reading the rest of
+    ///   its nodes outside of the slice
+    /// * Assemble - resolve only the nodes inside of the slice, store partial ways on disk,
+    ///   and assemble ways spanning several slices after the last pass
     #[clap(arg_enum)]
-    /// Skip - ignore ways whose IDs didn't fit into slice
     mode: Mode,
 
     /// Input pbf data.
@@ -32,200 +40,494 @@ pub struct OptsChunkedResolver {
     /// followed by 1*1024*1024*1024/8..2*1024*1024*1024/8-1, etc.
     mem_slice: usize,
 
+    /// What to do with ways using nodes missing from the cache, e.g. at the edges of an extract
+    #[clap(long, arg_enum, default_value = "drop")]
+    missing_nodes: MissingNodePolicy,
+
+    /// Directory for the partial ways of each pass in the assemble mode.
+    /// Defaults to the directory of the node cache.
+    #[clap(long)]
+    parts_dir: Option<PathBuf>,
+
+    /// Blob index file, built on the first run. With it, each pass reads only the blobs with ways.
+    #[clap(long)]
+    blob_index: Option<PathBuf>,
+
     #[clap(flatten)]
     advice: OptAdvice,
+
+    /// Report and skip blobs that cannot be decoded instead of stopping
+    #[clap(long)]
+    skip_bad_blobs: bool,
 }
 
-#[derive(ArgEnum, Debug, Clone, Copy)]
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
 enum Mode {
     Skip,
+    Assemble,
 }
 
 #[derive(Clone, Default, Debug)]
 struct Stats {
-    pub ways_viewed: usize,
+    pub blobs: usize,
+    /// Ways with all nodes resolved, and their geometry built
     pub ways_resolved: usize,
+    /// Ways with nodes after the current slice, left for later passes
+    pub ways_deferred: usize,
+    /// Ways spanning several slices with some nodes in the current slice, stored as way parts
+    pub ways_partial: usize,
     pub nodes_resolved: usize,
     pub empty_ways: usize,
+    /// Ways whose geometry could not be built
     pub errors: usize,
+    /// Ways dropped because of missing nodes, see `--missing-nodes`
     pub skipped: usize,
-    pub min_latitude: f64,
-    pub max_latitude: f64,
-    pub min_longitude: f64,
-    pub max_longitude: f64,
+    pub bad_blobs: usize,
+    pub missing: MissingNodeStats,
+    pub bbox: BBox,
+}
+
+/// What a pass does with a way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WayAction {
+    /// Resolve the way and build its geometry
+    Resolve,
+    /// Store the nodes inside of the slice as way parts, assembled after the last pass
+    StoreParts,
+    /// Nothing to resolve in this pass
+    Ignore,
 }
 
 impl Stats {
-    fn add_point(&mut self, lat: f64, lng: f64) {
-        self.nodes_resolved += 1;
-        self.min_latitude = self.min_latitude.min(lat);
-        self.max_latitude = self.max_latitude.max(lat);
-        self.min_longitude = self.min_longitude.min(lng);
-        self.max_longitude = self.max_longitude.max(lng);
+    /// Decide what the pass over the node IDs in `slice` does with a way whose smallest and
+    /// largest node IDs are `first` and `last`. Only ways that a later pass still needs are
+    /// counted as deferred.
+    fn plan_way(
+        &mut self,
+        mode: Mode,
+        slice: &Range<i64>,
+        (first, last): (i64, i64),
+        mut refs: impl Iterator<Item = i64>,
+    ) -> WayAction {
+        if last >= slice.end {
+            self.ways_deferred += 1;
+        }
+        match mode {
+            Mode::Skip if slice.contains(&last) => WayAction::Resolve,
+            Mode::Assemble if slice.contains(&first) && slice.contains(&last) => WayAction::Resolve,
+            Mode::Assemble if refs.any(|id| slice.contains(&id)) => {
+                self.ways_partial += 1;
+                WayAction::StoreParts
+            }
+            _ => WayAction::Ignore,
+        }
+    }
+
+    /// Count the missing nodes of an assembled way, and build its geometry unless it is skipped.
+    fn add_way(&mut self, way_id: i64, way: WayCoords) {
+        let skipped = way.coords.is_none();
+        self.missing.add_way(way_id, way.missing, skipped);
+        match way.coords {
+            Some(coords) => self.add_coords(coords),
+            None => self.skipped += 1,
+        }
+    }
+
+    /// Build the geometry of a way whose nodes have all been resolved.
+    fn add_coords(&mut self, coords: Vec<[f64; 2]>) {
+        let geometry = CoordSeq::new_from_vec(&coords).and_then(Geometry::create_line_string);
+        if geometry.is_err() {
+            self.errors += 1;
+            return;
+        }
+        for [lng, lat] in coords {
+            self.nodes_resolved += 1;
+            self.bbox.add_point(lat, lng);
+        }
+        self.ways_resolved += 1;
     }
 }
 
 impl AddAssign for Stats {
     fn add_assign(&mut self, other: Self) {
+        let mut missing = std::mem::take(&mut self.missing);
+        missing += other.missing;
         *self = Self {
-            ways_viewed: self.ways_viewed + other.ways_viewed,
+            blobs: self.blobs + other.blobs,
             ways_resolved: self.ways_resolved + other.ways_resolved,
+            ways_deferred: self.ways_deferred + other.ways_deferred,
+            ways_partial: self.ways_partial + other.ways_partial,
             nodes_resolved: self.nodes_resolved + other.nodes_resolved,
             empty_ways: self.empty_ways + other.empty_ways,
             errors: self.errors + other.errors,
             skipped: self.skipped + other.skipped,
-            min_latitude: self.min_latitude + other.min_latitude,
-            max_latitude: self.max_latitude + other.max_latitude,
-            min_longitude: self.min_longitude + other.min_longitude,
-            max_longitude: self.max_longitude + other.max_longitude,
+            bad_blobs: self.bad_blobs + other.bad_blobs,
+            missing,
+            bbox: self.bbox.union(other.bbox),
         };
     }
 }
 
+/// Blobs with ways according to the blob index.
+struct WayBlobs {
+    offsets: Vec<u64>,
+    /// Total size of the blobs
+    bytes: u64,
+}
+
+/// Results of one pass over the ways, used to tune `mem_slice`.
+struct PassReport {
+    /// Node IDs resolved by this pass, or None for the final assembly of way parts
+    node_ids: Option<Range<i64>>,
+    /// Bytes of the PBF blobs read by a pass, or of the way parts files read by the assembly
+    bytes_read: u64,
+    elapsed: Duration,
+    stats: Stats,
+}
+
+impl PassReport {
+    fn print(&self, pass: usize) {
+        let (name, input) = match &self.node_ids {
+            Some(ids) => (
+                format!(
+                    "Pass {pass} for node IDs {}..{}",
+                    ids.start.separated_string(),
+                    ids.end.separated_string()
+                ),
+                "of PBF blobs",
+            ),
+            None => ("Assembly of way parts".to_string(), "of way parts"),
+        };
+        println!(
+            "{name}: {} ways resolved, {} deferred, {} partial, {} errors, {} skipped, \
+             {} blobs, {} MB {input} read in {:.1} seconds",
+            self.stats.ways_resolved.separated_string(),
+            self.stats.ways_deferred.separated_string(),
+            self.stats.ways_partial.separated_string(),
+            self.stats.errors.separated_string(),
+            self.stats.skipped.separated_string(),
+            self.stats.blobs.separated_string(),
+            (self.bytes_read / 1024 / 1024).separated_string(),
+            self.elapsed.as_secs_f32()
+        );
+    }
+}
+
 pub fn run(args: OptsChunkedResolver) -> Result<(), Error> {
-    let cache = DenseFileCache::new(args.node_cache)?;
-    advise_cache(&cache, &args.advice)?;
+    print_osm_header(&args.pbf_file);
     let mut start_idx = 0;
     let chunk_size = (args.mem_slice * 1024 * 1024 * 1024 / 8) as i64;
     let max_node_id = AtomicI64::new(0);
-    let first_way_block = AtomicU64::new(u64::MAX);
+    // Skip the node blocks from the first pass if the cache metadata knows where ways start
+    let meta = NodeCacheMeta::read_for(&args.node_cache, &args.pbf_file)?;
+    let first_way_block = AtomicU64::new(meta.map_or(u64::MAX, |m| m.first_way_block));
+    let way_blobs = match &args.blob_index {
+        Some(path) => {
+            let index = BlobIndex::load_or_build(&args.pbf_file, path, args.skip_bad_blobs)?;
+            if index.bad_blobs() > 0 {
+                // Their ways cannot be read, so the indexed way blobs leave them out
+                println!("Skipped {} bad blobs", index.bad_blobs().separated_string());
+            }
+            let offsets: Vec<u64> = index
+                .blobs_with(EntityKind::Way)
+                .map(|e| e.offset)
+                .collect();
+            let bytes = offsets.iter().map(|&offset| index.blob_len(offset)).sum();
+            Some(WayBlobs { offsets, bytes })
+        }
+        None => None,
+    };
+    let parts_dir = match &args.parts_dir {
+        Some(dir) => dir.clone(),
+        None => args
+            .node_cache
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .to_path_buf(),
+    };
+    let mut part_files = Vec::new();
+    let mut reports = Vec::new();
 
     while start_idx <= max_node_id.load(Ordering::Relaxed) {
-        timed(
-            format!(
-                "Iteration for node IDs {}..{}",
-                start_idx.separated_string(),
-                (start_idx + chunk_size).separated_string()
-            )
-            .as_str(),
-            || {
-                run_one_pass(
-                    &cache,
-                    &args.pbf_file,
-                    &max_node_id,
-                    &first_way_block,
-                    start_idx,
-                    chunk_size,
-                )
-            },
+        let parts = if args.mode == Mode::Assemble {
+            let path = parts_dir.join(format!("way-parts-{}.bin", part_files.len()));
+            part_files.push(path);
+            Some(WayPartsWriter::create(&part_files[part_files.len() - 1])?)
+        } else {
+            None
+        };
+        let start = Instant::now();
+        let (stats, bytes_read) = run_one_pass(
+            &args,
+            &max_node_id,
+            &first_way_block,
+            start_idx..start_idx + chunk_size,
+            parts.as_ref(),
+            way_blobs.as_ref(),
         )?;
+        if let Some(parts) = parts {
+            parts.finish()?;
+        }
+        let report = PassReport {
+            node_ids: Some(start_idx..start_idx + chunk_size),
+            bytes_read,
+            elapsed: start.elapsed(),
+            stats,
+        };
+        report.print(reports.len() + 1);
+        reports.push(report);
         start_idx += chunk_size;
     }
 
+    if args.mode == Mode::Assemble {
+        let start = Instant::now();
+        let bytes_read = part_files
+            .iter()
+            .map(|path| path.metadata().map(|m| m.len()).unwrap_or(0))
+            .sum();
+        let stats = assemble(&args, part_files)?;
+        let report = PassReport {
+            node_ids: None,
+            bytes_read,
+            elapsed: start.elapsed(),
+            stats,
+        };
+        report.print(reports.len() + 1);
+        reports.push(report);
+    }
+
+    print_summary(&args, &reports);
     Ok(())
 }
 
+fn print_summary(args: &OptsChunkedResolver, reports: &[PassReport]) {
+    let mut total = Stats::default();
+    let mut bytes_read = 0;
+    let mut elapsed = Duration::default();
+    println!(
+        "Summary of {} passes with {} GB slices:",
+        reports.len(),
+        args.mem_slice
+    );
+    for (idx, report) in reports.iter().enumerate() {
+        report.print(idx + 1);
+        total += report.stats.clone();
+        bytes_read += report.bytes_read;
+        elapsed += report.elapsed;
+    }
+    println!(
+        "Total: {} ways resolved, {} empty, {} errors, {} skipped, {} MB read in {:.1} seconds",
+        total.ways_resolved.separated_string(),
+        total.empty_ways.separated_string(),
+        total.errors.separated_string(),
+        total.skipped.separated_string(),
+        (bytes_read / 1024 / 1024).separated_string(),
+        elapsed.as_secs_f32()
+    );
+    if let Some(slowest) = reports
+        .iter()
+        .enumerate()
+        .max_by_key(|(_, report)| report.elapsed)
+    {
+        println!(
+            "Slowest pass: {} with {:.1} seconds",
+            slowest.0 + 1,
+            slowest.1.elapsed.as_secs_f32()
+        );
+    }
+    println!("Bounding box: {:?}", total.bbox);
+}
+
 fn run_one_pass(
-    cache: &DenseFileCache,
-    pbf_file: &Path,
+    args: &OptsChunkedResolver,
     shared_max_node_id: &AtomicI64,
     first_way_block: &AtomicU64,
-    start_idx: i64,
-    chunk_size: i64,
-) -> Result<(), Error> {
+    node_ids: Range<i64>,
+    parts: Option<&WayPartsWriter>,
+    way_blobs: Option<&WayBlobs>,
+) -> Result<(Stats, u64), Error> {
     let (sender, receiver) = channel();
     let stats_collector = spawn_stats_aggregator("Chunked parser", receiver);
-    let mut reader = BlobReader::from_path(pbf_file)?;
+    // Without a blob index, blobs are read from the start offset to the end of the file
+    let file_len = args.pbf_file.metadata()?.len();
+    let mut bytes_read = file_len;
 
-    let read_from = first_way_block.load(Ordering::Relaxed);
-    if read_from < u64::MAX {
-        println!("Skipping to offset {read_from}");
-        reader.seek(ByteOffset(read_from)).unwrap();
+    let mut reader = WayReader::new(&args.pbf_file, &args.node_cache)
+        .skip_bad_blobs(args.skip_bad_blobs)
+        .missing_nodes(args.missing_nodes.into())
+        .advice(args.advice.values());
+    match way_blobs {
+        Some(blobs) => {
+            bytes_read = blobs.bytes;
+            reader = reader.blobs(blobs.offsets.clone());
+        }
+        None => {
+            let read_from = first_way_block.load(Ordering::Relaxed);
+            if read_from < u64::MAX {
+                println!("Skipping to offset {read_from}");
+                reader = reader.start_offset(read_from);
+                bytes_read = file_len - read_from;
+            }
+        }
     }
 
-    reader
-        .par_bridge()
-        .for_each_with((cache, sender), |(dfc, sender), blob| {
-            let blob = blob.unwrap();
-            if let BlobDecode::OsmData(block) = blob.decode().unwrap() {
-                let cache = dfc.get_accessor();
-                let mut stats = Stats::default();
-                let mut max_node_id = 0;
-                let last_idx = start_idx + chunk_size;
-                let mut blob_has_ways = false;
-                for group in block.groups() {
-                    for way in group.ways() {
-                        blob_has_ways = true;
-                        // Skip if this way's maximum node ID is outside of our range
-                        match way.refs().max() {
-                            None => {
-                                if start_idx == 0 {
-                                    // handle empty ways on the first pass
-                                    stats.empty_ways += 1;
-                                }
-                            }
-                            Some(last_node_id) => {
-                                if last_node_id > max_node_id {
-                                    max_node_id = last_node_id;
-                                }
-                                if last_node_id < start_idx || last_node_id >= last_idx {
-                                    stats.ways_viewed += 1;
-                                    continue;
-                                }
-                            }
-                        }
-
-                        for id in way.refs() {
-                            let (lat, lng) = cache.get_lat_lon(id as usize);
-                            stats.add_point(lat, lng)
+    let result = reader
+        .for_each_blob_with(sender.clone(), |sender, blob| -> Result<(), Error> {
+            let mut stats = Stats {
+                blobs: 1,
+                ..Stats::default()
+            };
+            let mut max_node_id = 0;
+            let mut blob_has_ways = false;
+            let mut blob_parts = Vec::new();
+            for way in blob.ways() {
+                blob_has_ways = true;
+                let (first_node_id, last_node_id) = match (way.refs().min(), way.refs().max()) {
+                    (Some(first), Some(last)) => (first, last),
+                    _ => {
+                        if node_ids.start == 0 {
+                            // handle empty ways on the first pass
+                            stats.empty_ways += 1;
                         }
-                        stats.ways_resolved += 1;
-
-                        // if let Mode::Resolve = mode {
-                        //     for id in way.refs() {
-                        //         let (lat, lng) = cache.get_lat_lon(id as usize);
-                        //         stats += (lat as f64, lng as f64)
-                        //     }
-                        //     continue;
-                        // }
-                        // let refs: Vec<[f64; 2]> = way
-                        //     .refs()
-                        //     .map(|id| {
-                        //         let (lat, lng) = cache.get_lat_lon(id as usize);
-                        //         [lat as f64, lng as f64]
-                        //     })
-                        //     .collect();
-                        // if let Mode::Vector = mode {
-                        //     for [lat, lng] in refs {
-                        //         stats += (lat as f64, lng as f64)
-                        //     }
-                        //     continue;
-                        // }
-                        // match get_bbox(&refs) {
-                        //     Ok((min_lat, max_lat, min_lng, max_lng)) => {
-                        //         stats += Stats {
-                        //             count: 1,
-                        //             errors: 0,
-                        //             min_latitude: min_lat,
-                        //             max_latitude: max_lat,
-                        //             min_longitude: min_lng,
-                        //             max_longitude: max_lng,
-                        //         }
-                        //     }
-                        //     Err(_) => {
-                        //         stats.errors += 1;
-                        //     }
-                        // }
+                        continue;
                     }
+                };
+                if last_node_id > max_node_id {
+                    max_node_id = last_node_id;
                 }
-                shared_max_node_id.fetch_max(max_node_id, Ordering::Relaxed);
-                if blob_has_ways {
-                    first_way_block.fetch_min(blob.offset().unwrap().0, Ordering::Relaxed);
+
+                let node_range = (first_node_id, last_node_id);
+                match stats.plan_way(args.mode, &node_ids, node_range, way.refs()) {
+                    WayAction::Resolve => match blob.resolve(&way) {
+                        Some(way) => stats.add_coords(way.coords),
+                        None => stats.skipped += 1,
+                    },
+                    // Only keep the nodes of this slice, the rest come from other passes
+                    WayAction::StoreParts => blob_parts.push(WayPart {
+                        way_id: way.id(),
+                        node_count: way.refs().count() as u32,
+                        nodes: way
+                            .refs()
+                            .enumerate()
+                            .filter(|(_, id)| node_ids.contains(id))
+                            .filter_map(|(pos, id)| {
+                                let (lat, lng) = blob.cache().get_node(id)?;
+                                Some((pos as u32, [lng, lat]))
+                            })
+                            .collect(),
+                    }),
+                    WayAction::Ignore => {}
                 }
-                sender.send(stats).unwrap();
-            };
+            }
+            if let Some(parts) = parts {
+                parts.add_block(blob_parts)?;
+            }
+            shared_max_node_id.fetch_max(max_node_id, Ordering::Relaxed);
+            if blob_has_ways {
+                let offset = blob
+                    .offset
+                    .context("Unable to get offset of a blob with ways")?;
+                first_way_block.fetch_min(offset, Ordering::Relaxed);
+            }
+            sender.send(stats)?;
+            Ok(())
+        })
+        .and_then(|totals| {
+            // Bad blobs and missing nodes are counted by the reader
+            sender.send(Stats {
+                bad_blobs: totals.bad_blobs,
+                missing: totals.missing,
+                ..Stats::default()
+            })?;
+            Ok(())
+        });
+    drop(sender);
+    let stats = stats_collector.join().unwrap();
+    result?;
+    Ok((stats, bytes_read))
+}
+
+/// Merge the partial ways stored by each pass, and build their geometries.
+fn assemble(args: &OptsChunkedResolver, part_files: Vec<PathBuf>) -> Result<Stats, Error> {
+    let (sender, receiver) = channel();
+    let stats_collector = spawn_stats_aggregator("Assembled ways", receiver);
+    let result = assemble_ways(part_files, args.missing_nodes.into(), sender);
+    let stats = stats_collector.join().unwrap();
+    result?;
+    Ok(stats)
+}
+
+fn assemble_ways(
+    part_files: Vec<PathBuf>,
+    missing_nodes: MissingNodes,
+    sender: Sender<Stats>,
+) -> Result<(), Error> {
+    let mut batch = Vec::with_capacity(ASSEMBLE_BATCH);
+    for way in MergedWays::open(part_files)? {
+        batch.push(way?);
+        if batch.len() == ASSEMBLE_BATCH {
+            assemble_batch(std::mem::take(&mut batch), missing_nodes, &sender)?;
+        }
+    }
+    assemble_batch(batch, missing_nodes, &sender)
+}
+
+fn assemble_batch(
+    batch: Vec<MergedWay>,
+    missing_nodes: MissingNodes,
+    sender: &Sender<Stats>,
+) -> Result<(), Error> {
+    let stats = batch
+        .into_par_iter()
+        .map(|way| {
+            let mut stats = Stats::default();
+            stats.add_way(way.way_id, missing_nodes.apply(way.nodes.into_iter()));
+            stats
+        })
+        .reduce(Stats::default, |mut a, b| {
+            a += b;
+            a
         });
-    stats_collector.join().unwrap();
+    sender.send(stats)?;
     Ok(())
 }
 
-// fn get_bbox(refs: &[[f64; 2]]) -> GResult<(f64, f64, f64, f64)> {
-//     let geometry = Geometry::create_line_string(CoordSeq::new_from_vec(refs)?)?;
-//     let geom = geometry.envelope()?;
-//     Ok((
-//         geom.get_y_min()?,
-//         geom.get_y_max()?,
-//         geom.get_x_min()?,
-//         geom.get_x_max()?,
-//     ))
-// }
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Plan the ways in passes of 10 node IDs, returning (resolved, deferred, partial) per pass
+    fn plan_passes(mode: Mode, ways: &[&[i64]]) -> Vec<(usize, usize, usize)> {
+        (0..3)
+            .map(|pass| {
+                let slice = pass * 10..(pass + 1) * 10;
+                let mut stats = Stats::default();
+                let mut resolved = 0;
+                for refs in ways {
+                    let first = *refs.iter().min().unwrap();
+                    let last = *refs.iter().max().unwrap();
+                    let action = stats.plan_way(mode, &slice, (first, last), refs.iter().copied());
+                    if action == WayAction::Resolve {
+                        resolved += 1;
+                    }
+                }
+                (resolved, stats.ways_deferred, stats.ways_partial)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_plan_way() {
+        let ways: [&[i64]; 5] = [&[1, 5], &[5, 15], &[5, 25], &[12, 18], &[22]];
+        // Each way is resolved once, and deferred by each pass before its largest node ID
+        assert_eq!(
+            plan_passes(Mode::Skip, &ways),
+            vec![(1, 4, 0), (2, 2, 0), (2, 0, 0)]
+        );
+        // Ways spanning several slices are stored in every pass with some of their nodes
+        assert_eq!(
+            plan_passes(Mode::Assemble, &ways),
+            vec![(1, 4, 2), (1, 2, 1), (1, 0, 1)]
+        );
+    }
+}
@@ -7,6 +7,10 @@ use std::path::PathBuf;
 pub struct OptsCounter1 {
     /// Input pbf data.
     pub pbf_file: PathBuf,
+
+    /// Report and skip blobs that cannot be decoded instead of stopping (count1a only)
+    #[clap(long)]
+    pub skip_bad_blobs: bool,
 }
 
 //noinspection DuplicatedCode
@@ -20,6 +24,7 @@ pub struct Stats {
     pub way_tags: usize,
     pub rels: usize,
     pub rel_tags: usize,
+    pub bad_blobs: usize,
 }
 
 impl Stats {
@@ -62,6 +67,7 @@ impl ops::Add for Stats {
             way_tags: self.way_tags + other.way_tags,
             rels: self.rels + other.rels,
             rel_tags: self.rel_tags + other.rel_tags,
+            bad_blobs: self.bad_blobs + other.bad_blobs,
         }
     }
 }
@@ -1,17 +1,21 @@
 use crate::counter1_utils::{OptsCounter1, Stats};
+use crate::utils::print_osm_header;
 use anyhow::Error;
-use osmpbf::{BlobDecode, BlobReader};
+use osmpbf::BlobReader;
+use planetiler::pbf::decode_data_block_or_skip;
 use rayon::iter::{ParallelBridge, ParallelIterator};
 
 pub fn run(args: OptsCounter1) -> Result<(), Error> {
+    print_osm_header(&args.pbf_file);
     // Read PBF file using multiple threads, and in each thread it will
     // decode blocks, count stats, and aggregate stats.
     let stats = BlobReader::from_path(args.pbf_file)?
         .par_bridge()
-        .map(|blob| {
+        .map(|blob| -> Result<Stats, Error> {
             let mut stats = Stats::default();
-            if let BlobDecode::OsmData(block) = blob.unwrap().decode().unwrap() {
-                for group in block.groups() {
+            let block = decode_data_block_or_skip(blob, args.skip_bad_blobs, &mut stats.bad_blobs)?;
+            if let Some(data) = block {
+                for group in data.block.groups() {
                     for node in group.nodes() {
                         stats.add_node(node.id(), node.tags().count());
                     }
@@ -26,9 +30,9 @@ pub fn run(args: OptsCounter1) -> Result<(), Error> {
                     }
                 }
             };
-            stats
+            Ok(stats)
         })
-        .reduce(Stats::default, |a, b| a + b);
+        .try_reduce(Stats::default, |a, b| Ok(a + b))?;
     println!("Single pass counting using osmpbf lib: {:#?}", stats);
     Ok(())
 }
@@ -1,4 +1,5 @@
 use crate::counter1_utils::Stats;
+use crate::utils::print_osm_header;
 use crate::OptsCounter1;
 use anyhow::Error;
 use osmpbfreader::blobs::result_blob_into_iter;
@@ -6,6 +7,7 @@ use osmpbfreader::OsmPbfReader;
 use par_map::ParMap;
 
 pub fn run(args: OptsCounter1) -> Result<(), Error> {
+    print_osm_header(&args.pbf_file);
     let stats = OsmPbfReader::new(std::fs::File::open(args.pbf_file).unwrap())
         .blobs()
         .par_map(|x| {
@@ -2,23 +2,34 @@ use std::ops::AddAssign;
 use std::path::PathBuf;
 use std::sync::mpsc::channel;
 
-use anyhow::Error;
+use anyhow::{ensure, Error};
 use clap::{ArgEnum, Parser};
-use geos::{CoordSeq, GResult, Geom, Geometry};
-use osmnodecache::{CacheStore, DenseFileCache};
-use osmpbf::{BlobDecode, BlobReader, ByteOffset};
-use rayon::iter::{ParallelBridge, ParallelIterator};
+use geos::{GResult, Geom, Geometry};
+use planetiler::area::build_way_geometry;
+use planetiler::node_cache::NodeLookup;
+use planetiler::profile::{ElementKind, FeatureCollector, Profile, SampleProfile, SourceFeature};
+use planetiler::tile_id::PackedTileID64;
+use planetiler::tile_index::{ElementId, TileIndex};
+use planetiler::tile_set::TileSet;
+use planetiler::tiler::slice_feature;
+use planetiler::ways::{MissingNodeStats, WayReader};
+use separator::Separatable;
 
-use crate::cache_nodes::parse_nodes;
+use crate::cache_nodes::reuse_or_parse_nodes;
 use crate::utils::MemAdvice::{Random, Sequential};
-use crate::utils::{advise_cache, spawn_stats_aggregator, timed, OptAdvice};
+use crate::utils::{
+    print_osm_header, spawn_stats_aggregator, timed, BBox, MissingNodePolicy, NodeCacheKind,
+    OptAdvice,
+};
 
 #[derive(Debug, Parser)]
 pub struct OptsCounter2 {
     /// What operations should be done with ways
     /// * Resolve - Resolve each node ID to lat/lng
     /// * Vector - Create a vector of lat/lng pairs
-    /// * Geometry - Create a geometry from vector
+    /// * Geometry - Create a line or polygon geometry from vector
+    /// * Render - Create a geometry and pass it to the sample profile
+    /// * Tiles - Render features and slice them into tiles across their zoom range
     #[clap(arg_enum)]
     mode: Mode,
 
@@ -28,8 +39,31 @@ pub struct OptsCounter2 {
     /// File for planet-size node cache.
     node_cache: PathBuf,
 
+    /// Dense cache is sized by the max node ID, sparse by the node count,
+    /// compressed is the smallest but slowest. Auto picks dense or sparse based on the node IDs.
+    #[clap(long, arg_enum, default_value = "auto")]
+    cache_kind: NodeCacheKind,
+
+    /// What to do with ways using nodes missing from the cache, e.g. at the edges of an extract.
+    /// Resolve mode never uses missing nodes, and only counts the ways the policy skips.
+    #[clap(long, arg_enum, default_value = "drop")]
+    missing_nodes: MissingNodePolicy,
+
+    /// Build the node cache even if its metadata shows it was built from the same PBF file
+    #[clap(long)]
+    rebuild_cache: bool,
+
     #[clap(flatten)]
     advice: OptAdvice,
+
+    /// Report and skip blobs that cannot be decoded instead of stopping
+    #[clap(long)]
+    skip_bad_blobs: bool,
+
+    /// In the tiles mode, record the tiles of each way in this tile index database,
+    /// so that only the tiles of changed ways need to be rendered again.
+    #[clap(long)]
+    tile_index: Option<PathBuf>,
 }
 
 #[derive(ArgEnum, Debug, Clone, Copy)]
@@ -37,42 +71,52 @@ enum Mode {
     Resolve,
     Vector,
     Geometry,
+    Render,
+    Tiles,
 }
 
 #[derive(Clone, Default, Debug)]
 struct Stats {
     pub count: usize,
     pub errors: usize,
-    pub min_latitude: f64,
-    pub max_latitude: f64,
-    pub min_longitude: f64,
-    pub max_longitude: f64,
+    pub bad_blobs: usize,
+    pub polygons: usize,
+    pub features: usize,
+    pub tiles: usize,
+    pub missing: MissingNodeStats,
+    pub bbox: BBox,
 }
 
 impl Stats {
     fn add_point(&mut self, lat: f64, lng: f64) {
         self.count += 1;
-        self.min_latitude = self.min_latitude.min(lat);
-        self.max_latitude = self.max_latitude.max(lat);
-        self.min_longitude = self.min_longitude.min(lng);
-        self.max_longitude = self.max_longitude.max(lng);
+        self.bbox.add_point(lat, lng);
     }
 }
 
 impl AddAssign for Stats {
     fn add_assign(&mut self, other: Self) {
+        let mut missing = std::mem::take(&mut self.missing);
+        missing += other.missing;
         *self = Self {
             count: self.count + other.count,
             errors: self.errors + other.errors,
-            min_latitude: self.min_latitude.min(other.min_latitude),
-            max_latitude: self.max_latitude.max(other.max_latitude),
-            min_longitude: self.min_longitude.min(other.min_longitude),
-            max_longitude: self.max_longitude.max(other.max_longitude),
+            bad_blobs: self.bad_blobs + other.bad_blobs,
+            polygons: self.polygons + other.polygons,
+            features: self.features + other.features,
+            tiles: self.tiles + other.tiles,
+            missing,
+            bbox: self.bbox.union(other.bbox),
         };
     }
 }
 
 pub fn run(args: OptsCounter2) -> Result<(), Error> {
+    ensure!(
+        args.tile_index.is_none() || matches!(args.mode, Mode::Tiles),
+        "Tile index can only be created in the tiles mode"
+    );
+    print_osm_header(&args.pbf_file);
     let (advice1, advice2) = if args.advice.advice.is_empty() {
         // By default, use sequential memmap creation, but random during node resolution
         (
@@ -86,12 +130,19 @@ pub fn run(args: OptsCounter2) -> Result<(), Error> {
     } else {
         (args.advice.clone(), args.advice.clone())
     };
-    let first_way_block_offset = timed("Node cache created", || {
-        parse_nodes(&args.pbf_file, args.node_cache.clone(), &advice1)
+    let info = timed("Node cache created", || {
+        reuse_or_parse_nodes(
+            &args.pbf_file,
+            args.node_cache.clone(),
+            args.cache_kind.into(),
+            &advice1,
+            args.skip_bad_blobs,
+            args.rebuild_cache,
+        )
     })?;
 
     timed("Ways parsed", || {
-        parse_ways(args, &advice2, first_way_block_offset)
+        parse_ways(args, &advice2, info.first_way_block)
     })
 }
 
@@ -100,82 +151,145 @@ pub fn parse_ways(
     advice: &OptAdvice,
     starting_offset: u64,
 ) -> Result<(), Error> {
-    let cache = DenseFileCache::new(args.node_cache.clone())?;
-    advise_cache(&cache, advice)?;
+    let index = match &args.tile_index {
+        Some(path) => Some(TileIndex::open(path)?),
+        None => None,
+    };
+    let mode = args.mode;
+    if starting_offset > 0 && starting_offset < u64::MAX {
+        println!("Skipping to offset {starting_offset}");
+    }
+    let reader = WayReader::new(&args.pbf_file, &args.node_cache)
+        .start_offset(starting_offset)
+        .skip_untagged(matches!(mode, Mode::Render | Mode::Tiles))
+        .skip_bad_blobs(args.skip_bad_blobs)
+        .missing_nodes(args.missing_nodes.into())
+        .advice(advice.values());
 
     let (sender, receiver) = channel();
     let stats_collector = spawn_stats_aggregator("Resolved ways", receiver);
-    let mut reader = BlobReader::from_path(args.pbf_file)?;
-
-    if starting_offset > 0 {
-        println!("Skipping to offset {starting_offset}");
-        reader.seek(ByteOffset(starting_offset))?;
-    }
 
     // Read PBF file using multiple threads, and in each thread it will
     // decode ways into arrays of points
-    reader
-        .par_bridge()
-        .for_each_with((cache, sender), |(dfc, sender), blob| {
-            let cache = dfc.get_accessor();
-            let mode = args.mode;
+    let result = reader
+        .for_each_blob_with(sender.clone(), |sender, blob| -> Result<(), Error> {
             let mut stats = Stats::default();
-            if let BlobDecode::OsmData(block) = blob.unwrap().decode().unwrap() {
-                for group in block.groups() {
-                    for way in group.ways() {
-                        if let Mode::Resolve = mode {
-                            for id in way.refs() {
-                                let (lat, lng) = cache.get_lat_lon(id as usize);
-                                stats.add_point(lat as f64, lng as f64)
+            let mut features = FeatureCollector::default();
+            for way in blob.ways() {
+                if let Mode::Resolve = mode {
+                    let (mut found, mut missing) = (0, 0);
+                    for id in way.refs() {
+                        match blob.cache().get_node(id) {
+                            Some((lat, lng)) => {
+                                found += 1;
+                                stats.add_point(lat, lng);
                             }
-                            continue;
+                            None => missing += 1,
                         }
-                        let refs: Vec<[f64; 2]> = way
-                            .refs()
-                            .map(|id| {
-                                let (lat, lng) = cache.get_lat_lon(id as usize);
-                                [lat as f64, lng as f64]
-                            })
-                            .collect();
-                        if let Mode::Vector = mode {
-                            for [lat, lng] in refs {
-                                stats.add_point(lat as f64, lng as f64)
-                            }
-                            continue;
-                        }
-                        match get_bbox(&refs) {
-                            Ok((min_lat, max_lat, min_lng, max_lng)) => {
-                                stats += Stats {
-                                    count: 1,
-                                    errors: 0,
-                                    min_latitude: min_lat,
-                                    max_latitude: max_lat,
-                                    min_longitude: min_lng,
-                                    max_longitude: max_lng,
+                    }
+                    blob.count_way(way.id(), found, missing);
+                    continue;
+                }
+                let way = match blob.resolve(&way) {
+                    Some(way) => way,
+                    None => continue,
+                };
+                if let Mode::Vector = mode {
+                    for [lng, lat] in way.coords {
+                        stats.add_point(lat, lng)
+                    }
+                    continue;
+                }
+                let way_geom = match build_way_geometry(&way.tags, &way.coords) {
+                    Ok(v) => v,
+                    Err(_) => {
+                        stats.errors += 1;
+                        continue;
+                    }
+                };
+                if way_geom.is_polygon {
+                    stats.polygons += 1;
+                }
+                if way_geom.repaired {
+                    // Invalid rings are still rendered after repair, but reported as errors
+                    stats.errors += 1;
+                }
+                if let Mode::Render | Mode::Tiles = mode {
+                    let feature = SourceFeature {
+                        id: way.id,
+                        kind: ElementKind::Way,
+                        tags: &way.tags,
+                        geometry: &way_geom.geometry,
+                    };
+                    SampleProfile.process_way(&feature, &mut features);
+                    let mut way_tiles = TileSet::new();
+                    for feature in features.drain() {
+                        stats.features += 1;
+                        if let Mode::Tiles = mode {
+                            match slice_feature(&feature, &way_geom.geometry) {
+                                Ok(sliced) => {
+                                    stats.tiles += sliced.tile_count() as usize;
+                                    let tiles = sliced.tiles.iter().map(|(tile, _)| *tile);
+                                    way_tiles = way_tiles.union(&tiles.collect());
+                                    // Filled tiles start at the fill's min zoom, below its tile
+                                    for fill in &sliced.fills {
+                                        way_tiles.insert_descendants_at(
+                                            PackedTileID64::new(fill.tile),
+                                            fill.min_zoom..=fill.max_zoom,
+                                        )?;
+                                    }
                                 }
-                            }
-                            Err(_) => {
-                                stats.errors += 1;
+                                Err(_) => stats.errors += 1,
                             }
                         }
                     }
+                    if let Some(index) = &index {
+                        if !way_tiles.is_empty() {
+                            index.insert(ElementId::new(ElementKind::Way, way.id), &way_tiles)?;
+                        }
+                    }
+                    continue;
                 }
-            };
-            sender.send(stats).unwrap();
+                match get_bbox(&way_geom.geometry) {
+                    Ok(bbox) => {
+                        stats.count += 1;
+                        stats.bbox = stats.bbox.union(bbox);
+                    }
+                    Err(_) => {
+                        stats.errors += 1;
+                    }
+                }
+            }
+            sender.send(stats)?;
+            Ok(())
+        })
+        .and_then(|totals| {
+            // Bad blobs and missing nodes are counted by the reader
+            sender.send(Stats {
+                bad_blobs: totals.bad_blobs,
+                missing: totals.missing,
+                ..Stats::default()
+            })?;
+            Ok(())
         });
+    drop(sender);
 
     stats_collector.join().unwrap();
 
-    Ok(())
+    if let Some(index) = index {
+        index.flush()?;
+        println!("Tile index has {} ways", index.len().separated_string());
+    }
+    result
 }
 
-fn get_bbox(refs: &[[f64; 2]]) -> GResult<(f64, f64, f64, f64)> {
-    let geometry = Geometry::create_line_string(CoordSeq::new_from_vec(refs)?)?;
+/// Bounding box of a geometry in (lon, lat) coordinates
+fn get_bbox(geometry: &Geometry) -> GResult<BBox> {
     let geom = geometry.envelope()?;
-    Ok((
-        geom.get_y_min()?,
-        geom.get_y_max()?,
-        geom.get_x_min()?,
-        geom.get_x_max()?,
-    ))
+    Ok(BBox {
+        min_latitude: geom.get_y_min()?,
+        max_latitude: geom.get_y_max()?,
+        min_longitude: geom.get_x_min()?,
+        max_longitude: geom.get_x_max()?,
+    })
 }
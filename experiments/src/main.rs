@@ -3,24 +3,31 @@ extern crate core;
 use crate::cache_nodes2::OptsCacheNodes2;
 use clap::Parser;
 mod geostruct;
+use crate::blob_index::OptsBlobIndex;
 use crate::cache_nodes::OptsCacheNodes;
+use crate::check_cache::OptsCheckCache;
 use crate::chunked_resolver::OptsChunkedResolver;
 use crate::counter1_utils::OptsCounter1;
 use crate::counter2::OptsCounter2;
+use crate::multipolygon::OptsMultipolygon;
 use crate::node_id_dist::OptsNodeIdDistribution;
+use crate::track_bench::OptsTrackBench;
 use crate::track_tiles::OptsTrackTiles;
 use crate::utils::timed;
 
+mod blob_index;
 mod cache_nodes;
 mod cache_nodes2;
 mod cache_nodes3;
+mod check_cache;
 mod chunked_resolver;
 mod counter1_utils;
 mod counter1a;
 mod counter1b;
 mod counter2;
+mod multipolygon;
 mod node_id_dist;
-mod tile_id;
+mod track_bench;
 mod track_tiles;
 mod utils;
 
@@ -43,16 +50,29 @@ enum Command {
     /// Create a node cache using memory map.
     CacheNodes(OptsCacheNodes),
     /// Create a node cache using sequential writer.
+    /// Requires a file declared sorted by type then ID.
     CacheNodes2(OptsCacheNodes2),
     /// Create a node cache opening files for each block in parallel.
+    /// Requires a file declared sorted by type then ID.
     CacheNodes3(OptsCacheNodes2),
+    /// Index the offsets and entity ID ranges of all blobs of an OSM PBF file,
+    /// and check if it is sorted by type then ID.
+    BlobIndex(OptsBlobIndex),
+    /// Verify a node cache against its PBF file, or print cached positions of some nodes.
+    CheckCache(OptsCheckCache),
     /// Iterate over an OSM PBF file and count the number of features and tags
     NodeDist(OptsNodeIdDistribution),
-    /// Resolve all ways to their geopoints via node cache, and calculate total bound box.
-    /// Assumes nodes are stored before ways.
+    /// Resolve all ways to their geopoints via node cache in several passes over node ID ranges,
+    /// and calculate total bound box. Assumes nodes are stored before ways.
     Chunked(OptsChunkedResolver),
-    /// Create a disk map with (feature ID -> list of tile IDs). Evaluate how to track which feature exists in which tiles.
+    /// Assemble multipolygon and boundary relations from their member ways using the node cache.
+    /// Assumes nodes are stored before ways, and ways before relations.
+    Multipolygon(OptsMultipolygon),
+    /// Query the tile index of which tiles each OSM element was rendered into,
+    /// and which elements each tile has.
     Track(OptsTrackTiles),
+    /// Write random rows of tiles for synthetic ways into a tile index, to measure its write speed.
+    TrackBench(OptsTrackBench),
 }
 
 fn main() {
@@ -66,8 +86,12 @@ fn main() {
             Command::CacheNodes(arg) => cache_nodes::run(arg),
             Command::CacheNodes2(arg) => cache_nodes2::run(arg),
             Command::CacheNodes3(arg) => cache_nodes3::run(arg),
+            Command::BlobIndex(arg) => blob_index::run(arg),
+            Command::CheckCache(arg) => check_cache::run(arg),
             Command::Chunked(arg) => chunked_resolver::run(arg),
+            Command::Multipolygon(arg) => multipolygon::run(arg),
             Command::Track(arg) => track_tiles::run(arg),
+            Command::TrackBench(arg) => track_bench::run(arg),
         };
 
         if let Err(v) = res {
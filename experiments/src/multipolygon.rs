@@ -0,0 +1,198 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::AddAssign;
+use std::path::PathBuf;
+use std::sync::mpsc::channel;
+
+use anyhow::Error;
+use clap::Parser;
+use planetiler::multipolygon::{
+    assemble_relation, read_area_relations, read_way_refs, AreaRelation,
+};
+use planetiler::profile::{ElementKind, FeatureCollector, Profile, SampleProfile, SourceFeature};
+use planetiler::ways::MissingNodeStats;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use separator::Separatable;
+
+use crate::cache_nodes::reuse_or_parse_nodes;
+use crate::utils::MemAdvice::{Random, Sequential};
+use crate::utils::{
+    open_node_cache, print_osm_header, spawn_stats_aggregator, timed, MissingNodePolicy,
+    NodeCacheKind, OptAdvice,
+};
+
+#[derive(Debug, Parser)]
+pub struct OptsMultipolygon {
+    /// Input pbf data.
+    pbf_file: PathBuf,
+
+    /// File for planet-size node cache.
+    node_cache: PathBuf,
+
+    /// Dense cache is sized by the max node ID, sparse by the node count,
+    /// compressed is the smallest but slowest. Auto picks dense or sparse based on the node IDs.
+    #[clap(long, arg_enum, default_value = "auto")]
+    cache_kind: NodeCacheKind,
+
+    /// What to do with member ways using nodes missing from the cache, e.g. at the edges
+    /// of an extract. A dropped member leaves its ring unclosed.
+    #[clap(long, arg_enum, default_value = "drop")]
+    missing_nodes: MissingNodePolicy,
+
+    /// Build the node cache even if its metadata shows it was built from the same PBF file
+    #[clap(long)]
+    rebuild_cache: bool,
+
+    #[clap(flatten)]
+    advice: OptAdvice,
+
+    /// Report and skip blobs that cannot be decoded instead of stopping
+    #[clap(long)]
+    skip_bad_blobs: bool,
+}
+
+#[derive(Clone, Default, Debug)]
+struct Stats {
+    pub relations: usize,
+    pub polygons: usize,
+    pub missing_ways: usize,
+    pub unclosed_rings: usize,
+    pub invalid_fixed: usize,
+    pub empty: usize,
+    pub errors: usize,
+    pub features: usize,
+    pub missing: MissingNodeStats,
+}
+
+impl AddAssign for Stats {
+    fn add_assign(&mut self, other: Self) {
+        let mut missing = std::mem::take(&mut self.missing);
+        missing += other.missing;
+        *self = Self {
+            relations: self.relations + other.relations,
+            polygons: self.polygons + other.polygons,
+            missing_ways: self.missing_ways + other.missing_ways,
+            unclosed_rings: self.unclosed_rings + other.unclosed_rings,
+            invalid_fixed: self.invalid_fixed + other.invalid_fixed,
+            empty: self.empty + other.empty,
+            errors: self.errors + other.errors,
+            features: self.features + other.features,
+            missing,
+        };
+    }
+}
+
+pub fn run(args: OptsMultipolygon) -> Result<(), Error> {
+    print_osm_header(&args.pbf_file);
+    let (advice1, advice2) = if args.advice.advice.is_empty() {
+        // By default, use sequential memmap creation, but random during node resolution
+        (
+            OptAdvice {
+                advice: vec![Sequential],
+            },
+            OptAdvice {
+                advice: vec![Random],
+            },
+        )
+    } else {
+        (args.advice.clone(), args.advice.clone())
+    };
+    let info = timed("Node cache created", || {
+        reuse_or_parse_nodes(
+            &args.pbf_file,
+            args.node_cache.clone(),
+            args.cache_kind.into(),
+            &advice1,
+            args.skip_bad_blobs,
+            args.rebuild_cache,
+        )
+    })?;
+
+    let (relations, bad_relation_blobs) = timed("Relations parsed", || {
+        read_area_relations(
+            &args.pbf_file,
+            info.first_relation_block,
+            args.skip_bad_blobs,
+        )
+    })?;
+    let needed: HashSet<i64> = relations.iter().flat_map(|r| r.ways.clone()).collect();
+    println!(
+        "Found {} multipolygon relations with {} member ways",
+        relations.len().separated_string(),
+        needed.len().separated_string()
+    );
+
+    let (ways, bad_way_blobs) = timed("Member ways parsed", || {
+        read_way_refs(
+            &args.pbf_file,
+            info.first_way_block,
+            &needed,
+            args.skip_bad_blobs,
+        )
+    })?;
+    drop(needed);
+    let bad_blobs = bad_relation_blobs + bad_way_blobs;
+    if bad_blobs > 0 {
+        println!("Skipped {} bad blobs", bad_blobs.separated_string());
+    }
+
+    timed("Multipolygons assembled", || {
+        assemble(&args, &advice2, &relations, &ways)
+    })
+}
+
+fn assemble(
+    args: &OptsMultipolygon,
+    advice: &OptAdvice,
+    relations: &[AreaRelation],
+    ways: &HashMap<i64, Vec<i64>>,
+) -> Result<(), Error> {
+    let cache = open_node_cache(&args.node_cache, advice)?;
+
+    let (sender, receiver) = channel();
+    let stats_collector = spawn_stats_aggregator("Multipolygons", receiver);
+
+    let result = relations.par_iter().try_for_each_with(
+        (cache, sender),
+        |(dfc, sender), rel| -> Result<(), Error> {
+            let cache = dfc.reader();
+            let mut stats = Stats {
+                relations: 1,
+                ..Stats::default()
+            };
+            match assemble_relation(rel, ways, &*cache, args.missing_nodes.into()) {
+                Ok(assembled) => {
+                    stats.missing_ways += assembled.missing_ways;
+                    stats.missing += assembled.missing;
+                    stats.unclosed_rings += assembled.unclosed_rings;
+                    if let Some(geometry) = assembled.geometry {
+                        stats.polygons += 1;
+                        if assembled.repaired {
+                            stats.invalid_fixed += 1;
+                        }
+                        let tags = rel.tags();
+                        let mut features = FeatureCollector::default();
+                        SampleProfile.process_relation(
+                            &SourceFeature {
+                                id: rel.id,
+                                kind: ElementKind::Relation,
+                                tags: &tags,
+                                geometry: &geometry,
+                            },
+                            &mut features,
+                        );
+                        stats.features += features.drain().count();
+                    } else {
+                        stats.empty += 1;
+                    }
+                }
+                Err(_) => stats.errors += 1,
+            }
+            sender.send(stats)?;
+            Ok(())
+        },
+    );
+
+    stats_collector.join().unwrap();
+
+    result
+}
@@ -3,10 +3,11 @@ use std::ops;
 use std::path::PathBuf;
 use std::sync::mpsc::channel;
 
-use crate::utils::{spawn_stats_aggregator, Histogram};
+use crate::utils::{print_osm_header, spawn_stats_aggregator, Histogram};
 use anyhow::Error;
 use clap::Parser;
-use osmpbf::{BlobDecode, BlobReader};
+use osmpbf::BlobReader;
+use planetiler::pbf::decode_data_block_or_skip;
 use rayon::iter::{ParallelBridge, ParallelIterator};
 use separator::Separatable;
 
@@ -14,10 +15,15 @@ use separator::Separatable;
 pub struct OptsNodeIdDistribution {
     /// Input pbf data.
     pbf_file: PathBuf,
+
+    /// Report and skip blobs that cannot be decoded instead of stopping
+    #[clap(long)]
+    skip_bad_blobs: bool,
 }
 
 struct Stats {
     pub ways: usize,
+    pub bad_blobs: usize,
     pub node_counts: Histogram,
     pub node_distance: Histogram,
 }
@@ -28,6 +34,7 @@ impl Default for Stats {
     fn default() -> Self {
         Stats {
             ways: 0,
+            bad_blobs: 0,
             node_counts: Histogram::new(
                 |v| v.min(50),
                 |v| {
@@ -55,6 +62,9 @@ impl Default for Stats {
 impl Debug for Stats {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "Total ways: {:}", self.ways.separated_string()).unwrap();
+        if self.bad_blobs > 0 {
+            writeln!(f, "Skipped bad blobs: {}", self.bad_blobs).unwrap();
+        }
         writeln!(
             f,
             "{}",
@@ -91,22 +101,25 @@ impl Stats {
 impl ops::AddAssign for Stats {
     fn add_assign(&mut self, other: Self) {
         self.ways += other.ways;
+        self.bad_blobs += other.bad_blobs;
         self.node_counts += other.node_counts;
         self.node_distance += other.node_distance;
     }
 }
 
 pub fn run(args: OptsNodeIdDistribution) -> Result<(), Error> {
+    print_osm_header(&args.pbf_file);
     let (sender, receiver) = channel();
     let stats_collector = spawn_stats_aggregator("Node distribution", receiver);
 
     // For each way, find min & max node IDs used, and create a histogram of the int(log(max-min))
-    BlobReader::from_path(args.pbf_file)?
+    let result = BlobReader::from_path(args.pbf_file)?
         .par_bridge()
-        .for_each_with(sender, |sender, blob| {
+        .try_for_each_with(sender, |sender, blob| -> Result<(), Error> {
             let mut stats = Stats::default();
-            if let BlobDecode::OsmData(block) = blob.unwrap().decode().unwrap() {
-                for group in block.groups() {
+            let block = decode_data_block_or_skip(blob, args.skip_bad_blobs, &mut stats.bad_blobs)?;
+            if let Some(data) = block {
+                for group in data.block.groups() {
                     for way in group.ways() {
                         let mut min_id = i64::MAX;
                         let mut max_id = i64::MIN;
@@ -120,10 +133,11 @@ pub fn run(args: OptsNodeIdDistribution) -> Result<(), Error> {
                     }
                 }
             };
-            sender.send(stats).unwrap();
+            sender.send(stats)?;
+            Ok(())
         });
 
     stats_collector.join().unwrap();
 
-    Ok(())
+    result
 }
@@ -0,0 +1,71 @@
+use planetiler::profile::ElementKind;
+use planetiler::tile_id::{PackedTileID64, TileID};
+use planetiler::tile_index::{ElementId, TileIndex};
+use planetiler::tile_set::TileSet;
+use rand::{thread_rng, Rng};
+use rayon::prelude::*;
+use std::path::PathBuf;
+
+use anyhow::Error;
+use clap::Parser;
+use separator::Separatable;
+
+const ZOOM: u8 = 14;
+
+#[derive(Debug, Parser)]
+pub struct OptsTrackBench {
+    /// Tile index database directory.
+    db_file: PathBuf,
+
+    /// Number of simultaneous batches to write.
+    batches: u32,
+
+    /// Number of ways in each batch.
+    count: u32,
+
+    /// For each way create a random row of zoom 14 tiles between 1 and this value long.
+    max_tiles_per_id: u16,
+
+    /// Use DB compression
+    #[clap(short, long)]
+    zip: bool,
+
+    /// Flash every N seconds
+    #[clap(short, long, default_value_t = 10)]
+    flash: u64,
+
+    /// Cache capacity, in MB
+    #[clap(short, long, default_value_t = 1048576)]
+    cache: u64,
+}
+
+pub fn run(args: OptsTrackBench) -> Result<(), Error> {
+    let db = sled::Config::new()
+        .flush_every_ms(Some(args.flash * 1000))
+        .path(&args.db_file)
+        .use_compression(args.zip)
+        .cache_capacity(args.cache * 1024)
+        .open()?;
+    let index = TileIndex::from_db(db)?;
+    (0..args.batches).into_par_iter().try_for_each(|batch_id| {
+        let mut rng = thread_rng();
+        (0..args.count).try_for_each(|v| {
+            let id = i64::from(batch_id) * i64::from(args.count) + i64::from(v);
+            let tile_ids: u32 = rng.gen_range(1..=u32::from(args.max_tiles_per_id).min(1 << ZOOM));
+            let max = (1 << ZOOM) - tile_ids;
+            let (x, y) = (rng.gen_range(0..=max), rng.gen_range(0..=max));
+            let tiles: TileSet = (x..x + tile_ids)
+                .map(|x| PackedTileID64::new(TileID::new(ZOOM, x, y)))
+                .collect();
+            index.insert(ElementId::new(ElementKind::Way, id), &tiles)
+        })
+    })?;
+    index.flush()?;
+    println!(
+        "Created {} entries, {} elements in {}",
+        (u64::from(args.count) * u64::from(args.batches)).separated_string(),
+        index.len().separated_string(),
+        args.db_file.display()
+    );
+    Ok(())
+}
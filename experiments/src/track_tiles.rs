@@ -1,70 +1,92 @@
-use rand::{thread_rng, Rng};
-use rayon::prelude::*;
 use std::path::PathBuf;
 
-use anyhow::Error;
+use anyhow::{anyhow, Error};
 use clap::Parser;
+use planetiler::profile::ElementKind;
+use planetiler::tile_id::{PackedTileID64, TileID};
+use planetiler::tile_index::{ElementId, TileIndex};
+use separator::Separatable;
 
 #[derive(Debug, Parser)]
 pub struct OptsTrackTiles {
-    /// Tile tracking database file.
+    /// Tile index database, created by `count2 tiles --tile-index`.
     db_file: PathBuf,
 
-    /// Number of simultaneous batches to write.
-    batches: u32,
+    /// Show the tiles of these nodes
+    #[clap(long)]
+    node: Vec<i64>,
 
-    /// Number of features in each batch.
-    count: u32,
+    /// Show the tiles of these ways
+    #[clap(long)]
+    way: Vec<i64>,
 
-    /// For each feature ID create random tiles between 1 and this value.
-    max_tiles_per_id: u16,
+    /// Show the tiles of these relations
+    #[clap(long)]
+    relation: Vec<i64>,
 
-    /// Use DB compression
-    #[clap(short, long)]
-    zip: bool,
-
-    /// Flash every N seconds
-    #[clap(short, long, default_value_t = 10)]
-    flash: u64,
-
-    /// Cache capacity, in MB
-    #[clap(short, long, default_value_t = 1048576)]
-    cache: u64,
+    /// Show the elements rendered into these tiles, given as zoom/x/y
+    #[clap(long)]
+    tile: Vec<String>,
 }
 
 pub fn run(args: OptsTrackTiles) -> Result<(), Error> {
-    let db = sled::Config::new()
-        .flush_every_ms(Some(args.flash * 1000))
-        .path(args.db_file)
-        .use_compression(args.zip)
-        .cache_capacity(args.cache * 1024)
-        .open()?;
-    (0..args.batches)
-        .collect::<Vec<_>>()
-        .par_iter()
-        .map(|batch_id| {
-            let mut rng = thread_rng();
-            let mut batch = sled::Batch::default();
-            (0..args.count)
-                .map(|v| {
-                    let key = (*batch_id as u64 * v as u64).to_be_bytes();
-                    let tile_ids: u32 = rng.gen_range(1..=args.max_tiles_per_id as u32);
-                    let value: Vec<_> = (0..tile_ids).flat_map(|v| v.to_be_bytes()).collect();
-                    let value2 = as_u8_slice(value.as_slice());
-                    let value3 = sled::IVec::from(value2);
-                    batch.insert(&key, value3);
-                })
-                .for_each(drop);
-            db.apply_batch(batch).unwrap();
-        })
-        .for_each(drop);
-    println!("Created {} entries", args.count * args.batches);
+    let index = TileIndex::open(&args.db_file)?;
+    println!(
+        "{} elements in {}",
+        index.len().separated_string(),
+        args.db_file.display()
+    );
+    let elements: Vec<_> = [
+        (ElementKind::Node, args.node),
+        (ElementKind::Way, args.way),
+        (ElementKind::Relation, args.relation),
+    ]
+    .into_iter()
+    .flat_map(|(kind, ids)| ids.into_iter().map(move |id| ElementId::new(kind, id)))
+    .collect();
+    for &element in &elements {
+        match index.get(element)? {
+            Some(tiles) => println!(
+                "{:?} {}: {} tiles in {} Hilbert ranges",
+                element.kind,
+                element.id,
+                tiles.len().separated_string(),
+                tiles.range_count().separated_string()
+            ),
+            None => println!("{:?} {}: not in the index", element.kind, element.id),
+        }
+    }
+    if elements.len() > 1 {
+        let tiles = index.affected_tiles(elements)?;
+        println!(
+            "Changing these elements affects {} tiles",
+            tiles.len().separated_string()
+        );
+    }
+    for tile in args.tile {
+        let elements = index.elements(parse_tile(&tile)?)?;
+        println!(
+            "Tile {tile}: {} elements",
+            elements.len().separated_string()
+        );
+        for element in elements {
+            println!("  {:?} {}", element.kind, element.id);
+        }
+    }
     Ok(())
 }
 
-// https://stackoverflow.com/a/29042896/177275
-fn as_u8_slice<T>(v: &[T]) -> &[u8] {
-    unsafe {
-        std::slice::from_raw_parts(v.as_ptr() as *const u8, v.len() * std::mem::size_of::<T>())
+/// Parse a tile given as zoom/x/y
+fn parse_tile(value: &str) -> Result<PackedTileID64, Error> {
+    let parts = value
+        .split('/')
+        .map(|v| v.parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()?;
+    match parts[..] {
+        [zoom, x, y] => {
+            let tile = TileID::try_new(u8::try_from(zoom)?, x, y)?;
+            Ok(PackedTileID64::try_new(tile)?)
+        }
+        _ => Err(anyhow!("Tile {value} is not in the zoom/x/y format")),
     }
 }
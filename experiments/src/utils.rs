@@ -1,9 +1,13 @@
 use anyhow::{Context, Error};
 use clap::{ArgEnum, Args};
 use osmnodecache::{Advice, DenseFileCache};
+use planetiler::node_cache::{open_cache, CacheKind, NodeCache};
+use planetiler::osm_header::OsmHeader;
+use planetiler::ways::MissingNodes;
 use std::fmt::Debug;
 use std::fmt::Write;
 use std::ops::AddAssign;
+use std::path::Path;
 use std::sync::mpsc::Receiver;
 use std::thread;
 use std::thread::JoinHandle;
@@ -104,6 +108,28 @@ impl AddAssign for Histogram {
     }
 }
 
+/// Print the OSM header of a PBF file, so that every run shows which data it used.
+/// A missing or unreadable header is only reported, the data blobs may still be fine.
+pub fn print_osm_header(pbf_file: &Path) -> Option<OsmHeader> {
+    match OsmHeader::read(pbf_file) {
+        Ok(Some(header)) => {
+            println!("OSM header of {}:\n{header}", pbf_file.display());
+            Some(header)
+        }
+        Ok(None) => {
+            eprintln!(
+                "The first blob of {} is not an OSM header",
+                pbf_file.display()
+            );
+            None
+        }
+        Err(err) => {
+            eprintln!("Unable to read the OSM header: {err:#}");
+            None
+        }
+    }
+}
+
 pub fn timed<F, R>(msg: &str, func: F) -> R
 where
     F: FnOnce() -> R,
@@ -114,10 +140,12 @@ where
     res
 }
 
+/// Sum up stats sent by worker threads, printing them periodically and when the channel
+/// is closed. Joining the returned handle gives the totals.
 pub fn spawn_stats_aggregator<T: 'static + Default + AddAssign + Debug + Send>(
     msg: &'static str,
     receiver: Receiver<T>,
-) -> JoinHandle<()> {
+) -> JoinHandle<T> {
     thread::spawn(move || {
         let start = Instant::now();
         let mut last_report = Instant::now();
@@ -130,9 +158,84 @@ pub fn spawn_stats_aggregator<T: 'static + Default + AddAssign + Debug + Send>(
             }
         }
         println!("{} results: {:#?}", msg, stats);
+        stats
     })
 }
 
+/// Bounding box of the resolved nodes. Empty (inverted) until the first point is added.
+#[derive(Clone, Copy, Debug)]
+pub struct BBox {
+    pub min_latitude: f64,
+    pub max_latitude: f64,
+    pub min_longitude: f64,
+    pub max_longitude: f64,
+}
+
+impl Default for BBox {
+    fn default() -> Self {
+        Self {
+            min_latitude: f64::INFINITY,
+            max_latitude: f64::NEG_INFINITY,
+            min_longitude: f64::INFINITY,
+            max_longitude: f64::NEG_INFINITY,
+        }
+    }
+}
+
+impl BBox {
+    pub fn add_point(&mut self, lat: f64, lng: f64) {
+        self.min_latitude = self.min_latitude.min(lat);
+        self.max_latitude = self.max_latitude.max(lat);
+        self.min_longitude = self.min_longitude.min(lng);
+        self.max_longitude = self.max_longitude.max(lng);
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        Self {
+            min_latitude: self.min_latitude.min(other.min_latitude),
+            max_latitude: self.max_latitude.max(other.max_latitude),
+            min_longitude: self.min_longitude.min(other.min_longitude),
+            max_longitude: self.max_longitude.max(other.max_longitude),
+        }
+    }
+}
+
+#[derive(Debug, ArgEnum, Clone, Copy)]
+pub enum NodeCacheKind {
+    Dense,
+    Sparse,
+    Compressed,
+    Auto,
+}
+
+impl From<NodeCacheKind> for CacheKind {
+    fn from(value: NodeCacheKind) -> Self {
+        match value {
+            NodeCacheKind::Dense => CacheKind::Dense,
+            NodeCacheKind::Sparse => CacheKind::Sparse,
+            NodeCacheKind::Compressed => CacheKind::Compressed,
+            NodeCacheKind::Auto => CacheKind::Auto,
+        }
+    }
+}
+
+#[derive(Debug, ArgEnum, Clone, Copy)]
+pub enum MissingNodePolicy {
+    Keep,
+    Drop,
+    Truncate,
+}
+
+impl From<MissingNodePolicy> for MissingNodes {
+    fn from(value: MissingNodePolicy) -> Self {
+        match value {
+            MissingNodePolicy::Keep => MissingNodes::Keep,
+            MissingNodePolicy::Drop => MissingNodes::Drop,
+            MissingNodePolicy::Truncate => MissingNodes::Truncate,
+        }
+    }
+}
+
 #[repr(i32)]
 #[derive(Debug, ArgEnum, Clone, Copy)]
 pub enum MemAdvice {
@@ -212,6 +315,19 @@ pub struct OptAdvice {
     advice: Vec<MemAdvice>,
 }
 
+impl OptAdvice {
+    /// Memmap advice as expected by the planetiler library.
+    #[cfg(unix)]
+    pub fn values(&self) -> Vec<Advice> {
+        self.advice.iter().map(|adv| Advice::from(*adv)).collect()
+    }
+
+    #[cfg(not(unix))]
+    pub fn values(&self) -> Vec<Advice> {
+        Vec::new()
+    }
+}
+
 pub fn advise_cache(cache: &DenseFileCache, advice: &OptAdvice) -> Result<(), Error> {
     #[cfg(unix)]
     for advice in &advice.advice {
@@ -224,55 +340,13 @@ pub fn advise_cache(cache: &DenseFileCache, advice: &OptAdvice) -> Result<(), Er
     Ok(())
 }
 
-#[derive(Clone, Debug)]
-pub struct NodeStats {
-    pub node_count: usize,
-    pub min_node_id: i64,
-    pub max_node_id: i64,
-    pub min_latitude: f64,
-    pub max_latitude: f64,
-    pub min_longitude: f64,
-    pub max_longitude: f64,
-}
-
-impl NodeStats {
-    pub fn add_node(&mut self, node_id: i64, lat: f64, lng: f64) {
-        *self = Self {
-            node_count: self.node_count + 1,
-            min_node_id: self.min_node_id.min(node_id),
-            max_node_id: self.max_node_id.max(node_id),
-            min_latitude: self.min_latitude.min(lat),
-            max_latitude: self.max_latitude.max(lat),
-            min_longitude: self.min_longitude.min(lng),
-            max_longitude: self.max_longitude.max(lng),
-        };
-    }
-}
-
-impl Default for NodeStats {
-    fn default() -> Self {
-        Self {
-            node_count: 0,
-            min_node_id: i64::MAX,
-            max_node_id: i64::MIN,
-            min_latitude: 0.0,
-            max_latitude: 0.0,
-            min_longitude: 0.0,
-            max_longitude: 0.0,
+/// Open a dense or sparse node cache, advising the memmap of a dense one.
+pub fn open_node_cache(path: &Path, advice: &OptAdvice) -> Result<NodeCache, Error> {
+    let cache = open_cache(path, &advice.values())?;
+    if let NodeCache::Dense(_) = cache {
+        for adv in advice.values() {
+            println!("Advising memmap as {adv:?}");
         }
     }
-}
-
-impl AddAssign for NodeStats {
-    fn add_assign(&mut self, other: Self) {
-        *self = Self {
-            node_count: self.node_count + other.node_count,
-            min_node_id: self.min_node_id.min(other.min_node_id),
-            max_node_id: self.max_node_id.max(other.max_node_id),
-            min_latitude: self.min_latitude.min(other.min_latitude),
-            max_latitude: self.max_latitude.max(other.max_latitude),
-            min_longitude: self.min_longitude.min(other.min_longitude),
-            max_longitude: self.max_longitude.max(other.max_longitude),
-        };
-    }
+    Ok(cache)
 }
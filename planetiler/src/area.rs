@@ -0,0 +1,237 @@
+use geos::{CoordSeq, GResult, Geom, Geometry};
+
+use crate::profile::Tags;
+
+/// Keys that make a closed way an area, with the values that are exceptions to that rule.
+/// Adapted from the `areaKeys` list of the id-tagging-schema project.
+const AREA_KEYS: &[(&str, &[&str])] = &[
+    (
+        "aerialway",
+        &[
+            "cable_car",
+            "chair_lift",
+            "drag_lift",
+            "gondola",
+            "goods",
+            "j-bar",
+            "magic_carpet",
+            "mixed_lift",
+            "platter",
+            "rope_tow",
+            "t-bar",
+            "zip_line",
+        ],
+    ),
+    (
+        "aeroway",
+        &["jet_bridge", "parking_position", "runway", "taxiway"],
+    ),
+    ("allotments", &[]),
+    ("amenity", &["bench", "line"]),
+    ("area:highway", &[]),
+    (
+        "attraction",
+        &[
+            "dark_ride",
+            "river_rafting",
+            "summer_toboggan",
+            "train",
+            "water_slide",
+        ],
+    ),
+    ("boundary", &["administrative", "political", "postal_code"]),
+    ("bridge:support", &[]),
+    ("building", &[]),
+    ("building:part", &[]),
+    ("club", &[]),
+    ("craft", &[]),
+    ("disused:amenity", &[]),
+    ("disused:shop", &[]),
+    (
+        "emergency",
+        &[
+            "designated",
+            "destination",
+            "no",
+            "official",
+            "private",
+            "yes",
+        ],
+    ),
+    ("golf", &["cartpath", "hole", "path"]),
+    ("healthcare", &[]),
+    ("historic", &["citywalls"]),
+    ("indoor", &["corridor", "wall"]),
+    ("industrial", &[]),
+    ("internet_access", &[]),
+    ("junction", &[]),
+    ("landuse", &[]),
+    ("leisure", &["slipway", "track"]),
+    (
+        "man_made",
+        &[
+            "breakwater",
+            "crane",
+            "cutline",
+            "dyke",
+            "embankment",
+            "groyne",
+            "pier",
+            "pipeline",
+            "torii",
+            "video_wall",
+        ],
+    ),
+    ("military", &["trench"]),
+    (
+        "natural",
+        &[
+            "arete",
+            "bay",
+            "cliff",
+            "coastline",
+            "ridge",
+            "strait",
+            "tree_row",
+            "valley",
+        ],
+    ),
+    ("office", &[]),
+    ("place", &[]),
+    ("playground", &["balancebeam", "slide", "zipwire"]),
+    ("police", &[]),
+    ("power", &["cable", "line", "minor_line"]),
+    ("public_transport", &["platform"]),
+    ("residential", &[]),
+    ("shop", &[]),
+    ("telecom", &[]),
+    ("tourism", &["artwork", "attraction"]),
+    (
+        "waterway",
+        &[
+            "canal",
+            "dam",
+            "ditch",
+            "drain",
+            "fish_pass",
+            "lock_gate",
+            "river",
+            "stream",
+            "tidal_channel",
+            "weir",
+        ],
+    ),
+];
+
+/// Decide if a closed way with these tags represents an area rather than a closed line.
+/// An explicit `area=yes/no` always wins. Otherwise it is an area if any of its tags has
+/// a known area key, unless the value is `no` or one of the key's exceptions,
+/// regardless of the order of the tags.
+pub fn is_area(tags: &Tags) -> bool {
+    match tags.get("area") {
+        Some("yes") => return true,
+        Some("no") => return false,
+        _ => {}
+    }
+    tags.iter().any(|(key, value)| {
+        if value == "no" {
+            return false;
+        }
+        AREA_KEYS
+            .iter()
+            .any(|(area_key, exceptions)| key == *area_key && !exceptions.contains(&value))
+    })
+}
+
+/// A closed ring has at least 4 points, with the first and the last one being the same.
+pub fn is_closed(coords: &[[f64; 2]]) -> bool {
+    coords.len() >= 4 && coords.first() == coords.last()
+}
+
+/// Signed area of a closed ring using the shoelace formula.
+/// Positive for counter-clockwise rings with the Y axis pointing up (e.g. lon/lat).
+pub fn signed_area(ring: &[[f64; 2]]) -> f64 {
+    ring.windows(2)
+        .map(|w| w[0][0] * w[1][1] - w[1][0] * w[0][1])
+        .sum::<f64>()
+        / 2.0
+}
+
+pub struct WayGeometry<'a> {
+    pub geometry: Geometry<'a>,
+    pub is_polygon: bool,
+    /// The polygon ring was invalid (e.g. self-intersecting) and had to be repaired
+    pub repaired: bool,
+}
+
+/// Build a geometry for a way from its (lon, lat) points: a polygon for closed ways
+/// that are areas according to their tags, or a line string otherwise.
+/// Polygon exteriors are oriented counter-clockwise, following the right-hand rule.
+pub fn build_way_geometry<'a>(tags: &Tags, coords: &[[f64; 2]]) -> GResult<WayGeometry<'a>> {
+    if !is_closed(coords) || !is_area(tags) {
+        return Ok(WayGeometry {
+            geometry: Geometry::create_line_string(CoordSeq::new_from_vec(coords)?)?,
+            is_polygon: false,
+            repaired: false,
+        });
+    }
+    let ring = if signed_area(coords) < 0.0 {
+        let mut reversed = coords.to_vec();
+        reversed.reverse();
+        Geometry::create_linear_ring(CoordSeq::new_from_vec(&reversed)?)?
+    } else {
+        Geometry::create_linear_ring(CoordSeq::new_from_vec(coords)?)?
+    };
+    let polygon = Geometry::create_polygon(ring, Vec::new())?;
+    if polygon.is_valid() {
+        Ok(WayGeometry {
+            geometry: polygon,
+            is_polygon: true,
+            repaired: false,
+        })
+    } else {
+        Ok(WayGeometry {
+            geometry: polygon.buffer(0.0, 8)?,
+            is_polygon: true,
+            repaired: true,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn area(tags: &[(&str, &str)]) -> bool {
+        is_area(&Tags::new(tags.iter().copied()))
+    }
+
+    #[test]
+    fn test_is_area() {
+        assert!(area(&[("building", "yes")]));
+        assert!(area(&[("landuse", "forest")]));
+        assert!(area(&[("amenity", "parking")]));
+        assert!(!area(&[("amenity", "bench")]));
+        assert!(area(&[("highway", "pedestrian"), ("area", "yes")]));
+        assert!(!area(&[("highway", "residential")]));
+        assert!(!area(&[("natural", "coastline")]));
+        assert!(!area(&[("building", "yes"), ("area", "no")]));
+        assert!(!area(&[("building", "no")]));
+        // Conflicting keys: any area key makes it an area, in any order
+        assert!(area(&[("amenity", "bench"), ("landuse", "grass")]));
+        assert!(area(&[("landuse", "grass"), ("amenity", "bench")]));
+        assert!(area(&[("building", "no"), ("amenity", "parking")]));
+        assert!(!area(&[]));
+    }
+
+    #[test]
+    fn test_winding() {
+        let ccw = [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 0.0]];
+        assert!(is_closed(&ccw));
+        assert!(signed_area(&ccw) > 0.0);
+        let mut cw = ccw;
+        cw.reverse();
+        assert!(signed_area(&cw) < 0.0);
+        assert!(!is_closed(&ccw[..3]));
+    }
+}
@@ -0,0 +1,476 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{bail, ensure, Context, Result};
+use osmpbf::{Blob, BlobReader, BlobType, ByteOffset};
+use rayon::iter::{ParallelBridge, ParallelIterator};
+
+use crate::cache_meta::{file_checksum, file_version};
+use crate::pbf::decode_data_block_or_skip;
+use crate::varint::read_u64;
+
+/// First bytes of a blob index file.
+pub const BLOB_INDEX_MAGIC: &[u8; 8] = b"PTBLOBI2";
+
+/// magic, PBF file size, modification time and checksum, and the number of entries
+const HEADER_SIZE: usize = 8 + 4 * 8;
+
+/// offset, blob kind, and a presence flag with min and max ID for each entity kind
+const ENTRY_SIZE: usize = 8 + 1 + 3 * 17;
+
+/// Kind of OSM entities stored in a data blob, in the order of a sorted PBF file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum EntityKind {
+    Node,
+    Way,
+    Relation,
+}
+
+impl EntityKind {
+    const ALL: [EntityKind; 3] = [EntityKind::Node, EntityKind::Way, EntityKind::Relation];
+}
+
+/// Type of a blob in a PBF file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlobKind {
+    Header,
+    Data,
+    Unknown,
+    /// Data blob that could not be decoded, and was skipped because of `skip_bad_blobs`
+    Bad,
+}
+
+/// Smallest and largest ID of the entities of one kind in a blob.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IdRange {
+    pub min: i64,
+    pub max: i64,
+}
+
+impl IdRange {
+    fn add(range: &mut Option<IdRange>, id: i64) {
+        *range = Some(match *range {
+            Some(r) => IdRange {
+                min: r.min.min(id),
+                max: r.max.max(id),
+            },
+            None => IdRange { min: id, max: id },
+        });
+    }
+}
+
+/// Index entry of a single blob.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlobEntry {
+    pub offset: u64,
+    pub kind: BlobKind,
+    /// ID ranges of nodes, ways, and relations in the blob, None if it has none of that kind
+    pub ids: [Option<IdRange>; 3],
+}
+
+impl BlobEntry {
+    /// ID range of the given entity kind, or None if the blob has none of them.
+    pub fn ids(&self, kind: EntityKind) -> Option<IdRange> {
+        self.ids[kind as usize]
+    }
+}
+
+/// Offsets, types, and entity ID ranges of all blobs of a PBF file. Building it requires
+/// decoding the whole file once, and the saved index lets readers seek straight to the
+/// blobs with the entities they need.
+///
+/// ```no_run
+/// use osmpbf::{BlobReader, ByteOffset};
+/// use planetiler::blob_index::{BlobIndex, EntityKind};
+///
+/// let index = BlobIndex::load_or_build("planet.osm.pbf", "planet.osm.pbf.blobs", false)?;
+/// let mut reader = BlobReader::from_path("planet.osm.pbf")?;
+/// if let Some(offset) = index.first_offset(EntityKind::Way) {
+///     reader.seek(ByteOffset(offset))?;
+/// }
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlobIndex {
+    /// Size and modification time of the indexed PBF file
+    pbf_version: (u64, u64),
+    /// Checksum of the start and the end of the indexed PBF file, see [`file_checksum`]
+    pbf_checksum: u32,
+    /// Sorted by offset
+    entries: Vec<BlobEntry>,
+}
+
+impl BlobIndex {
+    /// Decode all blobs of the PBF file in parallel to index them.
+    /// With `skip_bad_blobs`, blobs that cannot be decoded are recorded as [`BlobKind::Bad`].
+    pub fn build(pbf_file: impl AsRef<Path>, skip_bad_blobs: bool) -> Result<Self> {
+        let pbf_file = pbf_file.as_ref();
+        let entries = Mutex::new(Vec::new());
+        BlobReader::from_path(pbf_file)?
+            .par_bridge()
+            .try_for_each(|blob| -> Result<()> {
+                if let Some(entry) = index_blob(blob, skip_bad_blobs)? {
+                    entries.lock().unwrap().push(entry);
+                }
+                Ok(())
+            })?;
+        let mut entries = entries.into_inner().unwrap();
+        entries.sort_unstable_by_key(|e| e.offset);
+        Ok(Self {
+            pbf_version: file_version(pbf_file)?,
+            pbf_checksum: file_checksum(pbf_file)?,
+            entries,
+        })
+    }
+
+    /// Read the index from `index_file` if it was built from the same PBF file,
+    /// otherwise build it and save it to `index_file`. A saved index with bad blobs
+    /// is only used with `skip_bad_blobs`, same as when building it.
+    pub fn load_or_build(
+        pbf_file: impl AsRef<Path>,
+        index_file: impl AsRef<Path>,
+        skip_bad_blobs: bool,
+    ) -> Result<Self> {
+        let (pbf_file, index_file) = (pbf_file.as_ref(), index_file.as_ref());
+        if index_file.exists() {
+            let index = Self::read(index_file)?;
+            if index.matches(pbf_file)? {
+                ensure!(
+                    skip_bad_blobs || index.bad_blobs() == 0,
+                    "{} blobs of {} cannot be decoded according to the blob index {}",
+                    index.bad_blobs(),
+                    pbf_file.display(),
+                    index_file.display()
+                );
+                return Ok(index);
+            }
+        }
+        let index = Self::build(pbf_file, skip_bad_blobs)?;
+        index.write(index_file)?;
+        Ok(index)
+    }
+
+    /// Check if `pbf_file` has the same size, modification time, and checksum as the one
+    /// the index was built from.
+    pub fn matches(&self, pbf_file: &Path) -> Result<bool> {
+        Ok(file_version(pbf_file)? == self.pbf_version
+            && file_checksum(pbf_file)? == self.pbf_checksum)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let file = File::create(path)
+            .with_context(|| format!("Unable to create blob index {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(BLOB_INDEX_MAGIC)?;
+        for v in [
+            self.pbf_version.0,
+            self.pbf_version.1,
+            u64::from(self.pbf_checksum),
+            self.entries.len() as u64,
+        ] {
+            writer.write_all(&v.to_le_bytes())?;
+        }
+        for entry in &self.entries {
+            writer.write_all(&entry.offset.to_le_bytes())?;
+            writer.write_all(&[entry.kind as u8])?;
+            for range in entry.ids {
+                let IdRange { min, max } = range.unwrap_or(IdRange { min: 0, max: 0 });
+                writer.write_all(&[u8::from(range.is_some())])?;
+                writer.write_all(&min.to_le_bytes())?;
+                writer.write_all(&max.to_le_bytes())?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Unable to open blob index {}", path.display()))?;
+        let mut data = Vec::new();
+        BufReader::new(file).read_to_end(&mut data)?;
+        ensure!(
+            data.len() >= HEADER_SIZE && data[..8] == BLOB_INDEX_MAGIC[..],
+            "{} is not a blob index",
+            path.display()
+        );
+        let count = read_u64(&data[32..]) as usize;
+        ensure!(
+            Some(data.len()) == count.checked_mul(ENTRY_SIZE).map(|len| len + HEADER_SIZE),
+            "Blob index {} is truncated",
+            path.display()
+        );
+        let pbf_checksum = u32::try_from(read_u64(&data[24..]))
+            .with_context(|| format!("Blob index {} is corrupted", path.display()))?;
+        let entries = data[HEADER_SIZE..]
+            .chunks_exact(ENTRY_SIZE)
+            .map(parse_entry)
+            .collect::<Result<_>>()
+            .with_context(|| format!("Blob index {} is corrupted", path.display()))?;
+        Ok(Self {
+            pbf_version: (read_u64(&data[8..]), read_u64(&data[16..])),
+            pbf_checksum,
+            entries,
+        })
+    }
+
+    /// All blobs, sorted by offset.
+    pub fn entries(&self) -> &[BlobEntry] {
+        &self.entries
+    }
+
+    /// Number of blobs that could not be decoded, see [`BlobKind::Bad`].
+    pub fn bad_blobs(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|e| e.kind == BlobKind::Bad)
+            .count()
+    }
+
+    /// Bytes from the blob at `offset` to the next indexed blob or to the end of the file,
+    /// i.e. the size of the blob with its header, e.g. to know how much reading some blobs takes.
+    pub fn blob_len(&self, offset: u64) -> u64 {
+        let next = self.entries.partition_point(|e| e.offset <= offset);
+        match self.entries.get(next) {
+            Some(entry) => entry.offset - offset,
+            None => self.pbf_version.0.saturating_sub(offset),
+        }
+    }
+
+    /// Offset of the first blob with entities of the given kind, e.g. to skip all node blocks.
+    pub fn first_offset(&self, kind: EntityKind) -> Option<u64> {
+        self.blobs_with(kind).next().map(|e| e.offset)
+    }
+
+    /// Blobs with entities of the given kind, sorted by offset.
+    pub fn blobs_with(&self, kind: EntityKind) -> impl Iterator<Item = &BlobEntry> {
+        self.entries.iter().filter(move |e| e.ids(kind).is_some())
+    }
+
+    /// Check that the file is sorted by type then ID, as declared by the
+    /// [`crate::pbf::SORT_TYPE_THEN_ID`] header feature: no blob may have an entity
+    /// of an earlier kind, or an ID of the same kind not larger than one of an earlier blob.
+    /// The order of entities inside of each blob is not checked.
+    pub fn check_sorted(&self) -> Result<()> {
+        let mut last: Option<(EntityKind, i64, u64)> = None;
+        for entry in &self.entries {
+            for kind in EntityKind::ALL {
+                let ids = match entry.ids(kind) {
+                    Some(ids) => ids,
+                    None => continue,
+                };
+                if let Some((last_kind, last_id, last_offset)) = last {
+                    if (kind, ids.min) <= (last_kind, last_id) {
+                        bail!(
+                            "The PBF file is not sorted by type then ID: {kind:?} {} in the blob \
+                             at offset {} comes after {last_kind:?} {last_id} at offset {last_offset}",
+                            ids.min,
+                            entry.offset
+                        );
+                    }
+                }
+                last = Some((kind, ids.max, entry.offset));
+            }
+        }
+        Ok(())
+    }
+
+    /// Blobs that may contain entities of the given kind with IDs in the range.
+    pub fn blobs_in_range(
+        &self,
+        kind: EntityKind,
+        ids: RangeInclusive<i64>,
+    ) -> impl Iterator<Item = &BlobEntry> {
+        self.entries.iter().filter(move |e| match e.ids(kind) {
+            Some(r) => r.min <= *ids.end() && r.max >= *ids.start(),
+            None => false,
+        })
+    }
+}
+
+/// Read blobs at the given offsets, e.g. the ones returned by [`BlobIndex::blobs_with`].
+pub fn read_blobs_at(
+    pbf_file: impl AsRef<Path>,
+    offsets: Vec<u64>,
+) -> Result<impl Iterator<Item = osmpbf::Result<Blob>>> {
+    let mut reader = BlobReader::from_path(pbf_file)?;
+    Ok(offsets
+        .into_iter()
+        .map(move |offset| reader.blob_from_offset(ByteOffset(offset))))
+}
+
+fn index_blob(blob: osmpbf::Result<Blob>, skip_bad_blobs: bool) -> Result<Option<BlobEntry>> {
+    let blob = blob.context("Unable to read blob")?;
+    let offset = blob.offset().context("Unable to get offset of a blob")?.0;
+    let kind = match blob.get_type() {
+        BlobType::OsmHeader => BlobKind::Header,
+        BlobType::OsmData => BlobKind::Data,
+        BlobType::Unknown(_) => BlobKind::Unknown,
+    };
+    let mut entry = BlobEntry {
+        offset,
+        kind,
+        ids: [None; 3],
+    };
+    if kind != BlobKind::Data {
+        return Ok(Some(entry));
+    }
+    let mut bad_blobs = 0;
+    let data = match decode_data_block_or_skip(Ok(blob), skip_bad_blobs, &mut bad_blobs)? {
+        Some(data) => data,
+        None => {
+            // Keep the offset, so that the size of the previous blob is still known
+            entry.kind = BlobKind::Bad;
+            return Ok(Some(entry));
+        }
+    };
+    let [nodes, ways, relations] = &mut entry.ids;
+    for group in data.block.groups() {
+        for node in group.nodes() {
+            IdRange::add(nodes, node.id());
+        }
+        for node in group.dense_nodes() {
+            IdRange::add(nodes, node.id());
+        }
+        for way in group.ways() {
+            IdRange::add(ways, way.id());
+        }
+        for relation in group.relations() {
+            IdRange::add(relations, relation.id());
+        }
+    }
+    Ok(Some(entry))
+}
+
+fn parse_entry(data: &[u8]) -> Result<BlobEntry> {
+    let kind = match data[8] {
+        0 => BlobKind::Header,
+        1 => BlobKind::Data,
+        2 => BlobKind::Unknown,
+        3 => BlobKind::Bad,
+        v => bail!("Unknown blob kind {v}"),
+    };
+    let mut ids = [None; 3];
+    for (idx, kind) in EntityKind::ALL.into_iter().enumerate() {
+        let pos = 9 + idx * 17;
+        if data[pos] != 0 {
+            ids[kind as usize] = Some(IdRange {
+                min: read_u64(&data[pos + 1..]) as i64,
+                max: read_u64(&data[pos + 9..]) as i64,
+            });
+        }
+    }
+    Ok(BlobEntry {
+        offset: read_u64(data),
+        kind,
+        ids,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn entry(offset: u64, nodes: Option<(i64, i64)>, ways: Option<(i64, i64)>) -> BlobEntry {
+        let range = |r: Option<(i64, i64)>| r.map(|(min, max)| IdRange { min, max });
+        BlobEntry {
+            offset,
+            kind: BlobKind::Data,
+            ids: [range(nodes), range(ways), None],
+        }
+    }
+
+    fn index() -> BlobIndex {
+        BlobIndex {
+            pbf_version: (1000, 12345),
+            pbf_checksum: 0xcafe,
+            entries: vec![
+                BlobEntry {
+                    offset: 0,
+                    kind: BlobKind::Header,
+                    ids: [None; 3],
+                },
+                entry(100, Some((1, 8000)), None),
+                entry(200, Some((8001, 20000)), None),
+                entry(300, None, Some((5, 900))),
+                entry(400, None, Some((901, 1500))),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_queries() {
+        let index = index();
+        assert_eq!(index.first_offset(EntityKind::Node), Some(100));
+        assert_eq!(index.first_offset(EntityKind::Way), Some(300));
+        assert_eq!(index.first_offset(EntityKind::Relation), None);
+        assert_eq!(index.blobs_with(EntityKind::Way).count(), 2);
+        let offsets = |kind, ids| {
+            index
+                .blobs_in_range(kind, ids)
+                .map(|e| e.offset)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(offsets(EntityKind::Node, 8000..=8001), vec![100, 200]);
+        assert!(offsets(EntityKind::Node, 20001..=30000).is_empty());
+        assert_eq!(offsets(EntityKind::Way, 1000..=1000), vec![400]);
+        assert_eq!(index.blob_len(0), 100);
+        assert_eq!(index.blob_len(300), 100);
+        assert_eq!(index.blob_len(400), 600);
+    }
+
+    #[test]
+    fn test_check_sorted() {
+        let mut index = index();
+        index.check_sorted().unwrap();
+        // Nodes after ways
+        index.entries.push(entry(500, Some((20001, 30000)), None));
+        assert!(index.check_sorted().is_err());
+        // Overlapping node IDs
+        index.entries.truncate(2);
+        index.entries.push(entry(200, Some((7000, 9000)), None));
+        assert!(index.check_sorted().is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.blobs");
+        let mut index = index();
+        index.entries.push(BlobEntry {
+            offset: 500,
+            kind: BlobKind::Bad,
+            ids: [None; 3],
+        });
+        index.write(&path).unwrap();
+        assert_eq!(BlobIndex::read(&path).unwrap(), index);
+        assert_eq!(index.bad_blobs(), 1);
+        assert_eq!(index.blob_len(400), 100);
+
+        // An index with bad blobs is only reused when skipping them
+        let pbf_file = dir.path().join("test.osm.pbf");
+        std::fs::write(&pbf_file, [0; 1000]).unwrap();
+        index.pbf_version = file_version(&pbf_file).unwrap();
+        index.pbf_checksum = file_checksum(&pbf_file).unwrap();
+        index.write(&path).unwrap();
+        assert!(BlobIndex::load_or_build(&pbf_file, &path, false).is_err());
+        assert_eq!(
+            BlobIndex::load_or_build(&pbf_file, &path, true).unwrap(),
+            index
+        );
+
+        // Same size and modification time, but different contents
+        assert!(index.matches(&pbf_file).unwrap());
+        index.pbf_checksum ^= 1;
+        assert!(!index.matches(&pbf_file).unwrap());
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &data[..data.len() - 1]).unwrap();
+        assert!(BlobIndex::read(&path).is_err());
+    }
+}
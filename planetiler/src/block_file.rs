@@ -0,0 +1,246 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, ensure, Context, Result};
+
+use crate::varint::read_u64;
+
+/// first_id, last_id, offset, and length of a block, each a little endian u64
+const INDEX_ENTRY_SIZE: usize = 32;
+
+/// Index entry of a single block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockInfo {
+    pub first_id: u64,
+    pub last_id: u64,
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// Writes a file of independently encoded blocks, each covering a range of IDs.
+/// Blocks may be added from multiple threads in any order, but their ID ranges must not overlap.
+///
+/// File layout: magic bytes, the blocks, the block index sorted by the first ID,
+/// and a footer with the number of index entries and the offset of the index.
+pub struct BlockFileWriter {
+    state: Mutex<WriterState>,
+}
+
+struct WriterState {
+    writer: BufWriter<File>,
+    offset: u64,
+    index: Vec<BlockInfo>,
+}
+
+impl BlockFileWriter {
+    pub fn create(path: &Path, magic: &[u8; 8]) -> Result<Self> {
+        let file =
+            File::create(path).with_context(|| format!("Unable to create {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(magic)?;
+        Ok(Self {
+            state: Mutex::new(WriterState {
+                writer,
+                offset: magic.len() as u64,
+                index: Vec::new(),
+            }),
+        })
+    }
+
+    /// Add an encoded block with the given inclusive ID range.
+    pub fn add_block(&self, first_id: u64, last_id: u64, data: &[u8]) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.writer.write_all(data)?;
+        let info = BlockInfo {
+            first_id,
+            last_id,
+            offset: state.offset,
+            len: data.len() as u64,
+        };
+        state.offset += info.len;
+        state.index.push(info);
+        Ok(())
+    }
+
+    /// Write the block index and close the file. Returns the number of blocks.
+    /// If the ID ranges of two blocks overlap, fails with `overlap_reason` as the context,
+    /// e.g. the input not being sorted.
+    pub fn finish(self, overlap_reason: &str) -> Result<usize> {
+        let WriterState {
+            mut writer,
+            offset,
+            mut index,
+        } = self.state.into_inner().unwrap();
+        index.sort_unstable_by_key(|b| b.first_id);
+        if let Some(w) = index.windows(2).find(|w| w[1].first_id <= w[0].last_id) {
+            return Err(anyhow!(
+                "IDs {}..={} and {}..={} of two blocks overlap",
+                w[0].first_id,
+                w[0].last_id,
+                w[1].first_id,
+                w[1].last_id
+            )
+            .context(overlap_reason.to_string()));
+        }
+        for b in &index {
+            for v in [b.first_id, b.last_id, b.offset, b.len] {
+                writer.write_all(&v.to_le_bytes())?;
+            }
+        }
+        writer.write_all(&(index.len() as u64).to_le_bytes())?;
+        writer.write_all(&offset.to_le_bytes())?;
+        writer.flush()?;
+        Ok(index.len())
+    }
+}
+
+/// Reads blocks written by [`BlockFileWriter`]. Only the index is loaded on open,
+/// blocks can be read concurrently from multiple threads.
+pub struct BlockFileReader {
+    file: File,
+    index: Vec<BlockInfo>,
+}
+
+impl BlockFileReader {
+    /// Check if the file starts with the given magic bytes.
+    pub fn has_magic(path: &Path, magic: &[u8; 8]) -> Result<bool> {
+        let mut buf = [0_u8; 8];
+        let mut file =
+            File::open(path).with_context(|| format!("Unable to open {}", path.display()))?;
+        Ok(file.read_exact(&mut buf).is_ok() && &buf == magic)
+    }
+
+    pub fn open(path: &Path, magic: &[u8; 8]) -> Result<Self> {
+        ensure!(
+            Self::has_magic(path, magic)?,
+            "{} has an unexpected file type",
+            path.display()
+        );
+        let mut file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        ensure!(file_len >= 24, "{} is truncated", path.display());
+        let mut footer = [0_u8; 16];
+        file.seek(SeekFrom::Start(file_len - 16))?;
+        file.read_exact(&mut footer)?;
+        let count = read_u64(&footer[..8]) as usize;
+        let index_offset = read_u64(&footer[8..]);
+        let index_end = (count as u64)
+            .checked_mul(INDEX_ENTRY_SIZE as u64)
+            .and_then(|len| len.checked_add(index_offset));
+        ensure!(
+            index_end == Some(file_len - 16),
+            "Block index of {} is corrupted",
+            path.display()
+        );
+        let mut data = vec![0; count * INDEX_ENTRY_SIZE];
+        file.seek(SeekFrom::Start(index_offset))?;
+        file.read_exact(&mut data)?;
+        let index = data
+            .chunks_exact(INDEX_ENTRY_SIZE)
+            .map(|e| BlockInfo {
+                first_id: read_u64(&e[..8]),
+                last_id: read_u64(&e[8..16]),
+                offset: read_u64(&e[16..24]),
+                len: read_u64(&e[24..]),
+            })
+            .collect();
+        Ok(Self { file, index })
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Block index, sorted by the first ID.
+    pub fn index(&self) -> &[BlockInfo] {
+        &self.index
+    }
+
+    /// Position of the block whose ID range contains `id`, if any.
+    pub fn find(&self, id: u64) -> Option<usize> {
+        let idx = self.index.partition_point(|b| b.first_id <= id);
+        if idx > 0 && id <= self.index[idx - 1].last_id {
+            Some(idx - 1)
+        } else {
+            None
+        }
+    }
+
+    /// Read the encoded data of the block at position `idx` of the index.
+    pub fn read(&self, idx: usize) -> Result<Vec<u8>> {
+        let info = self.index[idx];
+        let mut data = vec![0; info.len as usize];
+        read_exact_at(&self.file, &mut data, info.offset)
+            .with_context(|| format!("Unable to read block at offset {}", info.offset))?;
+        Ok(data)
+    }
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    const MAGIC: &[u8; 8] = b"PTTEST01";
+
+    #[test]
+    fn test_round_trip() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.bin");
+        let writer = BlockFileWriter::create(&path, MAGIC).unwrap();
+        writer.add_block(20, 29, b"second").unwrap();
+        writer.add_block(1, 10, b"first").unwrap();
+        assert_eq!(writer.finish("").unwrap(), 2);
+
+        assert!(!BlockFileReader::has_magic(&path, b"PTTEST02").unwrap());
+        assert!(BlockFileReader::open(&path, b"PTTEST02").is_err());
+        let reader = BlockFileReader::open(&path, MAGIC).unwrap();
+        assert_eq!(reader.len(), 2);
+        assert_eq!(reader.index()[1].first_id, 20);
+        assert_eq!(reader.read(0).unwrap(), b"first");
+        assert_eq!(reader.read(1).unwrap(), b"second");
+        assert_eq!(reader.find(10), Some(0));
+        assert_eq!(reader.find(25), Some(1));
+        for id in [0, 11, 30] {
+            assert_eq!(reader.find(id), None, "id {id}");
+        }
+    }
+
+    #[test]
+    fn test_overlapping_blocks() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.bin");
+        let writer = BlockFileWriter::create(&path, MAGIC).unwrap();
+        writer.add_block(1, 10, b"a").unwrap();
+        writer.add_block(5, 20, b"b").unwrap();
+        let err = writer.finish("Test blocks are not sorted").unwrap_err();
+        assert_eq!(err.to_string(), "Test blocks are not sorted");
+    }
+}
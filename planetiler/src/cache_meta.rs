@@ -0,0 +1,313 @@
+use std::fs::{remove_file, File};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anyhow::{bail, Context, Result};
+use flate2::Crc;
+use serde_json::{json, Value};
+
+use crate::node_cache::{CacheKind, NodeCacheInfo, NodeStats};
+
+/// Metadata of a node cache, stored next to it as `<cache file>.meta.json`.
+/// It lets later runs skip straight to the ways of the PBF file without scanning its nodes,
+/// and detect a cache built from a different PBF file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NodeCacheMeta {
+    /// Absolute path of the PBF file the cache was built from
+    pub pbf_file: PathBuf,
+    pub pbf_size: u64,
+    /// Modification time of the PBF file, in seconds since the Unix epoch
+    pub pbf_mtime: u64,
+    /// See [`file_checksum`]
+    pub pbf_checksum: u32,
+    /// Offset of the first block with ways or relations, `u64::MAX` if there are none
+    pub first_way_block: u64,
+    /// Offset of the first block with relations, `u64::MAX` if there are none
+    pub first_relation_block: u64,
+    /// Number of blobs that could not be decoded and were skipped
+    pub bad_blobs: usize,
+    pub kind: CacheKind,
+    pub stats: NodeStats,
+}
+
+impl NodeCacheMeta {
+    /// Describe a cache just built from `pbf_file`.
+    pub fn new(pbf_file: &Path, info: &NodeCacheInfo) -> Result<Self> {
+        let (pbf_size, pbf_mtime) = file_version(pbf_file)?;
+        Ok(Self {
+            pbf_file: pbf_file
+                .canonicalize()
+                .with_context(|| format!("Unable to resolve path {}", pbf_file.display()))?,
+            pbf_size,
+            pbf_mtime,
+            pbf_checksum: file_checksum(pbf_file)?,
+            first_way_block: info.first_way_block,
+            first_relation_block: info.first_relation_block,
+            bad_blobs: info.bad_blobs,
+            kind: info.kind,
+            stats: info.stats.clone(),
+        })
+    }
+
+    /// Path of the metadata file of a node cache.
+    pub fn path(cache_file: &Path) -> PathBuf {
+        let mut path = cache_file.as_os_str().to_owned();
+        path.push(".meta.json");
+        PathBuf::from(path)
+    }
+
+    pub fn write(&self, cache_file: &Path) -> Result<()> {
+        let path = Self::path(cache_file);
+        let file =
+            File::create(&path).with_context(|| format!("Unable to create {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer_pretty(&mut writer, &self.to_json())?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Read the metadata of a node cache, or None if it has no metadata file.
+    pub fn read(cache_file: &Path) -> Result<Option<Self>> {
+        let path = Self::path(cache_file);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context(format!("Unable to open {}", path.display())),
+        };
+        let value: Value = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("Unable to parse {}", path.display()))?;
+        Self::from_json(&value)
+            .map(Some)
+            .with_context(|| format!("Invalid node cache metadata {}", path.display()))
+    }
+
+    /// Delete the metadata file of a node cache, if it exists.
+    pub fn remove(cache_file: &Path) -> Result<()> {
+        let path = Self::path(cache_file);
+        match remove_file(&path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(e).context(format!("Unable to delete {}", path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Read the metadata of a node cache, failing if the cache was built from a different
+    /// PBF file. Returns None if the cache has no metadata file.
+    pub fn read_for(cache_file: &Path, pbf_file: &Path) -> Result<Option<Self>> {
+        let meta = match Self::read(cache_file)? {
+            Some(meta) => meta,
+            None => return Ok(None),
+        };
+        if !meta.matches(pbf_file)? {
+            bail!(
+                "Node cache {} was built from {} ({} bytes, modified at {}), \
+                 which is not the same file as {}",
+                cache_file.display(),
+                meta.pbf_file.display(),
+                meta.pbf_size,
+                meta.pbf_mtime,
+                pbf_file.display()
+            );
+        }
+        Ok(Some(meta))
+    }
+
+    /// Check if `pbf_file` has the same size, modification time, and checksum as the one
+    /// the cache was built from. The path is not compared, so that the files can be moved.
+    pub fn matches(&self, pbf_file: &Path) -> Result<bool> {
+        Ok(file_version(pbf_file)? == (self.pbf_size, self.pbf_mtime)
+            && file_checksum(pbf_file)? == self.pbf_checksum)
+    }
+
+    /// Result of building the node cache, as returned by the builder.
+    pub fn info(&self) -> NodeCacheInfo {
+        NodeCacheInfo {
+            first_way_block: self.first_way_block,
+            first_relation_block: self.first_relation_block,
+            bad_blobs: self.bad_blobs,
+            stats: self.stats.clone(),
+            kind: self.kind,
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let stats = &self.stats;
+        json!({
+            "pbf_file": self.pbf_file.to_string_lossy(),
+            "pbf_size": self.pbf_size,
+            "pbf_mtime": self.pbf_mtime,
+            "pbf_checksum": self.pbf_checksum,
+            "first_way_block": self.first_way_block,
+            "first_relation_block": self.first_relation_block,
+            "bad_blobs": self.bad_blobs,
+            "kind": format!("{:?}", self.kind),
+            "stats": {
+                "node_count": stats.node_count,
+                "min_node_id": stats.min_node_id,
+                "max_node_id": stats.max_node_id,
+                "min_latitude": stats.min_latitude,
+                "max_latitude": stats.max_latitude,
+                "min_longitude": stats.min_longitude,
+                "max_longitude": stats.max_longitude,
+            },
+        })
+    }
+
+    fn from_json(value: &Value) -> Result<Self> {
+        let stats = &value["stats"];
+        let kind = match get_str(value, "kind")? {
+            "Dense" => CacheKind::Dense,
+            "Sparse" => CacheKind::Sparse,
+            "Compressed" => CacheKind::Compressed,
+            v => bail!("Unknown cache kind {v}"),
+        };
+        Ok(Self {
+            pbf_file: PathBuf::from(get_str(value, "pbf_file")?),
+            pbf_size: get_u64(value, "pbf_size")?,
+            pbf_mtime: get_u64(value, "pbf_mtime")?,
+            pbf_checksum: u32::try_from(get_u64(value, "pbf_checksum")?)?,
+            first_way_block: get_u64(value, "first_way_block")?,
+            first_relation_block: get_u64(value, "first_relation_block")?,
+            bad_blobs: get_u64(value, "bad_blobs")? as usize,
+            kind,
+            stats: NodeStats {
+                node_count: get_u64(stats, "node_count")? as usize,
+                min_node_id: get_i64(stats, "min_node_id")?,
+                max_node_id: get_i64(stats, "max_node_id")?,
+                min_latitude: get_f64(stats, "min_latitude")?,
+                max_latitude: get_f64(stats, "max_latitude")?,
+                min_longitude: get_f64(stats, "min_longitude")?,
+                max_longitude: get_f64(stats, "max_longitude")?,
+            },
+        })
+    }
+}
+
+/// Size and modification time (seconds since the Unix epoch) of a file.
+pub(crate) fn file_version(path: &Path) -> Result<(u64, u64)> {
+    let meta = path
+        .metadata()
+        .with_context(|| format!("Unable to read metadata of {}", path.display()))?;
+    let mtime = meta.modified()?.duration_since(UNIX_EPOCH)?.as_secs();
+    Ok((meta.len(), mtime))
+}
+
+/// Bytes at the start and at the end of a file covered by [`file_checksum`].
+const CHECKSUM_BYTES: u64 = 64 * 1024;
+
+/// CRC32 of the first and the last [`CHECKSUM_BYTES`] of a file. For a PBF file, that is
+/// its header blob with the replication timestamp and its last blob, so that a different
+/// file with the same size and modification time, e.g. a copy of another extract, is detected
+/// without reading the whole file.
+pub(crate) fn file_checksum(path: &Path) -> Result<u32> {
+    let mut file =
+        File::open(path).with_context(|| format!("Unable to open {}", path.display()))?;
+    let len = file.metadata()?.len();
+    let mut crc = Crc::new();
+    let mut buf = vec![0; len.min(CHECKSUM_BYTES) as usize];
+    file.read_exact(&mut buf)?;
+    crc.update(&buf);
+    let tail_start = len.saturating_sub(CHECKSUM_BYTES).max(CHECKSUM_BYTES);
+    if tail_start < len {
+        buf.resize((len - tail_start) as usize, 0);
+        file.seek(SeekFrom::Start(tail_start))?;
+        file.read_exact(&mut buf)?;
+        crc.update(&buf);
+    }
+    Ok(crc.sum())
+}
+
+fn get_str<'a>(value: &'a Value, key: &str) -> Result<&'a str> {
+    value[key]
+        .as_str()
+        .with_context(|| format!("Missing {key}"))
+}
+
+fn get_u64(value: &Value, key: &str) -> Result<u64> {
+    value[key]
+        .as_u64()
+        .with_context(|| format!("Missing {key}"))
+}
+
+fn get_i64(value: &Value, key: &str) -> Result<i64> {
+    value[key]
+        .as_i64()
+        .with_context(|| format!("Missing {key}"))
+}
+
+fn get_f64(value: &Value, key: &str) -> Result<f64> {
+    value[key]
+        .as_f64()
+        .with_context(|| format!("Missing {key}"))
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let dir = tempdir().unwrap();
+        let pbf_file = dir.path().join("test.osm.pbf");
+        let cache_file = dir.path().join("test.cache");
+        std::fs::write(&pbf_file, b"not really a pbf").unwrap();
+        NodeCacheMeta::remove(&cache_file).unwrap();
+        assert_eq!(NodeCacheMeta::read(&cache_file).unwrap(), None);
+
+        let mut stats = NodeStats::default();
+        stats.add_node(10, 1.5, -2.5);
+        stats.add_node(7_000_000_000, -80.0, 170.0);
+        let info = NodeCacheInfo {
+            first_way_block: 1234,
+            first_relation_block: u64::MAX,
+            bad_blobs: 1,
+            stats,
+            kind: CacheKind::Sparse,
+        };
+        let meta = NodeCacheMeta::new(&pbf_file, &info).unwrap();
+        meta.write(&cache_file).unwrap();
+        assert_eq!(
+            NodeCacheMeta::path(&cache_file),
+            dir.path().join("test.cache.meta.json")
+        );
+        let read = NodeCacheMeta::read_for(&cache_file, &pbf_file).unwrap();
+        assert_eq!(read.as_ref(), Some(&meta));
+        assert_eq!(read.unwrap().info().first_way_block, 1234);
+        // Same size and modification time, but different content
+        let other = NodeCacheMeta {
+            pbf_checksum: meta.pbf_checksum ^ 1,
+            ..meta.clone()
+        };
+        assert!(!other.matches(&pbf_file).unwrap());
+
+        std::fs::write(&pbf_file, b"a different pbf file").unwrap();
+        assert!(!meta.matches(&pbf_file).unwrap());
+        assert!(NodeCacheMeta::read_for(&cache_file, &pbf_file).is_err());
+
+        NodeCacheMeta::remove(&cache_file).unwrap();
+        assert!(!NodeCacheMeta::path(&cache_file).exists());
+    }
+
+    #[test]
+    fn test_checksum() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.osm.pbf");
+        let mut data = vec![0_u8; 200_000];
+        std::fs::write(&path, &data).unwrap();
+        let checksum = file_checksum(&path).unwrap();
+        data[199_999] = 1;
+        std::fs::write(&path, &data).unwrap();
+        let changed = file_checksum(&path).unwrap();
+        assert_ne!(changed, checksum);
+        // The middle of the file is not read
+        data[100_000] = 1;
+        std::fs::write(&path, &data).unwrap();
+        assert_eq!(file_checksum(&path).unwrap(), changed);
+        std::fs::write(&path, b"short").unwrap();
+        assert_ne!(file_checksum(&path).unwrap(), changed);
+    }
+}
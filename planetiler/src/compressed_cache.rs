@@ -0,0 +1,406 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{ensure, Context, Result};
+
+use crate::block_file::{BlockFileReader, BlockFileWriter};
+use crate::node_cache::NodeLookup;
+use crate::sparse_cache::{pack_e7, unpack_e7, unpack_lat_lon};
+use crate::varint::{read_varint, write_varint, zigzag_decode, zigzag_encode};
+
+/// First bytes of a compressed node cache file.
+pub const COMPRESSED_CACHE_MAGIC: &[u8; 8] = b"PTCOMPR1";
+
+/// Default number of decoded blocks kept in memory by [`CompressedFileCache`].
+pub const DEFAULT_LRU_BLOCKS: usize = 1024;
+
+/// Number of separately locked parts of the LRU of decoded blocks.
+const LRU_SHARDS: usize = 16;
+
+/// Encode nodes sorted by ID. The block starts with the node count, followed by the
+/// varint delta of each node ID from the previous one (first one is relative to itself),
+/// and zigzag varint deltas of latitude and longitude in 1e-7 degrees.
+fn encode_block(nodes: &[(u64, u64)]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(nodes.len() * 6);
+    write_varint(&mut buf, nodes.len() as u64);
+    let (mut last_id, mut last_lat, mut last_lon) = (nodes[0].0, 0_i64, 0_i64);
+    for &(id, value) in nodes {
+        let (lat, lon) = unpack_e7(value);
+        write_varint(&mut buf, id - last_id);
+        write_varint(&mut buf, zigzag_encode(i64::from(lat) - last_lat));
+        write_varint(&mut buf, zigzag_encode(i64::from(lon) - last_lon));
+        last_id = id;
+        last_lat = i64::from(lat);
+        last_lon = i64::from(lon);
+    }
+    buf
+}
+
+/// Decoded block, with packed positions in the same order as the IDs.
+#[derive(Debug, Default)]
+struct DecodedBlock {
+    ids: Vec<u64>,
+    values: Vec<u64>,
+}
+
+fn decode_block(first_id: u64, data: &[u8]) -> Result<DecodedBlock> {
+    let mut pos = 0;
+    let count = read_varint(data, &mut pos)? as usize;
+    let mut block = DecodedBlock {
+        ids: Vec::with_capacity(count),
+        values: Vec::with_capacity(count),
+    };
+    let (mut id, mut lat, mut lon) = (first_id, 0_i64, 0_i64);
+    for _ in 0..count {
+        id += read_varint(data, &mut pos)?;
+        lat += zigzag_decode(read_varint(data, &mut pos)?);
+        lon += zigzag_decode(read_varint(data, &mut pos)?);
+        block.ids.push(id);
+        block.values.push(pack_e7(lat as i32, lon as i32));
+    }
+    ensure!(
+        pos == data.len(),
+        "Unexpected data at the end of a node block"
+    );
+    Ok(block)
+}
+
+/// Writes a compressed node cache, one block per call of [`CompressedCacheWriter::add_block`].
+/// See [`BlockFileWriter`] for the file layout.
+pub struct CompressedCacheWriter {
+    writer: BlockFileWriter,
+}
+
+impl CompressedCacheWriter {
+    pub fn create(path: &Path) -> Result<Self> {
+        Ok(Self {
+            writer: BlockFileWriter::create(path, COMPRESSED_CACHE_MAGIC)
+                .context("Unable to create compressed node cache")?,
+        })
+    }
+
+    /// Add a block of (node ID, packed position) pairs, see [`crate::sparse_cache::pack_lat_lon`].
+    /// Blocks may come from multiple threads in any order, but their ID ranges must not overlap.
+    pub fn add_block(&self, mut nodes: Vec<(u64, u64)>) -> Result<()> {
+        if nodes.is_empty() {
+            return Ok(());
+        }
+        nodes.sort_unstable_by_key(|(id, _)| *id);
+        nodes.dedup_by_key(|(id, _)| *id);
+        let data = encode_block(&nodes);
+        self.writer
+            .add_block(nodes[0].0, nodes[nodes.len() - 1].0, &data)
+    }
+
+    /// Write the block index and close the file. Returns the number of blocks.
+    pub fn finish(self) -> Result<usize> {
+        self.writer
+            .finish("Node blocks overlap, the PBF file is not sorted")
+            .context("Unable to finish the compressed node cache")
+    }
+}
+
+/// Read-only node cache created by [`CompressedCacheWriter`]. Only the block index is kept
+/// in memory, blocks are read and decoded on demand, and the most recently used ones are
+/// kept in a shared LRU. Ways mostly use nodes with nearby IDs, so most lookups hit it.
+#[derive(Clone)]
+pub struct CompressedFileCache {
+    inner: Arc<Reader>,
+}
+
+struct Reader {
+    blocks: BlockFileReader,
+    /// Consecutive blocks go to different shards, so threads reading nearby ways
+    /// rarely wait for each other
+    shards: Vec<Mutex<BlockLru>>,
+    /// Lookups that failed to read or decode their block
+    read_errors: AtomicUsize,
+}
+
+impl CompressedFileCache {
+    /// Check if the file starts with the compressed cache magic bytes.
+    pub fn is_compressed_file(path: &Path) -> Result<bool> {
+        BlockFileReader::has_magic(path, COMPRESSED_CACHE_MAGIC)
+    }
+
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::open_with_capacity(path, DEFAULT_LRU_BLOCKS)
+    }
+
+    /// Open the cache, keeping up to `capacity` decoded blocks in memory.
+    pub fn open_with_capacity(path: &Path, capacity: usize) -> Result<Self> {
+        ensure!(capacity > 0, "LRU capacity must be positive");
+        let blocks = BlockFileReader::open(path, COMPRESSED_CACHE_MAGIC)
+            .with_context(|| format!("Unable to open compressed node cache {}", path.display()))?;
+        let shard_count = LRU_SHARDS.min(capacity);
+        let shards = (0..shard_count)
+            .map(|_| Mutex::new(BlockLru::new(capacity / shard_count)))
+            .collect();
+        Ok(Self {
+            inner: Arc::new(Reader {
+                blocks,
+                shards,
+                read_errors: AtomicUsize::new(0),
+            }),
+        })
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.inner.blocks.len()
+    }
+
+    /// Number of [`NodeLookup`] calls whose block could not be read or decoded.
+    /// Their nodes are reported as missing, and the first error is printed.
+    pub fn read_errors(&self) -> usize {
+        self.inner.read_errors.load(Ordering::Relaxed)
+    }
+
+    /// Packed position of the node, or None if the node is not in the cache.
+    pub fn get(&self, id: u64) -> Result<Option<u64>> {
+        let idx = match self.inner.blocks.find(id) {
+            Some(idx) => idx,
+            None => return Ok(None),
+        };
+        let block = self.block(idx)?;
+        Ok(block
+            .ids
+            .binary_search(&id)
+            .ok()
+            .map(|pos| block.values[pos]))
+    }
+
+    fn block(&self, idx: usize) -> Result<Arc<DecodedBlock>> {
+        let shard = &self.inner.shards[idx % self.inner.shards.len()];
+        if let Some(block) = shard.lock().unwrap().get(idx) {
+            return Ok(block);
+        }
+        // Decode without holding the lock, another thread might decode the same block
+        let info = self.inner.blocks.index()[idx];
+        let data = self.inner.blocks.read(idx)?;
+        let block =
+            Arc::new(decode_block(info.first_id, &data).with_context(|| {
+                format!("Unable to decode node block at offset {}", info.offset)
+            })?);
+        shard.lock().unwrap().insert(idx, block.clone());
+        Ok(block)
+    }
+}
+
+impl NodeLookup for CompressedFileCache {
+    fn get_node(&self, id: i64) -> Option<(f64, f64)> {
+        match self.get(u64::try_from(id).ok()?) {
+            Ok(value) => value.map(unpack_lat_lon),
+            Err(err) => {
+                if self.inner.read_errors.fetch_add(1, Ordering::Relaxed) == 0 {
+                    eprintln!("Nodes of unreadable compressed cache blocks are missing: {err:#}");
+                }
+                None
+            }
+        }
+    }
+}
+
+/// No entry, the end of the LRU list
+const NO_SLOT: usize = usize::MAX;
+
+/// Least recently used decoded blocks, keyed by the block position in the index.
+/// Entries form a doubly linked list from the most to the least recently used one,
+/// so lookups, insertions and evictions take constant time.
+struct BlockLru {
+    capacity: usize,
+    /// Block position -> entry slot
+    slots: HashMap<usize, usize>,
+    entries: Vec<LruEntry>,
+    /// Most recently used slot
+    head: usize,
+    /// Least recently used slot, evicted first
+    tail: usize,
+}
+
+struct LruEntry {
+    idx: usize,
+    block: Arc<DecodedBlock>,
+    prev: usize,
+    next: usize,
+}
+
+impl BlockLru {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            slots: HashMap::with_capacity(capacity),
+            entries: Vec::with_capacity(capacity),
+            head: NO_SLOT,
+            tail: NO_SLOT,
+        }
+    }
+
+    fn get(&mut self, idx: usize) -> Option<Arc<DecodedBlock>> {
+        let slot = *self.slots.get(&idx)?;
+        self.unlink(slot);
+        self.push_front(slot);
+        Some(self.entries[slot].block.clone())
+    }
+
+    fn insert(&mut self, idx: usize, block: Arc<DecodedBlock>) {
+        let slot = if let Some(&slot) = self.slots.get(&idx) {
+            self.unlink(slot);
+            self.entries[slot].block = block;
+            slot
+        } else if self.entries.len() < self.capacity {
+            self.entries.push(LruEntry {
+                idx,
+                block,
+                prev: NO_SLOT,
+                next: NO_SLOT,
+            });
+            self.slots.insert(idx, self.entries.len() - 1);
+            self.entries.len() - 1
+        } else {
+            let slot = self.tail;
+            self.unlink(slot);
+            self.slots.remove(&self.entries[slot].idx);
+            self.slots.insert(idx, slot);
+            self.entries[slot].idx = idx;
+            self.entries[slot].block = block;
+            slot
+        };
+        self.push_front(slot);
+    }
+
+    fn unlink(&mut self, slot: usize) {
+        let (prev, next) = (self.entries[slot].prev, self.entries[slot].next);
+        match prev {
+            NO_SLOT => self.head = next,
+            prev => self.entries[prev].next = next,
+        }
+        match next {
+            NO_SLOT => self.tail = prev,
+            next => self.entries[next].prev = prev,
+        }
+    }
+
+    fn push_front(&mut self, slot: usize) {
+        self.entries[slot].prev = NO_SLOT;
+        self.entries[slot].next = self.head;
+        match self.head {
+            NO_SLOT => self.tail = slot,
+            head => self.entries[head].prev = slot,
+        }
+        self.head = slot;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::sparse_cache::pack_lat_lon;
+
+    fn nodes(ids: impl Iterator<Item = u64>) -> Vec<(u64, u64)> {
+        ids.map(|id| {
+            let lat = (id % 1000) as f64 / 100.0 - 5.0;
+            (id, pack_lat_lon(lat, -lat * 2.0))
+        })
+        .collect()
+    }
+
+    #[test]
+    fn test_block_round_trip() {
+        let nodes = nodes([5, 6, 10, 1_000_000].into_iter());
+        let data = encode_block(&nodes);
+        let block = decode_block(5, &data).unwrap();
+        assert_eq!(block.ids, vec![5, 6, 10, 1_000_000]);
+        assert_eq!(
+            block.values,
+            nodes.iter().map(|(_, v)| *v).collect::<Vec<_>>()
+        );
+        assert!(decode_block(5, &data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_cache() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.cache");
+        let writer = CompressedCacheWriter::create(&path).unwrap();
+        // Blocks are added out of order, the way parallel readers produce them
+        writer.add_block(nodes(2000..3000)).unwrap();
+        writer.add_block(nodes((10..1000).step_by(3))).unwrap();
+        writer.add_block(Vec::new()).unwrap();
+        assert_eq!(writer.finish().unwrap(), 2);
+        assert!(CompressedFileCache::is_compressed_file(&path).unwrap());
+
+        // A single block LRU forces re-reading blocks
+        let cache = CompressedFileCache::open_with_capacity(&path, 1).unwrap();
+        assert_eq!(cache.block_count(), 2);
+        let expected = nodes([13, 2500, 2000, 997, 2999].into_iter());
+        for (id, value) in expected {
+            assert_eq!(cache.get(id).unwrap(), Some(value), "node {id}");
+        }
+        for id in [0, 11, 1000, 1500, 3000] {
+            assert_eq!(cache.get(id).unwrap(), None, "node {id}");
+        }
+        assert_eq!(cache.get_node(2123), Some((-3.77, 7.54)));
+        assert_eq!(cache.get_node(12), None);
+        assert_eq!(cache.get_node(-13), None);
+        assert_eq!(cache.read_errors(), 0);
+    }
+
+    #[test]
+    fn test_read_error() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.cache");
+        let writer = CompressedCacheWriter::create(&path).unwrap();
+        writer.add_block(nodes([5, 6].into_iter())).unwrap();
+        writer.finish().unwrap();
+        // Claim more nodes than the block has, right after the magic bytes
+        let mut data = std::fs::read(&path).unwrap();
+        data[COMPRESSED_CACHE_MAGIC.len()] = 100;
+        std::fs::write(&path, data).unwrap();
+
+        let cache = CompressedFileCache::open(&path).unwrap();
+        assert!(cache.get(5).is_err());
+        assert_eq!(cache.get_node(5), None);
+        assert_eq!(cache.get_node(6), None);
+        assert_eq!(cache.read_errors(), 2);
+    }
+
+    #[test]
+    fn test_overlapping_blocks() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.cache");
+        let writer = CompressedCacheWriter::create(&path).unwrap();
+        writer.add_block(nodes([1, 10].into_iter())).unwrap();
+        writer.add_block(nodes([5, 20].into_iter())).unwrap();
+        assert!(writer.finish().is_err());
+    }
+
+    #[test]
+    fn test_lru() {
+        let mut lru = BlockLru::new(2);
+        let block = Arc::new(DecodedBlock::default());
+        lru.insert(1, block.clone());
+        lru.insert(2, block.clone());
+        assert!(lru.get(1).is_some());
+        lru.insert(3, block.clone());
+        assert!(lru.get(2).is_none());
+        assert!(lru.get(1).is_some());
+        assert!(lru.get(3).is_some());
+        // 1 is now the least recently used one
+        lru.insert(3, block.clone());
+        lru.insert(4, block);
+        assert!(lru.get(1).is_none());
+        assert!(lru.get(3).is_some());
+        assert!(lru.get(4).is_some());
+        assert_eq!(lru.slots.len(), 2);
+
+        let mut single = BlockLru::new(1);
+        single.insert(1, Arc::new(DecodedBlock::default()));
+        single.insert(2, Arc::new(DecodedBlock::default()));
+        assert!(single.get(1).is_none());
+        assert!(single.get(2).is_some());
+    }
+}
@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+
+/// Larger tiles are almost never repeated, so only smaller ones are deduplicated,
+/// like in the Java planetiler.
+pub const MAX_DEDUP_TILE_SIZE: usize = 1000;
+
+/// Most distinct tiles kept for deduplication, limiting the memory to about 100 MB.
+pub const MAX_DEDUP_TILES: usize = 100_000;
+
+/// Finds earlier copies of small tiles, e.g. ocean or land fills, so that archives
+/// store their data once. Tiles are compared by their bytes, not just by a hash.
+#[derive(Debug)]
+pub struct TileDeduplicator<T> {
+    /// Tile data -> where it was written
+    tiles: HashMap<Box<[u8]>, T>,
+}
+
+impl<T: Copy> Default for TileDeduplicator<T> {
+    fn default() -> Self {
+        Self {
+            tiles: HashMap::new(),
+        }
+    }
+}
+
+impl<T: Copy> TileDeduplicator<T> {
+    /// Location of an earlier tile with the same data.
+    pub fn get(&self, data: &[u8]) -> Option<T> {
+        if data.len() > MAX_DEDUP_TILE_SIZE {
+            return None;
+        }
+        self.tiles.get(data).copied()
+    }
+
+    /// Remember where a tile was written, if it is small enough and the limit is not reached.
+    pub fn insert(&mut self, data: &[u8], location: T) {
+        if data.len() <= MAX_DEDUP_TILE_SIZE && self.tiles.len() < MAX_DEDUP_TILES {
+            self.tiles.insert(data.into(), location);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_dedup() {
+        let mut dedup = TileDeduplicator::default();
+        dedup.insert(b"ocean", 1);
+        dedup.insert(b"land", 2);
+        assert_eq!(dedup.get(b"ocean"), Some(1));
+        assert_eq!(dedup.get(b"oceans"), None);
+        let large = vec![7; MAX_DEDUP_TILE_SIZE + 1];
+        dedup.insert(&large, 3);
+        assert_eq!(dedup.get(&large), None);
+        for i in 0..MAX_DEDUP_TILES {
+            dedup.insert(&i.to_le_bytes(), 4);
+        }
+        assert_eq!(dedup.tiles.len(), MAX_DEDUP_TILES);
+        assert_eq!(dedup.get(b"land"), Some(2));
+    }
+}
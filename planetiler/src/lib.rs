@@ -0,0 +1,44 @@
+//! Building blocks for generating vector tiles from OpenStreetMap PBF files.
+//!
+//! * [`node_cache`] - cache node positions of a PBF file in a flat memory mapped file,
+//!   or in a [`sparse_cache`] for regional extracts, or a smaller [`compressed_cache`],
+//!   described by a [`cache_meta`] file next to it
+//! * [`osm_header`] - bounding box, writing program, and replication state of a PBF file
+//! * [`blob_index`] - offsets and ID ranges of PBF blobs, to seek straight to the needed ones
+//! * [`ways`] - read ways with their node positions resolved via the node cache,
+//!   or assemble them from [`way_parts`] resolved over several passes
+//! * [`multipolygon`] - assemble multipolygon and boundary relations into polygons
+//! * [`profile`] - map OSM elements to tile features
+//! * [`tiler`] and [`sorter`] - cut features into tiles, and sort them by tile on disk
+//! * [`mvt`], [`mbtiles`], [`pmtiles`] - encode tiles and write them into an archive
+//! * [`projection`] - Web Mercator projection of WGS84 coordinates
+//! * [`tile_id`] - tile coordinates, their compact encoding, and tile math
+//! * [`tile_index`] - persistent index of the tiles each OSM element was rendered into
+//! * [`tile_order`] - Hilbert and Z-order tile IDs that keep neighbouring tiles close
+//! * [`tile_set`] - compact sets of tiles, e.g. all tiles a feature touches
+
+pub mod area;
+pub mod blob_index;
+pub mod block_file;
+pub mod cache_meta;
+pub mod compressed_cache;
+mod dedup;
+pub mod mbtiles;
+pub mod multipolygon;
+pub mod mvt;
+pub mod node_cache;
+pub mod osm_header;
+pub mod pbf;
+pub mod pmtiles;
+pub mod profile;
+pub mod projection;
+pub mod sorter;
+pub mod sparse_cache;
+pub mod tile_id;
+pub mod tile_index;
+pub mod tile_order;
+pub mod tile_set;
+pub mod tiler;
+pub mod varint;
+pub mod way_parts;
+pub mod ways;
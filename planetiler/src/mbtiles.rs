@@ -0,0 +1,382 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rusqlite::{params, Connection};
+use serde_json::json;
+
+use crate::dedup::TileDeduplicator;
+use crate::osm_header::{OsmHeader, Replication};
+use crate::projection::MAX_LATITUDE;
+use crate::tile_id::{max_dimension, TileID};
+
+/// Number of tiles to insert before committing a transaction
+const BATCH_SIZE: usize = 10_000;
+
+/// Description of one layer for the `vector_layers` metadata entry.
+#[derive(Debug, Clone)]
+pub struct VectorLayer {
+    pub id: String,
+    pub description: String,
+    pub minzoom: u8,
+    pub maxzoom: u8,
+    /// Attribute name -> type, e.g. `"String"`, `"Number"`, or `"Boolean"`
+    pub fields: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MbtilesMetadata {
+    pub name: String,
+    pub description: String,
+    pub attribution: String,
+    pub version: String,
+    /// min_lon, min_lat, max_lon, max_lat
+    pub bounds: [f64; 4],
+    /// lon, lat, zoom. Computed from bounds if not set.
+    pub center: Option<(f64, f64, u8)>,
+    pub minzoom: u8,
+    pub maxzoom: u8,
+    pub vector_layers: Vec<VectorLayer>,
+    /// Replication state of the OSM data the tiles were built from, see [`Self::set_osm_header`]
+    pub replication: Replication,
+}
+
+impl Default for MbtilesMetadata {
+    fn default() -> Self {
+        Self {
+            name: "planetiler".to_string(),
+            description: String::new(),
+            attribution: String::new(),
+            version: "1.0.0".to_string(),
+            bounds: [-180.0, -MAX_LATITUDE, 180.0, MAX_LATITUDE],
+            center: None,
+            minzoom: 0,
+            maxzoom: 14,
+            vector_layers: Vec::new(),
+            replication: Replication::default(),
+        }
+    }
+}
+
+impl MbtilesMetadata {
+    /// Use the bounding box of the OSM data as bounds, clamped to the web mercator latitudes,
+    /// and keep its replication state.
+    pub fn set_osm_header(&mut self, header: &OsmHeader) {
+        if let Some([min_lon, min_lat, max_lon, max_lat]) = header.bbox {
+            self.bounds = [
+                min_lon.max(-180.0),
+                min_lat.max(-MAX_LATITUDE),
+                max_lon.min(180.0),
+                max_lat.min(MAX_LATITUDE),
+            ];
+        }
+        self.replication = header.replication.clone();
+    }
+
+    /// Copy with the bounds and replication state of the OSM header, if there is one.
+    pub(crate) fn with_osm_header(&self, header: Option<&OsmHeader>) -> Self {
+        let mut metadata = self.clone();
+        if let Some(header) = header {
+            metadata.set_osm_header(header);
+        }
+        metadata
+    }
+
+    /// `planetiler:osm:*` entries with the replication state, only the ones that are known.
+    fn replication_entries(&self) -> Vec<(&'static str, String)> {
+        let replication = &self.replication;
+        let mut entries = Vec::new();
+        if let Some(time) = replication.time() {
+            entries.push(("planetiler:osm:osmosisreplicationtime", time));
+        }
+        if let Some(sequence) = replication.sequence {
+            entries.push(("planetiler:osm:osmosisreplicationseq", sequence.to_string()));
+        }
+        if let Some(url) = &replication.base_url {
+            entries.push(("planetiler:osm:osmosisreplicationurl", url.clone()));
+        }
+        entries
+    }
+
+    /// lon, lat, zoom of the center, either explicitly set or computed from the bounds.
+    pub fn center(&self) -> (f64, f64, u8) {
+        let [min_lon, min_lat, max_lon, max_lat] = self.bounds;
+        self.center.unwrap_or((
+            (min_lon + max_lon) / 2.0,
+            (min_lat + max_lat) / 2.0,
+            self.minzoom,
+        ))
+    }
+
+    fn vector_layers_json(&self) -> Vec<serde_json::Value> {
+        self.vector_layers
+            .iter()
+            .map(|l| {
+                json!({
+                    "id": l.id,
+                    "description": l.description,
+                    "minzoom": l.minzoom,
+                    "maxzoom": l.maxzoom,
+                    "fields": l.fields,
+                })
+            })
+            .collect()
+    }
+
+    /// Metadata as a single JSON object, e.g. for the PMTiles metadata section.
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut value = json!({
+            "name": self.name,
+            "format": "pbf",
+            "type": "baselayer",
+            "description": self.description,
+            "attribution": self.attribution,
+            "version": self.version,
+            "vector_layers": self.vector_layers_json(),
+        });
+        for (name, entry) in self.replication_entries() {
+            value[name] = entry.into();
+        }
+        value
+    }
+
+    fn entries(&self) -> Vec<(&'static str, String)> {
+        let [min_lon, min_lat, max_lon, max_lat] = self.bounds;
+        let (lon, lat, zoom) = self.center();
+        let layers = self.vector_layers_json();
+        let mut entries = vec![
+            ("name", self.name.clone()),
+            ("format", "pbf".to_string()),
+            ("type", "baselayer".to_string()),
+            ("description", self.description.clone()),
+            ("attribution", self.attribution.clone()),
+            ("version", self.version.clone()),
+            ("bounds", format!("{min_lon},{min_lat},{max_lon},{max_lat}")),
+            ("center", format!("{lon},{lat},{zoom}")),
+            ("minzoom", self.minzoom.to_string()),
+            ("maxzoom", self.maxzoom.to_string()),
+            ("json", json!({ "vector_layers": layers }).to_string()),
+        ];
+        entries.extend(self.replication_entries());
+        entries
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct MbtilesStats {
+    pub tiles: usize,
+    pub unique_tiles: usize,
+    pub bytes: usize,
+}
+
+/// Writes encoded vector tiles into an MBTiles (SQLite) file.
+/// With deduplication enabled, tiles are stored using the `map`/`images` schema,
+/// so identical small blobs (e.g. ocean tiles) are stored only once.
+pub struct MbtilesWriter {
+    conn: Connection,
+    deduplicate: bool,
+    compress: bool,
+    /// Tile data -> tile_id in the images table
+    known_tiles: TileDeduplicator<i64>,
+    osm_header: Option<OsmHeader>,
+    pending: usize,
+    stats: MbtilesStats,
+}
+
+impl MbtilesWriter {
+    /// Create a new MBTiles file, replacing any existing one.
+    pub fn create(path: &Path, deduplicate: bool) -> Result<Self> {
+        if path.exists() {
+            std::fs::remove_file(path)
+                .with_context(|| format!("Unable to remove existing {}", path.display()))?;
+        }
+        let conn = Connection::open(path)
+            .with_context(|| format!("Unable to create {}", path.display()))?;
+        conn.execute_batch(
+            "PRAGMA synchronous = OFF;
+             PRAGMA journal_mode = OFF;
+             PRAGMA locking_mode = EXCLUSIVE;
+             PRAGMA page_size = 8192;
+             CREATE TABLE metadata (name TEXT, value TEXT);
+             CREATE UNIQUE INDEX name ON metadata (name);",
+        )?;
+        if deduplicate {
+            conn.execute_batch(
+                "CREATE TABLE map (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_id INTEGER);
+                 CREATE UNIQUE INDEX map_index ON map (zoom_level, tile_column, tile_row);
+                 CREATE TABLE images (tile_data BLOB, tile_id INTEGER);
+                 CREATE UNIQUE INDEX images_id ON images (tile_id);
+                 CREATE VIEW tiles AS
+                   SELECT map.zoom_level AS zoom_level,
+                          map.tile_column AS tile_column,
+                          map.tile_row AS tile_row,
+                          images.tile_data AS tile_data
+                   FROM map JOIN images ON images.tile_id = map.tile_id;",
+            )?;
+        } else {
+            conn.execute_batch(
+                "CREATE TABLE tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_data BLOB);
+                 CREATE UNIQUE INDEX tile_index ON tiles (zoom_level, tile_column, tile_row);",
+            )?;
+        }
+        conn.execute_batch("BEGIN")?;
+        Ok(Self {
+            conn,
+            deduplicate,
+            compress: true,
+            known_tiles: TileDeduplicator::default(),
+            osm_header: None,
+            pending: 0,
+            stats: MbtilesStats::default(),
+        })
+    }
+
+    /// Gzip tile data before storing it (default). Disable if tiles are already compressed.
+    pub fn compress(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Header of the PBF file the tiles were built from. Its bounding box and replication
+    /// state replace the ones of the metadata, see [`MbtilesMetadata::set_osm_header`].
+    pub fn osm_header(mut self, header: OsmHeader) -> Self {
+        self.osm_header = Some(header);
+        self
+    }
+
+    /// Store one encoded tile. The Y coordinate is flipped to the TMS scheme used by MBTiles.
+    pub fn write_tile(&mut self, tile: TileID, data: &[u8]) -> Result<()> {
+        let data = if self.compress {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()?
+        } else {
+            data.to_vec()
+        };
+        let row = max_dimension(tile.zoom) - 1 - tile.y;
+        self.stats.tiles += 1;
+
+        if self.deduplicate {
+            let tile_id = match self.known_tiles.get(&data) {
+                Some(id) => id,
+                None => {
+                    let id = self.stats.unique_tiles as i64;
+                    self.conn
+                        .prepare_cached("INSERT INTO images (tile_id, tile_data) VALUES (?, ?)")?
+                        .execute(params![id, data])?;
+                    self.known_tiles.insert(&data, id);
+                    self.stats.unique_tiles += 1;
+                    self.stats.bytes += data.len();
+                    id
+                }
+            };
+            self.conn
+                .prepare_cached(
+                    "INSERT INTO map (zoom_level, tile_column, tile_row, tile_id) VALUES (?, ?, ?, ?)",
+                )?
+                .execute(params![tile.zoom, tile.x, row, tile_id])?;
+        } else {
+            self.conn
+                .prepare_cached(
+                    "INSERT INTO tiles (zoom_level, tile_column, tile_row, tile_data) VALUES (?, ?, ?, ?)",
+                )?
+                .execute(params![tile.zoom, tile.x, row, data])?;
+            self.stats.unique_tiles += 1;
+            self.stats.bytes += data.len();
+        }
+
+        self.pending += 1;
+        if self.pending >= BATCH_SIZE {
+            self.conn.execute_batch("COMMIT; BEGIN")?;
+            self.pending = 0;
+        }
+        Ok(())
+    }
+
+    pub fn write_metadata(&mut self, metadata: &MbtilesMetadata) -> Result<()> {
+        let metadata = metadata.with_osm_header(self.osm_header.as_ref());
+        for (name, value) in metadata.entries() {
+            self.conn
+                .prepare_cached("INSERT OR REPLACE INTO metadata (name, value) VALUES (?, ?)")?
+                .execute(params![name, value])?;
+        }
+        Ok(())
+    }
+
+    /// Commit all pending tiles and close the file.
+    pub fn finish(self) -> Result<MbtilesStats> {
+        self.conn.execute_batch("COMMIT; ANALYZE;")?;
+        self.conn.close().map_err(|(_, e)| e)?;
+        Ok(self.stats)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_write_dedup() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.mbtiles");
+        let header = OsmHeader {
+            bbox: Some([-10.5, -89.0, 20.0, 50.25]),
+            replication: Replication {
+                timestamp: Some(1_644_872_400),
+                sequence: Some(3_456),
+                base_url: None,
+            },
+            ..OsmHeader::default()
+        };
+        let mut writer = MbtilesWriter::create(&path, true)
+            .unwrap()
+            .compress(false)
+            .osm_header(header);
+        writer.write_tile(TileID::new(0, 0, 0), b"root").unwrap();
+        writer.write_tile(TileID::new(1, 0, 0), b"ocean").unwrap();
+        writer.write_tile(TileID::new(1, 1, 0), b"ocean").unwrap();
+        writer.write_tile(TileID::new(1, 1, 1), b"land").unwrap();
+        writer
+            .write_metadata(&MbtilesMetadata {
+                maxzoom: 1,
+                ..MbtilesMetadata::default()
+            })
+            .unwrap();
+        let stats = writer.finish().unwrap();
+        assert_eq!(stats.tiles, 4);
+        assert_eq!(stats.unique_tiles, 3);
+
+        let conn = Connection::open(&path).unwrap();
+        let data: Vec<u8> = conn
+            .query_row(
+                "SELECT tile_data FROM tiles WHERE zoom_level=1 AND tile_column=1 AND tile_row=0",
+                [],
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert_eq!(data, b"land");
+        let metadata = |name: &str| -> Option<String> {
+            conn.query_row("SELECT value FROM metadata WHERE name=?", [name], |r| {
+                r.get(0)
+            })
+            .ok()
+        };
+        assert_eq!(metadata("maxzoom").as_deref(), Some("1"));
+        let bounds = format!("-10.5,{},20,50.25", -MAX_LATITUDE);
+        assert_eq!(metadata("bounds"), Some(bounds));
+        assert_eq!(
+            metadata("planetiler:osm:osmosisreplicationtime").as_deref(),
+            Some("2022-02-14T21:00:00Z")
+        );
+        assert_eq!(
+            metadata("planetiler:osm:osmosisreplicationseq").as_deref(),
+            Some("3456")
+        );
+        assert_eq!(metadata("planetiler:osm:osmosisreplicationurl"), None);
+    }
+}
@@ -0,0 +1,341 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use anyhow::Result;
+use geos::{CoordSeq, GResult, Geom, Geometry};
+use osmpbf::{BlobReader, ByteOffset, RelMemberType};
+use rayon::iter::{ParallelBridge, ParallelIterator};
+
+use crate::area::signed_area;
+use crate::node_cache::NodeLookup;
+use crate::pbf::decode_data_block_or_skip;
+use crate::profile::Tags;
+use crate::ways::{MissingNodeStats, MissingNodes};
+
+/// A multipolygon or boundary relation, with the way members needed to build it.
+#[derive(Debug, Clone)]
+pub struct AreaRelation {
+    pub id: i64,
+    pub tags: Vec<(String, String)>,
+    pub ways: Vec<i64>,
+}
+
+impl AreaRelation {
+    pub fn tags(&self) -> Tags<'_> {
+        Tags::new(self.tags.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+    }
+}
+
+/// Collect all `type=multipolygon` and `type=boundary` relations with their way members.
+/// Reading starts at the given offset, e.g. the first relation block found while caching nodes,
+/// and `u64::MAX` means there are no relations. With `skip_bad_blobs`, blobs that cannot be decoded are reported and skipped.
+/// Returns the relations and the number of skipped blobs.
+pub fn read_area_relations(
+    pbf_file: &Path,
+    start_offset: u64,
+    skip_bad_blobs: bool,
+) -> Result<(Vec<AreaRelation>, usize)> {
+    if start_offset == u64::MAX {
+        return Ok((Vec::new(), 0));
+    }
+    let mut reader = BlobReader::from_path(pbf_file)?;
+    if start_offset > 0 {
+        reader.seek(ByteOffset(start_offset))?;
+    }
+    let blocks = reader
+        .par_bridge()
+        .map(|blob| -> Result<(Vec<_>, usize)> {
+            let mut result = Vec::new();
+            let mut bad = 0;
+            if let Some(data) = decode_data_block_or_skip(blob, skip_bad_blobs, &mut bad)? {
+                for group in data.block.groups() {
+                    for rel in group.relations() {
+                        let is_area = rel
+                            .tags()
+                            .any(|(k, v)| k == "type" && (v == "multipolygon" || v == "boundary"));
+                        if !is_area {
+                            continue;
+                        }
+                        let ways = rel
+                            .members()
+                            .filter(|m| m.member_type == RelMemberType::Way)
+                            .map(|m| m.member_id)
+                            .collect();
+                        result.push(AreaRelation {
+                            id: rel.id(),
+                            tags: rel
+                                .tags()
+                                .map(|(k, v)| (k.to_string(), v.to_string()))
+                                .collect(),
+                            ways,
+                        });
+                    }
+                }
+            }
+            Ok((result, bad))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(flatten_blocks(blocks))
+}
+
+/// Read node IDs of every way in the `needed` set, e.g. all members of the area relations.
+/// Returns the node IDs by way ID and the number of skipped blobs. Like for
+/// [`read_area_relations`], a `u64::MAX` start offset means there are no ways.
+pub fn read_way_refs(
+    pbf_file: &Path,
+    start_offset: u64,
+    needed: &HashSet<i64>,
+    skip_bad_blobs: bool,
+) -> Result<(HashMap<i64, Vec<i64>>, usize)> {
+    if start_offset == u64::MAX {
+        return Ok((HashMap::new(), 0));
+    }
+    let mut reader = BlobReader::from_path(pbf_file)?;
+    if start_offset > 0 {
+        reader.seek(ByteOffset(start_offset))?;
+    }
+    let blocks = reader
+        .par_bridge()
+        .map(|blob| -> Result<(Vec<_>, usize)> {
+            let mut result = Vec::new();
+            let mut bad = 0;
+            if let Some(data) = decode_data_block_or_skip(blob, skip_bad_blobs, &mut bad)? {
+                for group in data.block.groups() {
+                    for way in group.ways() {
+                        if needed.contains(&way.id()) {
+                            result.push((way.id(), way.refs().collect()));
+                        }
+                    }
+                }
+            }
+            Ok((result, bad))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(flatten_blocks(blocks))
+}
+
+/// Join the results of each blob, and sum up their skipped blobs.
+fn flatten_blocks<T, C: FromIterator<T>>(blocks: Vec<(Vec<T>, usize)>) -> (C, usize) {
+    let bad_blobs = blocks.iter().map(|(_, bad)| bad).sum();
+    let items = blocks.into_iter().flat_map(|(items, _)| items).collect();
+    (items, bad_blobs)
+}
+
+/// Result of assembling a single relation.
+pub struct AssembledRelation<'a> {
+    /// None if no closed rings could be built
+    pub geometry: Option<Geometry<'a>>,
+    /// The geometry was invalid and had to be repaired
+    pub repaired: bool,
+    /// Member ways that were not found in the way refs
+    pub missing_ways: usize,
+    /// Ring fragments that could not be closed, and were dropped
+    pub unclosed_rings: usize,
+    /// Member ways with nodes missing from the node cache
+    pub missing: MissingNodeStats,
+}
+
+/// Assemble a relation from its member ways, resolving node IDs to (lon, lat) with the cache.
+/// Member ways using nodes missing from the cache are handled like any other way with
+/// `missing_nodes`, so a dropped or truncated member may leave its ring unclosed.
+pub fn assemble_relation<'a>(
+    relation: &AreaRelation,
+    ways: &HashMap<i64, Vec<i64>>,
+    cache: &dyn NodeLookup,
+    missing_nodes: MissingNodes,
+) -> GResult<AssembledRelation<'a>> {
+    let mut missing_ways = 0;
+    let mut missing = MissingNodeStats::default();
+    let mut members = Vec::with_capacity(relation.ways.len());
+    for id in &relation.ways {
+        let refs = match ways.get(id) {
+            Some(refs) => refs,
+            None => {
+                missing_ways += 1;
+                continue;
+            }
+        };
+        let found: Vec<i64> = refs
+            .iter()
+            .copied()
+            .filter(|node| cache.get_node(*node).is_some())
+            .collect();
+        let skipped = missing_nodes.skips(found.len(), refs.len() - found.len());
+        missing.add_way(*id, refs.len() - found.len(), skipped);
+        if !skipped {
+            members.push(match missing_nodes {
+                MissingNodes::Truncate => found,
+                _ => refs.clone(),
+            });
+        }
+    }
+    let (rings, unclosed_rings) = stitch_rings(members);
+    // Only the keep policy leaves missing nodes in the rings, and resolves them to (0, 0)
+    let rings: Vec<Vec<[f64; 2]>> = rings
+        .iter()
+        .filter_map(|ring| missing_nodes.resolve(cache, ring.iter().copied()).coords)
+        .collect();
+    let (geometry, repaired) = match build_multipolygon(&rings)? {
+        Some((geometry, repaired)) => (Some(geometry), repaired),
+        None => (None, false),
+    };
+    Ok(AssembledRelation {
+        geometry,
+        repaired,
+        missing_ways,
+        unclosed_rings,
+        missing,
+    })
+}
+
+/// Join member ways that share end nodes into closed rings.
+/// Returns the closed rings (first node repeated at the end),
+/// and the number of ring fragments that could not be closed.
+pub fn stitch_rings(mut ways: Vec<Vec<i64>>) -> (Vec<Vec<i64>>, usize) {
+    let mut rings = Vec::new();
+    let mut unclosed = 0;
+    ways.retain(|w| w.len() >= 2);
+    while let Some(mut ring) = ways.pop() {
+        // Once the end cannot be extended, try extending the other end before giving up
+        let mut reversed = false;
+        while ring.first() != ring.last() {
+            let end = *ring.last().unwrap();
+            let next = ways
+                .iter()
+                .position(|w| w.first() == Some(&end) || w.last() == Some(&end));
+            match next {
+                Some(idx) => {
+                    let mut way = ways.swap_remove(idx);
+                    if way.first() != Some(&end) {
+                        way.reverse();
+                    }
+                    ring.extend_from_slice(&way[1..]);
+                }
+                None if !reversed => {
+                    ring.reverse();
+                    reversed = true;
+                }
+                None => break,
+            }
+        }
+        if ring.len() >= 4 && ring.first() == ring.last() {
+            rings.push(ring);
+        } else {
+            unclosed += 1;
+        }
+    }
+    (rings, unclosed)
+}
+
+/// Ray casting point-in-polygon test.
+fn contains_point(ring: &[[f64; 2]], [x, y]: [f64; 2]) -> bool {
+    let mut inside = false;
+    for w in ring.windows(2) {
+        let ([x1, y1], [x2, y2]) = (w[0], w[1]);
+        if (y1 > y) != (y2 > y) && x < (x2 - x1) * (y - y1) / (y2 - y1) + x1 {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+/// Decide which rings are outer and which are holes based on nesting depth, ignoring member roles,
+/// which are frequently wrong in OSM data. Returns a list of (outer ring index, hole indexes).
+pub fn classify_rings(rings: &[Vec<[f64; 2]>]) -> Vec<(usize, Vec<usize>)> {
+    let mut order: Vec<usize> = (0..rings.len()).collect();
+    order.sort_by(|a, b| {
+        signed_area(&rings[*b])
+            .abs()
+            .total_cmp(&signed_area(&rings[*a]).abs())
+    });
+
+    let mut result: Vec<(usize, Vec<usize>)> = Vec::new();
+    // For each processed ring: (index, nesting depth)
+    let mut processed: Vec<(usize, usize)> = Vec::with_capacity(rings.len());
+    for idx in order {
+        // Rings are processed from largest to smallest, so the last container found is the smallest one
+        let parent = processed
+            .iter()
+            .rev()
+            .find(|(p, _)| contains_point(&rings[*p], rings[idx][0]))
+            .copied();
+        let depth = parent.map_or(0, |(_, d)| d + 1);
+        if depth % 2 == 0 {
+            result.push((idx, Vec::new()));
+        } else if let Some((parent, _)) = parent {
+            if let Some(outer) = result.iter_mut().find(|(o, _)| *o == parent) {
+                outer.1.push(idx);
+            }
+        }
+        processed.push((idx, depth));
+    }
+    result
+}
+
+/// Build a GEOS multipolygon from closed rings of (lon, lat) points.
+/// Returns the geometry, and true if it was invalid and had to be repaired.
+pub fn build_multipolygon<'a>(rings: &[Vec<[f64; 2]>]) -> GResult<Option<(Geometry<'a>, bool)>> {
+    let mut polygons = Vec::new();
+    for (outer, holes) in classify_rings(rings) {
+        let exterior = Geometry::create_linear_ring(CoordSeq::new_from_vec(&rings[outer])?)?;
+        let interiors = holes
+            .iter()
+            .map(|idx| Geometry::create_linear_ring(CoordSeq::new_from_vec(&rings[*idx])?))
+            .collect::<GResult<Vec<_>>>()?;
+        polygons.push(Geometry::create_polygon(exterior, interiors)?);
+    }
+    if polygons.is_empty() {
+        return Ok(None);
+    }
+    let geometry = Geometry::create_multipolygon(polygons)?;
+    if geometry.is_valid() {
+        Ok(Some((geometry, false)))
+    } else {
+        // Zero-width buffer is the traditional way to fix self-intersections and touching rings
+        Ok(Some((geometry.buffer(0.0, 8)?, true)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stitch() {
+        // Square split into three ways, one of them reversed, plus a separate closed way
+        let ways = vec![
+            vec![1, 2, 3],
+            vec![5, 4, 3],
+            vec![5, 6, 1],
+            vec![10, 11, 12, 10],
+        ];
+        let (rings, unclosed) = stitch_rings(ways);
+        assert_eq!(unclosed, 0);
+        assert_eq!(rings.len(), 2);
+        assert!(rings.contains(&vec![10, 11, 12, 10]));
+        let square = rings.iter().find(|r| r.len() == 7).unwrap();
+        assert_eq!(square.first(), square.last());
+
+        let (rings, unclosed) = stitch_rings(vec![vec![1, 2, 3], vec![3, 4]]);
+        assert!(rings.is_empty());
+        assert_eq!(unclosed, 1);
+    }
+
+    fn square(min: f64, max: f64) -> Vec<[f64; 2]> {
+        vec![[min, min], [max, min], [max, max], [min, max], [min, min]]
+    }
+
+    #[test]
+    fn test_classify() {
+        // An island inside a lake inside a forest, plus an unrelated forest
+        let rings = vec![
+            square(2.0, 8.0),
+            square(0.0, 10.0),
+            square(20.0, 30.0),
+            square(4.0, 6.0),
+        ];
+        let mut result = classify_rings(&rings);
+        result.sort();
+        assert_eq!(result, vec![(1, vec![0]), (2, vec![]), (3, vec![])]);
+    }
+}
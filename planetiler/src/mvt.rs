@@ -0,0 +1,546 @@
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+use crate::tile_id::TileID;
+use crate::varint::{write_varint, zigzag_encode};
+
+/// Default number of integer units per tile side, as recommended by the MVT spec.
+pub const DEFAULT_EXTENT: u32 = 4096;
+
+/// Default number of extra units kept around each tile edge.
+pub const DEFAULT_BUFFER: u32 = 64;
+
+const MVT_VERSION: u32 = 2;
+
+const CMD_MOVE_TO: u32 = 1;
+const CMD_LINE_TO: u32 = 2;
+const CMD_CLOSE_PATH: u32 = 7;
+
+/// A point in Web Mercator projection, normalized to the whole world:
+/// x and y are in the 0..1 range, with (0,0) at the top left corner (north-west).
+pub type Coord = [f64; 2];
+
+/// Geometry of a single feature, already clipped to the tile (plus buffer).
+/// Coordinates are world-normalized, see [`Coord`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum TileGeometry {
+    /// One or more points
+    Point(Vec<Coord>),
+    /// One or more line strings
+    LineString(Vec<Vec<Coord>>),
+    /// One or more polygons, each a list of rings. The first ring is the exterior,
+    /// the rest are holes. Rings may be in any winding order, the encoder fixes it.
+    Polygon(Vec<Vec<Vec<Coord>>>),
+}
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum GeomType {
+    Point = 1,
+    LineString = 2,
+    Polygon = 3,
+}
+
+/// Attribute value of a feature, matching the `Tile.Value` message of the MVT spec.
+#[derive(Debug, Clone)]
+pub enum Value {
+    String(String),
+    Float(f32),
+    Double(f64),
+    Int(i64),
+    UInt(u64),
+    SInt(i64),
+    Bool(bool),
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        // Floats are compared by their bits so that values can be used as dictionary keys
+        match (self, other) {
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Float(a), Value::Float(b)) => a.to_bits() == b.to_bits(),
+            (Value::Double(a), Value::Double(b)) => a.to_bits() == b.to_bits(),
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::UInt(a), Value::UInt(b)) => a == b,
+            (Value::SInt(a), Value::SInt(b)) => a == b,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Value {}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Value::String(v) => v.hash(state),
+            Value::Float(v) => v.to_bits().hash(state),
+            Value::Double(v) => v.to_bits().hash(state),
+            Value::Int(v) | Value::SInt(v) => v.hash(state),
+            Value::UInt(v) => v.hash(state),
+            Value::Bool(v) => v.hash(state),
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(v: &str) -> Self {
+        Value::String(v.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(v: String) -> Self {
+        Value::String(v)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(v: i64) -> Self {
+        Value::Int(v)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(v: f64) -> Self {
+        Value::Double(v)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(v: bool) -> Self {
+        Value::Bool(v)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Feature {
+    pub id: Option<u64>,
+    pub geometry: TileGeometry,
+    pub attrs: Vec<(String, Value)>,
+}
+
+#[derive(Debug, Default)]
+struct Layer {
+    name: String,
+    keys: Vec<String>,
+    key_index: HashMap<String, u32>,
+    values: Vec<Value>,
+    value_index: HashMap<Value, u32>,
+    /// Already encoded `Tile.Feature` messages
+    features: Vec<Vec<u8>>,
+}
+
+impl Layer {
+    fn key(&mut self, key: &str) -> u32 {
+        if let Some(idx) = self.key_index.get(key) {
+            return *idx;
+        }
+        let idx = self.keys.len() as u32;
+        self.keys.push(key.to_string());
+        self.key_index.insert(key.to_string(), idx);
+        idx
+    }
+
+    fn value(&mut self, value: Value) -> u32 {
+        if let Some(idx) = self.value_index.get(&value) {
+            return *idx;
+        }
+        let idx = self.values.len() as u32;
+        self.values.push(value.clone());
+        self.value_index.insert(value, idx);
+        idx
+    }
+}
+
+/// Encodes features of a single tile into a protobuf MVT 2.1 blob.
+///
+/// ```ignore
+/// let mut encoder = TileEncoder::new(tile).buffer(8);
+/// encoder.add_feature("water", feature);
+/// let blob = encoder.encode();
+/// ```
+#[derive(Debug)]
+pub struct TileEncoder {
+    tile: TileID,
+    extent: u32,
+    buffer: u32,
+    layers: Vec<Layer>,
+    layer_index: HashMap<String, usize>,
+}
+
+impl TileEncoder {
+    pub fn new(tile: TileID) -> Self {
+        Self {
+            tile,
+            extent: DEFAULT_EXTENT,
+            buffer: DEFAULT_BUFFER,
+            layers: Vec::new(),
+            layer_index: HashMap::new(),
+        }
+    }
+
+    /// Number of integer units per tile side.
+    pub fn extent(mut self, extent: u32) -> Self {
+        self.extent = extent;
+        self
+    }
+
+    /// Number of units beyond the tile edge to keep. Anything further is clamped.
+    pub fn buffer(mut self, buffer: u32) -> Self {
+        self.buffer = buffer;
+        self
+    }
+
+    pub fn tile(&self) -> TileID {
+        self.tile
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.iter().all(|l| l.features.is_empty())
+    }
+
+    /// Add a feature to the given layer. Returns false if the geometry
+    /// degenerated to nothing at this tile's resolution and was dropped.
+    pub fn add_feature(&mut self, layer: &str, feature: Feature) -> bool {
+        let (geom_type, geometry) = match self.encode_geometry(&feature.geometry) {
+            Some(v) => v,
+            None => return false,
+        };
+
+        let layer_idx = match self.layer_index.get(layer) {
+            Some(idx) => *idx,
+            None => {
+                self.layers.push(Layer {
+                    name: layer.to_string(),
+                    ..Layer::default()
+                });
+                self.layer_index
+                    .insert(layer.to_string(), self.layers.len() - 1);
+                self.layers.len() - 1
+            }
+        };
+        let layer = &mut self.layers[layer_idx];
+
+        let mut tags = Vec::with_capacity(feature.attrs.len() * 2);
+        for (key, value) in feature.attrs {
+            tags.push(layer.key(&key));
+            tags.push(layer.value(value));
+        }
+
+        let mut msg = ProtoWriter::default();
+        if let Some(id) = feature.id {
+            msg.varint_field(1, id);
+        }
+        msg.packed_field(2, &tags);
+        msg.varint_field(3, geom_type as u64);
+        msg.packed_field(4, &geometry);
+        layer.features.push(msg.into_inner());
+        true
+    }
+
+    /// Produce the `Tile` protobuf message.
+    pub fn encode(self) -> Vec<u8> {
+        let mut tile = ProtoWriter::default();
+        for layer in self.layers {
+            if layer.features.is_empty() {
+                continue;
+            }
+            let mut msg = ProtoWriter::default();
+            msg.varint_field(15, MVT_VERSION as u64);
+            msg.bytes_field(1, layer.name.as_bytes());
+            for feature in &layer.features {
+                msg.bytes_field(2, feature);
+            }
+            for key in &layer.keys {
+                msg.bytes_field(3, key.as_bytes());
+            }
+            for value in &layer.values {
+                msg.bytes_field(4, &encode_value(value));
+            }
+            msg.varint_field(5, self.extent as u64);
+            tile.bytes_field(3, &msg.into_inner());
+        }
+        tile.into_inner()
+    }
+
+    /// Convert a world-normalized coordinate into integer tile units, clamped to the buffer.
+    fn project(&self, [x, y]: Coord) -> (i32, i32) {
+        let scale = (1_u64 << self.tile.zoom) as f64;
+        let extent = self.extent as f64;
+        let min = -(self.buffer as f64);
+        let max = extent + self.buffer as f64;
+        let px = ((x * scale - self.tile.x as f64) * extent).round();
+        let py = ((y * scale - self.tile.y as f64) * extent).round();
+        (px.clamp(min, max) as i32, py.clamp(min, max) as i32)
+    }
+
+    fn encode_geometry(&self, geometry: &TileGeometry) -> Option<(GeomType, Vec<u32>)> {
+        let mut cmd = CommandEncoder::default();
+        let geom_type = match geometry {
+            TileGeometry::Point(points) => {
+                let points: Vec<_> = points.iter().map(|p| self.project(*p)).collect();
+                if !points.is_empty() {
+                    cmd.command(CMD_MOVE_TO, points.len());
+                    for p in points {
+                        cmd.point(p);
+                    }
+                }
+                GeomType::Point
+            }
+            TileGeometry::LineString(lines) => {
+                for line in lines {
+                    let line = self.project_path(line);
+                    if line.len() < 2 {
+                        continue;
+                    }
+                    cmd.command(CMD_MOVE_TO, 1);
+                    cmd.point(line[0]);
+                    cmd.command(CMD_LINE_TO, line.len() - 1);
+                    for p in &line[1..] {
+                        cmd.point(*p);
+                    }
+                }
+                GeomType::LineString
+            }
+            TileGeometry::Polygon(polygons) => {
+                for polygon in polygons {
+                    for (idx, ring) in polygon.iter().enumerate() {
+                        let mut ring = self.project_path(ring);
+                        if ring.len() > 1 && ring[0] == ring[ring.len() - 1] {
+                            ring.pop();
+                        }
+                        let area = ring_area(&ring);
+                        if ring.len() < 3 || area == 0 {
+                            if idx == 0 {
+                                // Without an exterior ring, the holes are meaningless
+                                break;
+                            }
+                            continue;
+                        }
+                        // Exterior rings must have positive area (clockwise in screen coordinates),
+                        // and interior rings must have negative area.
+                        if (idx == 0) != (area > 0) {
+                            ring[1..].reverse();
+                        }
+                        cmd.command(CMD_MOVE_TO, 1);
+                        cmd.point(ring[0]);
+                        cmd.command(CMD_LINE_TO, ring.len() - 1);
+                        for p in &ring[1..] {
+                            cmd.point(*p);
+                        }
+                        cmd.command(CMD_CLOSE_PATH, 1);
+                    }
+                }
+                GeomType::Polygon
+            }
+        };
+        if cmd.data.is_empty() {
+            None
+        } else {
+            Some((geom_type, cmd.data))
+        }
+    }
+
+    /// Project a path, dropping points that round to the same position as the previous one.
+    fn project_path(&self, path: &[Coord]) -> Vec<(i32, i32)> {
+        let mut result: Vec<(i32, i32)> = Vec::with_capacity(path.len());
+        for p in path {
+            let p = self.project(*p);
+            if result.last() != Some(&p) {
+                result.push(p);
+            }
+        }
+        result
+    }
+}
+
+/// Twice the signed area of a ring, positive for clockwise rings in screen coordinates.
+fn ring_area(ring: &[(i32, i32)]) -> i64 {
+    let mut sum = 0_i64;
+    for i in 0..ring.len() {
+        let (x1, y1) = ring[i];
+        let (x2, y2) = ring[(i + 1) % ring.len()];
+        sum += x1 as i64 * y2 as i64 - x2 as i64 * y1 as i64;
+    }
+    sum
+}
+
+/// Accumulates geometry commands with delta-encoded parameters
+#[derive(Default)]
+struct CommandEncoder {
+    data: Vec<u32>,
+    cursor: (i32, i32),
+}
+
+impl CommandEncoder {
+    fn command(&mut self, id: u32, count: usize) {
+        self.data.push((id & 0x7) | ((count as u32) << 3));
+    }
+
+    fn point(&mut self, (x, y): (i32, i32)) {
+        self.data.push(zigzag_encode(i64::from(x - self.cursor.0)) as u32);
+        self.data.push(zigzag_encode(i64::from(y - self.cursor.1)) as u32);
+        self.cursor = (x, y);
+    }
+}
+
+fn encode_value(value: &Value) -> Vec<u8> {
+    let mut msg = ProtoWriter::default();
+    match value {
+        Value::String(v) => msg.bytes_field(1, v.as_bytes()),
+        Value::Float(v) => {
+            msg.key(2, 5);
+            msg.buf.extend_from_slice(&v.to_le_bytes());
+        }
+        Value::Double(v) => {
+            msg.key(3, 1);
+            msg.buf.extend_from_slice(&v.to_le_bytes());
+        }
+        Value::Int(v) => msg.varint_field(4, *v as u64),
+        Value::UInt(v) => msg.varint_field(5, *v),
+        Value::SInt(v) => msg.varint_field(6, zigzag_encode(*v)),
+        Value::Bool(v) => msg.varint_field(7, *v as u64),
+    }
+    msg.into_inner()
+}
+
+/// Minimal protobuf wire format writer, sufficient for the vector tile schema.
+#[derive(Default)]
+struct ProtoWriter {
+    buf: Vec<u8>,
+}
+
+impl ProtoWriter {
+    fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+
+    fn varint(&mut self, value: u64) {
+        write_varint(&mut self.buf, value);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u32) {
+        self.varint(((field << 3) | wire_type) as u64);
+    }
+
+    fn varint_field(&mut self, field: u32, value: u64) {
+        self.key(field, 0);
+        self.varint(value);
+    }
+
+    fn bytes_field(&mut self, field: u32, value: &[u8]) {
+        self.key(field, 2);
+        self.varint(value.len() as u64);
+        self.buf.extend_from_slice(value);
+    }
+
+    fn packed_field(&mut self, field: u32, values: &[u32]) {
+        if values.is_empty() {
+            return;
+        }
+        let mut packed = ProtoWriter::default();
+        for v in values {
+            packed.varint(*v as u64);
+        }
+        self.bytes_field(field, &packed.buf);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Convert tile units of a z0 tile into world coordinates
+    fn pt(x: f64, y: f64) -> Coord {
+        [x / DEFAULT_EXTENT as f64, y / DEFAULT_EXTENT as f64]
+    }
+
+    fn encoder() -> TileEncoder {
+        TileEncoder::new(TileID::new(0, 0, 0))
+    }
+
+    // Expected values are the examples from the MVT 2.1 specification
+    #[test]
+    fn test_geometry_commands() {
+        let geom = TileGeometry::Point(vec![pt(25.0, 17.0)]);
+        assert_eq!(
+            encoder().encode_geometry(&geom),
+            Some((GeomType::Point, vec![9, 50, 34]))
+        );
+
+        let geom =
+            TileGeometry::LineString(vec![vec![pt(2.0, 2.0), pt(2.0, 10.0), pt(10.0, 10.0)]]);
+        assert_eq!(
+            encoder().encode_geometry(&geom),
+            Some((GeomType::LineString, vec![9, 4, 4, 18, 0, 16, 16, 0]))
+        );
+
+        // Counter-clockwise input is reversed into a clockwise exterior ring
+        let geom = TileGeometry::Polygon(vec![vec![vec![
+            pt(3.0, 6.0),
+            pt(20.0, 34.0),
+            pt(8.0, 12.0),
+            pt(3.0, 6.0),
+        ]]]);
+        let expected = vec![9, 6, 12, 18, 10, 12, 24, 44, 15];
+        assert_eq!(
+            encoder().encode_geometry(&geom),
+            Some((GeomType::Polygon, expected))
+        );
+    }
+
+    #[test]
+    fn test_degenerate() {
+        let geom = TileGeometry::LineString(vec![vec![pt(1.0, 1.0), pt(1.1, 1.1)]]);
+        assert_eq!(encoder().encode_geometry(&geom), None);
+
+        let mut enc = encoder();
+        let added = enc.add_feature(
+            "roads",
+            Feature {
+                id: None,
+                geometry: geom,
+                attrs: vec![],
+            },
+        );
+        assert!(!added);
+        assert!(enc.is_empty());
+        assert!(enc.encode().is_empty());
+    }
+
+    #[test]
+    fn test_buffer_clamp() {
+        let enc = TileEncoder::new(TileID::new(1, 1, 0)).buffer(10);
+        assert_eq!(enc.project([0.0, 0.75]), (-10, 4096 + 10));
+        assert_eq!(enc.project([0.75, 0.25]), (2048, 2048));
+    }
+
+    #[test]
+    fn test_layer_dictionary() {
+        let mut enc = encoder();
+        for id in 0..3 {
+            enc.add_feature(
+                "poi",
+                Feature {
+                    id: Some(id),
+                    geometry: TileGeometry::Point(vec![pt(1.0, 1.0)]),
+                    attrs: vec![
+                        ("class".to_string(), Value::from("shop")),
+                        ("rank".to_string(), Value::Int(id as i64 % 2)),
+                    ],
+                },
+            );
+        }
+        let layer = &enc.layers[0];
+        assert_eq!(layer.keys, vec!["class", "rank"]);
+        assert_eq!(
+            layer.values,
+            vec![Value::from("shop"), Value::Int(0), Value::Int(1)]
+        );
+        assert_eq!(layer.features.len(), 3);
+        assert!(!enc.encode().is_empty());
+    }
+}
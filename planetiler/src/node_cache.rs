@@ -0,0 +1,682 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::ops::AddAssign;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering::Relaxed};
+use std::sync::Mutex;
+
+use anyhow::{ensure, Context, Result};
+use osmnodecache::{Advice, Cache, CacheStore, DenseFileCache, DenseFileCacheOpts};
+use osmpbf::BlobReader;
+use rayon::iter::{
+    IntoParallelRefIterator, IntoParallelRefMutIterator, ParallelBridge, ParallelIterator,
+};
+
+use crate::cache_meta::NodeCacheMeta;
+use crate::compressed_cache::{CompressedCacheWriter, CompressedFileCache};
+use crate::pbf::{decode_data_block_or_skip, is_declared_sorted};
+use crate::sparse_cache::{pack_lat_lon, unpack_lat_lon, SparseFileCache};
+
+/// Default size of a single memory mapped page of the node cache file.
+pub const DEFAULT_PAGE_SIZE: usize = 10 * 1024 * 1024 * 1024;
+
+/// [`CacheKind::Auto`] keeps all nodes in memory before choosing the cache kind,
+/// so PBF files larger than this always use a dense cache. Sparse caches, and compressed
+/// caches of files not declared as sorted, also keep all nodes in memory and refuse them.
+pub const MAX_IN_MEMORY_PBF_SIZE: u64 = 2 * 1024 * 1024 * 1024;
+
+/// Each node of a sparse cache takes 16 bytes vs 8 bytes per possible ID of a dense one.
+/// Dense lookups are faster, so only pick sparse when it is at least this many times smaller.
+const SPARSE_MIN_GAIN: u64 = 4;
+
+/// Nodes per block of a compressed cache built from a PBF file not declared as sorted,
+/// about the number of nodes in one PBF blob.
+const UNSORTED_BLOCK_NODES: usize = 8000;
+
+/// Raw value of the node cache entries that were never set, which resolve to (0, 0).
+/// Nodes located exactly at (0, 0) are stored 1e-7 degrees east of it,
+/// so that a missing node is never mistaken for one of them.
+pub const UNSET_NODE: u64 = 0;
+
+/// Read-only access to the node positions of a [`NodeCache`], from a single thread.
+/// Sparse and compressed caches are written in one go and cannot be changed afterwards,
+/// so unlike [`Cache`] there is no way to set a node.
+pub trait NodeLookup {
+    /// Position (lat, lon) of a node, or None if the node is not in the cache.
+    fn get_node(&self, id: i64) -> Option<(f64, f64)>;
+}
+
+impl<T: NodeLookup + ?Sized> NodeLookup for &T {
+    fn get_node(&self, id: i64) -> Option<(f64, f64)> {
+        (**self).get_node(id)
+    }
+}
+
+/// Lookup through the accessor of a dense cache, where unset entries are [`UNSET_NODE`].
+struct DenseLookup<'a>(Box<dyn Cache + 'a>);
+
+impl NodeLookup for DenseLookup<'_> {
+    fn get_node(&self, id: i64) -> Option<(f64, f64)> {
+        if id < 0 || self.0.get(id as usize) == UNSET_NODE {
+            None
+        } else {
+            Some(self.0.get_lat_lon(id as usize))
+        }
+    }
+}
+
+/// Move nodes located exactly at (0, 0) so that they are not stored as [`UNSET_NODE`].
+fn not_unset(id: i64, lat: f64, lon: f64) -> (i64, f64, f64) {
+    if lat == 0.0 && lon == 0.0 {
+        (id, 0.0, 1e-7)
+    } else {
+        (id, lat, lon)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct NodeStats {
+    pub node_count: usize,
+    pub min_node_id: i64,
+    pub max_node_id: i64,
+    pub min_latitude: f64,
+    pub max_latitude: f64,
+    pub min_longitude: f64,
+    pub max_longitude: f64,
+}
+
+impl NodeStats {
+    /// Share of the node IDs between the smallest and the largest one that are used.
+    pub fn id_fill_ratio(&self) -> f64 {
+        if self.node_count == 0 {
+            return 0.0;
+        }
+        self.node_count as f64 / (self.max_node_id - self.min_node_id + 1) as f64
+    }
+
+    pub fn add_node(&mut self, node_id: i64, lat: f64, lng: f64) {
+        *self = Self {
+            node_count: self.node_count + 1,
+            min_node_id: self.min_node_id.min(node_id),
+            max_node_id: self.max_node_id.max(node_id),
+            min_latitude: self.min_latitude.min(lat),
+            max_latitude: self.max_latitude.max(lat),
+            min_longitude: self.min_longitude.min(lng),
+            max_longitude: self.max_longitude.max(lng),
+        };
+    }
+}
+
+impl Default for NodeStats {
+    fn default() -> Self {
+        Self {
+            node_count: 0,
+            min_node_id: i64::MAX,
+            max_node_id: i64::MIN,
+            min_latitude: 0.0,
+            max_latitude: 0.0,
+            min_longitude: 0.0,
+            max_longitude: 0.0,
+        }
+    }
+}
+
+impl AddAssign for NodeStats {
+    fn add_assign(&mut self, other: Self) {
+        *self = Self {
+            node_count: self.node_count + other.node_count,
+            min_node_id: self.min_node_id.min(other.min_node_id),
+            max_node_id: self.max_node_id.max(other.max_node_id),
+            min_latitude: self.min_latitude.min(other.min_latitude),
+            max_latitude: self.max_latitude.max(other.max_latitude),
+            min_longitude: self.min_longitude.min(other.min_longitude),
+            max_longitude: self.max_longitude.max(other.max_longitude),
+        };
+    }
+}
+
+/// Storage layout of the node cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheKind {
+    /// A memory mapped file indexed by node ID, sized by the maximum node ID
+    Dense,
+    /// Sorted node IDs with their positions, see [`SparseFileCache`]
+    Sparse,
+    /// Delta encoded blocks of nodes read on demand, see [`CompressedFileCache`].
+    /// Much smaller than dense, but slower to read. Never picked automatically.
+    Compressed,
+    /// Pick dense or sparse based on the node ID range and count of the PBF file
+    Auto,
+}
+
+impl CacheKind {
+    /// Choose the smallest reasonable cache kind for the nodes described by the stats.
+    pub fn for_stats(stats: &NodeStats) -> Self {
+        if stats.node_count == 0 {
+            return CacheKind::Sparse;
+        }
+        let dense_size = (stats.max_node_id.max(0) as u64 + 1) * 8;
+        let sparse_size = stats.node_count as u64 * 16;
+        if sparse_size * SPARSE_MIN_GAIN <= dense_size {
+            CacheKind::Sparse
+        } else {
+            CacheKind::Dense
+        }
+    }
+}
+
+/// Node cache of any kind, as created by [`NodeCacheBuilder`] and opened by [`open_cache`].
+#[derive(Clone)]
+pub enum NodeCache {
+    Dense(DenseFileCache),
+    Sparse(SparseFileCache),
+    Compressed(CompressedFileCache),
+}
+
+impl NodeCache {
+    pub fn kind(&self) -> CacheKind {
+        match self {
+            NodeCache::Dense(_) => CacheKind::Dense,
+            NodeCache::Sparse(_) => CacheKind::Sparse,
+            NodeCache::Compressed(_) => CacheKind::Compressed,
+        }
+    }
+}
+
+impl NodeCache {
+    /// Read-only access to the nodes, one per thread.
+    pub fn reader(&self) -> Box<dyn NodeLookup + '_> {
+        match self {
+            NodeCache::Dense(cache) => Box::new(DenseLookup(cache.get_accessor())),
+            NodeCache::Sparse(cache) => Box::new(cache),
+            NodeCache::Compressed(cache) => Box::new(cache),
+        }
+    }
+}
+
+/// Apply memory map advice to the cache. Advice is only supported on unix systems.
+pub fn advise_cache(cache: &DenseFileCache, advice: &[Advice]) -> Result<()> {
+    #[cfg(unix)]
+    for adv in advice {
+        cache
+            .advise(*adv)
+            .with_context(|| format!("Unable set {adv:?}"))?;
+    }
+    #[cfg(not(unix))]
+    let _ = (cache, advice);
+    Ok(())
+}
+
+/// Result of building a node cache.
+#[derive(Clone, Debug)]
+pub struct NodeCacheInfo {
+    /// Offset of the first block with ways or relations, `u64::MAX` if there are none
+    pub first_way_block: u64,
+    /// Offset of the first block with relations, `u64::MAX` if there are none
+    pub first_relation_block: u64,
+    /// Number of blobs that could not be decoded and were skipped
+    pub bad_blobs: usize,
+    pub stats: NodeStats,
+    /// The kind of the created cache, never [`CacheKind::Auto`]
+    pub kind: CacheKind,
+}
+
+/// Builds a flat node cache file (node ID -> lat/lon) from a PBF file.
+///
+/// ```no_run
+/// use planetiler::node_cache::NodeCacheBuilder;
+///
+/// let info = NodeCacheBuilder::new("planet.osm.pbf", "nodes.cache").build()?;
+/// println!("Cached {} nodes", info.stats.node_count);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct NodeCacheBuilder {
+    pbf_file: PathBuf,
+    cache_file: PathBuf,
+    page_size: usize,
+    advice: Vec<Advice>,
+    skip_bad_blobs: bool,
+    kind: CacheKind,
+}
+
+impl NodeCacheBuilder {
+    pub fn new(pbf_file: impl Into<PathBuf>, cache_file: impl Into<PathBuf>) -> Self {
+        Self {
+            pbf_file: pbf_file.into(),
+            cache_file: cache_file.into(),
+            page_size: DEFAULT_PAGE_SIZE,
+            advice: Vec::new(),
+            skip_bad_blobs: false,
+            kind: CacheKind::Auto,
+        }
+    }
+
+    /// Cache layout to create, [`CacheKind::Auto`] by default.
+    pub fn kind(mut self, kind: CacheKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    pub fn page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size;
+        self
+    }
+
+    /// Let OS know how the memmap will be used while the cache is being created.
+    pub fn advice(mut self, advice: Advice) -> Self {
+        self.advice.push(advice);
+        self
+    }
+
+    /// Skip blobs that cannot be decoded instead of failing, counting them in the result.
+    pub fn skip_bad_blobs(mut self, skip: bool) -> Self {
+        self.skip_bad_blobs = skip;
+        self
+    }
+
+    /// Read all nodes of the PBF file in parallel, and store their positions in the cache.
+    /// Also writes the [`NodeCacheMeta`] file next to the cache.
+    pub fn build(&self) -> Result<NodeCacheInfo> {
+        // A stale metadata file must not describe a partially written cache
+        NodeCacheMeta::remove(&self.cache_file)?;
+        let info = self.build_cache()?;
+        NodeCacheMeta::new(&self.pbf_file, &info)?.write(&self.cache_file)?;
+        Ok(info)
+    }
+
+    fn build_cache(&self) -> Result<NodeCacheInfo> {
+        let pbf_size = std::fs::metadata(&self.pbf_file)?.len();
+        let kind = match self.kind {
+            CacheKind::Auto if pbf_size > MAX_IN_MEMORY_PBF_SIZE => CacheKind::Dense,
+            kind => kind,
+        };
+        if kind == CacheKind::Dense {
+            let cache = self.open_dense()?;
+            return scan_nodes(&self.pbf_file, self.skip_bad_blobs, cache, |dfc, nodes| {
+                let mut cache = dfc.get_accessor();
+                for (id, lat, lon) in nodes {
+                    cache.set_lat_lon(id as usize, lat, lon);
+                }
+                Ok(())
+            });
+        }
+        // Blocks of a compressed cache must not overlap, so nodes of a file that is not
+        // known to be sorted are collected and sorted in memory first, like for a sparse cache
+        if kind == CacheKind::Compressed && is_declared_sorted(&self.pbf_file)? {
+            let writer = CompressedCacheWriter::create(&self.cache_file)?;
+            let mut info = scan_nodes(
+                &self.pbf_file,
+                self.skip_bad_blobs,
+                &writer,
+                |writer, nodes| writer.add_block(pack(nodes)),
+            )?;
+            writer.finish()?;
+            info.kind = kind;
+            return Ok(info);
+        }
+        ensure!(
+            pbf_size <= MAX_IN_MEMORY_PBF_SIZE,
+            "A {kind:?} node cache of {} would keep all its nodes in memory, use a dense cache, \
+             or a compressed cache of a file sorted by type then ID",
+            self.pbf_file.display()
+        );
+
+        let runs = Mutex::new(Vec::new());
+        let mut info = scan_nodes(&self.pbf_file, self.skip_bad_blobs, &runs, |runs, nodes| {
+            runs.lock().unwrap().push(pack(nodes));
+            Ok(())
+        })?;
+        let mut runs = runs.into_inner().unwrap();
+
+        info.kind = match kind {
+            CacheKind::Auto => CacheKind::for_stats(&info.stats),
+            kind => kind,
+        };
+        if info.kind == CacheKind::Dense {
+            // Runs are written as they are, the order of a dense cache does not matter
+            runs.par_iter()
+                .for_each_with(self.open_dense()?, |dfc, run| {
+                    let mut cache = dfc.get_accessor();
+                    for &(id, value) in run {
+                        let (lat, lon) = unpack_lat_lon(value);
+                        cache.set_lat_lon(id as usize, lat, lon);
+                    }
+                });
+            return Ok(info);
+        }
+        // Each run is the nodes of one blob, usually sorted already. Merging the sorted runs
+        // avoids a second copy of all nodes.
+        runs.par_iter_mut()
+            .for_each(|run| run.sort_unstable_by_key(|(id, _)| *id));
+        if info.kind == CacheKind::Sparse {
+            SparseFileCache::write(&self.cache_file, merge_runs(&runs))?;
+        } else {
+            let writer = CompressedCacheWriter::create(&self.cache_file)?;
+            let mut block = Vec::with_capacity(UNSORTED_BLOCK_NODES);
+            for node in merge_runs(&runs) {
+                block.push(node);
+                if block.len() == UNSORTED_BLOCK_NODES {
+                    writer.add_block(std::mem::take(&mut block))?;
+                }
+            }
+            writer.add_block(block)?;
+            writer.finish()?;
+        }
+        Ok(info)
+    }
+
+    fn open_dense(&self) -> Result<DenseFileCache> {
+        let cache = DenseFileCacheOpts::new(self.cache_file.clone())
+            .page_size(self.page_size)
+            .open()?;
+        advise_cache(&cache, &self.advice)?;
+        Ok(cache)
+    }
+}
+
+/// Decode all blobs in parallel, passing the (id, lat, lon) of each blob's nodes to `op`.
+/// The kind of the returned info is always dense, callers storing nodes elsewhere must set it.
+fn scan_nodes<T, F>(pbf_file: &Path, skip_bad_blobs: bool, init: T, op: F) -> Result<NodeCacheInfo>
+where
+    T: Send + Clone,
+    F: Fn(&mut T, Vec<(i64, f64, f64)>) -> Result<()> + Sync + Send,
+{
+    let first_way_block = AtomicU64::new(u64::MAX);
+    let first_relation_block = AtomicU64::new(u64::MAX);
+    let bad_blobs = AtomicUsize::new(0);
+    let stats = BlobReader::from_path(pbf_file)?
+        .par_bridge()
+        .map_with(init, |state, blob| -> Result<NodeStats> {
+            let mut stats = NodeStats::default();
+            let mut bad = 0;
+            let data = decode_data_block_or_skip(blob, skip_bad_blobs, &mut bad)?;
+            bad_blobs.fetch_add(bad, Relaxed);
+            if let Some(data) = data {
+                let mut nodes = Vec::new();
+                let mut blob_has_ways = false;
+                let mut blob_has_relations = false;
+                for group in data.block.groups() {
+                    for node in group.nodes() {
+                        nodes.push(not_unset(node.id(), node.lat(), node.lon()));
+                    }
+                    for node in group.dense_nodes() {
+                        nodes.push(not_unset(node.id(), node.lat(), node.lon()));
+                    }
+                    // TBD: is this the quickest way to test for empty?
+                    if group.relations().next().is_some() {
+                        blob_has_ways = true;
+                        blob_has_relations = true;
+                    } else if group.ways().next().is_some() {
+                        blob_has_ways = true;
+                    }
+                }
+                for &(id, lat, lon) in &nodes {
+                    stats.add_node(id, lat, lon);
+                }
+                // Negative IDs are new elements of editor files, and cannot be cached by ID
+                ensure!(
+                    stats.min_node_id >= 0,
+                    "Node {} has a negative ID, which node caches do not support",
+                    stats.min_node_id
+                );
+                if !nodes.is_empty() {
+                    op(state, nodes)?;
+                }
+                if blob_has_ways {
+                    let offset = data
+                        .offset
+                        .context("Unable to get offset of a blob with ways")?;
+                    first_way_block.fetch_min(offset, Relaxed);
+                    if blob_has_relations {
+                        first_relation_block.fetch_min(offset, Relaxed);
+                    }
+                }
+            };
+            Ok(stats)
+        })
+        .try_reduce(NodeStats::default, |mut a, b| {
+            a += b;
+            Ok(a)
+        })?;
+
+    Ok(NodeCacheInfo {
+        first_way_block: first_way_block.load(Relaxed),
+        first_relation_block: first_relation_block.load(Relaxed),
+        bad_blobs: bad_blobs.load(Relaxed),
+        stats,
+        kind: CacheKind::Dense,
+    })
+}
+
+/// Merge runs of nodes sorted by ID into a single sorted sequence, keeping one node per ID.
+fn merge_runs(runs: &[Vec<(u64, u64)>]) -> impl Iterator<Item = (u64, u64)> + Clone + '_ {
+    // (next ID, run, position in the run) of each run that is not exhausted yet
+    let mut heap: BinaryHeap<_> = runs
+        .iter()
+        .enumerate()
+        .filter(|(_, run)| !run.is_empty())
+        .map(|(idx, run)| Reverse((run[0].0, idx, 0)))
+        .collect();
+    let mut last_id = None;
+    std::iter::from_fn(move || loop {
+        let Reverse((id, idx, pos)) = heap.pop()?;
+        if let Some(&(next_id, _)) = runs[idx].get(pos + 1) {
+            heap.push(Reverse((next_id, idx, pos + 1)));
+        }
+        if last_id != Some(id) {
+            last_id = Some(id);
+            return Some(runs[idx][pos]);
+        }
+    })
+}
+
+fn pack(nodes: Vec<(i64, f64, f64)>) -> Vec<(u64, u64)> {
+    nodes
+        .into_iter()
+        .map(|(id, lat, lon)| (id as u64, pack_lat_lon(lat, lon)))
+        .collect()
+}
+
+/// Open an existing node cache file of any kind for reading.
+/// Memory map advice only applies to dense caches.
+pub fn open_cache(cache_file: impl AsRef<Path>, advice: &[Advice]) -> Result<NodeCache> {
+    let cache_file = cache_file.as_ref();
+    if SparseFileCache::is_sparse_file(cache_file)? {
+        return Ok(NodeCache::Sparse(SparseFileCache::open(cache_file)?));
+    }
+    if CompressedFileCache::is_compressed_file(cache_file)? {
+        return Ok(NodeCache::Compressed(CompressedFileCache::open(
+            cache_file,
+        )?));
+    }
+    let cache = DenseFileCache::new(cache_file.to_path_buf())?;
+    advise_cache(&cache, advice)?;
+    Ok(NodeCache::Dense(cache))
+}
+
+/// Largest difference in degrees between the PBF and the cached position of a node.
+const CHECK_TOLERANCE: f64 = 1e-6;
+
+/// Number of bad nodes kept as samples by [`check_cache`].
+const MAX_CHECK_SAMPLES: usize = 20;
+
+/// A node whose cached position differs from the one in the PBF file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BadNode {
+    pub id: i64,
+    /// (lat, lon) in the PBF file
+    pub expected: (f64, f64),
+    /// (lat, lon) in the cache, None if the node is not set
+    pub cached: Option<(f64, f64)>,
+}
+
+/// Result of comparing a node cache with the PBF file it was created from.
+#[derive(Clone, Debug, Default)]
+pub struct CacheCheck {
+    /// Nodes stored with a different position
+    pub mismatches: usize,
+    /// Nodes missing from the cache
+    pub unset: usize,
+    /// Dense cache file is too short to contain the largest node ID
+    pub truncated: bool,
+    /// Some of the bad nodes
+    pub samples: Vec<BadNode>,
+}
+
+impl CacheCheck {
+    pub fn is_ok(&self) -> bool {
+        self.mismatches == 0 && self.unset == 0 && !self.truncated
+    }
+}
+
+impl AddAssign for CacheCheck {
+    fn add_assign(&mut self, other: Self) {
+        self.mismatches += other.mismatches;
+        self.unset += other.unset;
+        self.truncated |= other.truncated;
+        let room = MAX_CHECK_SAMPLES.saturating_sub(self.samples.len());
+        self.samples.extend(other.samples.into_iter().take(room));
+    }
+}
+
+/// Re-read all nodes of the PBF file, and compare their positions with the ones in the cache.
+/// Nodes beyond the end of a truncated dense cache are reported as unset.
+pub fn check_cache(
+    pbf_file: &Path,
+    cache_file: &Path,
+    advice: &[Advice],
+    skip_bad_blobs: bool,
+) -> Result<(NodeCacheInfo, CacheCheck)> {
+    let cache = open_cache(cache_file, advice)?;
+    let capacity = match cache {
+        NodeCache::Dense(_) => std::fs::metadata(cache_file)?.len() / 8,
+        _ => u64::MAX,
+    };
+    let check = Mutex::new(CacheCheck::default());
+    let mut info = scan_nodes(
+        pbf_file,
+        skip_bad_blobs,
+        (&cache, &check),
+        |(cache, check), nodes| {
+            let reader = cache.reader();
+            let mut result = CacheCheck::default();
+            for (id, lat, lon) in nodes {
+                let cached = if (id as u64) < capacity {
+                    reader.get_node(id)
+                } else {
+                    result.truncated = true;
+                    None
+                };
+                match cached {
+                    Some((cached_lat, cached_lon))
+                        if (cached_lat - lat).abs() <= CHECK_TOLERANCE
+                            && (cached_lon - lon).abs() <= CHECK_TOLERANCE =>
+                    {
+                        continue
+                    }
+                    Some(_) => result.mismatches += 1,
+                    None => result.unset += 1,
+                }
+                if result.samples.len() < MAX_CHECK_SAMPLES {
+                    result.samples.push(BadNode {
+                        id,
+                        expected: (lat, lon),
+                        cached,
+                    });
+                }
+            }
+            *check.lock().unwrap() += result;
+            Ok(())
+        },
+    )?;
+    info.kind = cache.kind();
+    let mut check = check.into_inner().unwrap();
+    check.samples.sort_by_key(|node| node.id);
+    Ok((info, check))
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn stats(node_count: usize, max_node_id: i64) -> NodeStats {
+        NodeStats {
+            node_count,
+            min_node_id: 1,
+            max_node_id,
+            ..NodeStats::default()
+        }
+    }
+
+    #[test]
+    fn test_kind_for_stats() {
+        // A planet has most of the IDs in use
+        assert_eq!(
+            CacheKind::for_stats(&stats(8_000_000_000, 10_000_000_000)),
+            CacheKind::Dense
+        );
+        // A city extract uses a tiny fraction of the same ID range
+        assert_eq!(
+            CacheKind::for_stats(&stats(2_000_000, 10_000_000_000)),
+            CacheKind::Sparse
+        );
+        assert_eq!(CacheKind::for_stats(&stats(101, 800)), CacheKind::Dense);
+        assert_eq!(CacheKind::for_stats(&stats(100, 800)), CacheKind::Sparse);
+        assert_eq!(
+            CacheKind::for_stats(&NodeStats::default()),
+            CacheKind::Sparse
+        );
+    }
+
+    #[test]
+    fn test_get_node() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.cache");
+        let (_, lat, lon) = not_unset(7, 0.0, 0.0);
+        let nodes = [(5, pack_lat_lon(1.5, -2.5)), (7, pack_lat_lon(lat, lon))];
+        SparseFileCache::write(&path, nodes.iter().copied()).unwrap();
+        let cache = open_cache(&path, &[]).unwrap();
+        let reader = cache.reader();
+        assert_eq!(reader.get_node(5), Some((1.5, -2.5)));
+        assert_eq!(reader.get_node(7), Some((0.0, 1e-7)));
+        assert_eq!(reader.get_node(6), None);
+        assert_eq!(reader.get_node(-5), None);
+    }
+
+    #[test]
+    fn test_merge_runs() {
+        let runs = vec![
+            vec![(1, 10), (4, 40), (9, 90)],
+            vec![],
+            vec![(2, 20), (4, 41), (5, 50)],
+        ];
+        let merged: Vec<_> = merge_runs(&runs).collect();
+        assert_eq!(merged, [(1, 10), (2, 20), (4, 40), (5, 50), (9, 90)]);
+        assert_eq!(merge_runs(&[]).count(), 0);
+    }
+
+    #[test]
+    fn test_check_samples() {
+        let bad = |id| CacheCheck {
+            mismatches: 1,
+            samples: vec![BadNode {
+                id,
+                expected: (1.0, 2.0),
+                cached: Some((0.5, 2.0)),
+            }],
+            ..CacheCheck::default()
+        };
+        let mut check = CacheCheck::default();
+        assert!(check.is_ok());
+        for id in 0..MAX_CHECK_SAMPLES as i64 + 5 {
+            check += bad(id);
+        }
+        assert!(!check.is_ok());
+        assert_eq!(check.mismatches, MAX_CHECK_SAMPLES + 5);
+        assert_eq!(check.samples.len(), MAX_CHECK_SAMPLES);
+        assert_eq!(stats(50, 100).id_fill_ratio(), 0.5);
+    }
+}
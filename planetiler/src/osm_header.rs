@@ -0,0 +1,353 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use anyhow::{bail, ensure, Context, Result};
+use flate2::read::ZlibDecoder;
+
+use crate::pbf::SORT_TYPE_THEN_ID;
+use crate::varint::{read_varint, zigzag_decode};
+
+/// Largest size of a blob header allowed by the PBF format.
+const MAX_BLOB_HEADER_SIZE: usize = 64 * 1024;
+
+/// Largest size of a blob allowed by the PBF format.
+const MAX_BLOB_SIZE: usize = 32 * 1024 * 1024;
+
+/// Replication state of the OSM data, as set by Osmosis or osmium.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Replication {
+    /// Seconds since the Unix epoch
+    pub timestamp: Option<i64>,
+    pub sequence: Option<i64>,
+    pub base_url: Option<String>,
+}
+
+impl Replication {
+    /// Timestamp formatted as an ISO 8601 UTC date and time, e.g. `2022-02-14T21:00:00Z`.
+    pub fn time(&self) -> Option<String> {
+        self.timestamp.map(format_timestamp)
+    }
+}
+
+/// Contents of the `OSMHeader` blob at the start of a PBF file.
+///
+/// ```no_run
+/// use planetiler::osm_header::OsmHeader;
+///
+/// if let Some(header) = OsmHeader::read("planet.osm.pbf")? {
+///     println!("{header}");
+/// }
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OsmHeader {
+    /// min_lon, min_lat, max_lon, max_lat, same as [`crate::mbtiles::MbtilesMetadata::bounds`]
+    pub bbox: Option<[f64; 4]>,
+    /// Features a reader must support to read the file, e.g. `DenseNodes`
+    pub required_features: Vec<String>,
+    /// Features a reader may use, e.g. [`SORT_TYPE_THEN_ID`]
+    pub optional_features: Vec<String>,
+    pub writing_program: Option<String>,
+    pub source: Option<String>,
+    pub replication: Replication,
+}
+
+impl OsmHeader {
+    /// Read the header blob of a PBF file. Only the first blob of the file is read,
+    /// and None is returned if it is not an `OSMHeader` blob.
+    pub fn read(pbf_file: impl AsRef<Path>) -> Result<Option<Self>> {
+        let pbf_file = pbf_file.as_ref();
+        let file = File::open(pbf_file)
+            .with_context(|| format!("Unable to open {}", pbf_file.display()))?;
+        Self::from_reader(BufReader::new(file))
+            .with_context(|| format!("Unable to read the OSM header of {}", pbf_file.display()))
+    }
+
+    fn from_reader(mut reader: impl Read) -> Result<Option<Self>> {
+        let mut len = [0_u8; 4];
+        reader.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        ensure!(len <= MAX_BLOB_HEADER_SIZE, "Blob header is too large");
+        let mut blob_header = vec![0; len];
+        reader.read_exact(&mut blob_header)?;
+
+        let (mut blob_type, mut blob_size) = (None, None);
+        for field in Fields::new(&blob_header) {
+            match field? {
+                (1, Value::Bytes(v)) => blob_type = Some(v),
+                (3, Value::Varint(v)) => blob_size = Some(v as usize),
+                _ => {}
+            }
+        }
+        if blob_type != Some(&b"OSMHeader"[..]) {
+            return Ok(None);
+        }
+        let blob_size = blob_size.context("Blob header has no data size")?;
+        ensure!(blob_size <= MAX_BLOB_SIZE, "Header blob is too large");
+        let mut blob = vec![0; blob_size];
+        reader.read_exact(&mut blob)?;
+        Ok(Some(Self::parse(&decode_blob(&blob)?)?))
+    }
+
+    /// Parse an uncompressed `HeaderBlock` message.
+    fn parse(data: &[u8]) -> Result<Self> {
+        let mut header = Self::default();
+        for field in Fields::new(data) {
+            match field? {
+                (1, Value::Bytes(v)) => header.bbox = Some(parse_bbox(v)?),
+                (4, Value::Bytes(v)) => header.required_features.push(to_string(v)),
+                (5, Value::Bytes(v)) => header.optional_features.push(to_string(v)),
+                (16, Value::Bytes(v)) => header.writing_program = Some(to_string(v)),
+                (17, Value::Bytes(v)) => header.source = Some(to_string(v)),
+                (32, Value::Varint(v)) => header.replication.timestamp = Some(v as i64),
+                (33, Value::Varint(v)) => header.replication.sequence = Some(v as i64),
+                (34, Value::Bytes(v)) => header.replication.base_url = Some(to_string(v)),
+                _ => {}
+            }
+        }
+        Ok(header)
+    }
+
+    /// True if the header declares the file sorted by type, then ID.
+    pub fn is_sorted(&self) -> bool {
+        self.optional_features
+            .iter()
+            .any(|f| f == SORT_TYPE_THEN_ID)
+    }
+}
+
+impl std::fmt::Display for OsmHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let unknown = "unknown".to_string();
+        match self.bbox {
+            Some([min_lon, min_lat, max_lon, max_lat]) => {
+                writeln!(f, "Bounds: {min_lon},{min_lat},{max_lon},{max_lat}")?
+            }
+            None => writeln!(f, "Bounds: none")?,
+        }
+        writeln!(
+            f,
+            "Written by {} from {}",
+            self.writing_program.as_ref().unwrap_or(&unknown),
+            self.source.as_ref().unwrap_or(&unknown)
+        )?;
+        writeln!(
+            f,
+            "Replication: {} (sequence {}) from {}",
+            self.replication.time().unwrap_or_else(|| unknown.clone()),
+            self.replication
+                .sequence
+                .map_or_else(|| unknown.clone(), |v| v.to_string()),
+            self.replication.base_url.as_ref().unwrap_or(&unknown)
+        )?;
+        writeln!(
+            f,
+            "Required features: {}",
+            self.required_features.join(", ")
+        )?;
+        write!(
+            f,
+            "Optional features: {}",
+            self.optional_features.join(", ")
+        )
+    }
+}
+
+/// Uncompressed contents of a `Blob` message.
+fn decode_blob(blob: &[u8]) -> Result<Vec<u8>> {
+    let mut raw_size = None;
+    for field in Fields::new(blob) {
+        match field? {
+            (1, Value::Bytes(v)) => return Ok(v.to_vec()),
+            (2, Value::Varint(v)) => raw_size = Some(v as usize),
+            (3, Value::Bytes(v)) => {
+                let raw_size = raw_size.unwrap_or(0).min(MAX_BLOB_SIZE);
+                let mut data = Vec::with_capacity(raw_size);
+                ZlibDecoder::new(v)
+                    .take(MAX_BLOB_SIZE as u64)
+                    .read_to_end(&mut data)
+                    .context("Unable to decompress the header blob")?;
+                return Ok(data);
+            }
+            (4..=7, _) => bail!("Unsupported compression of the header blob"),
+            _ => {}
+        }
+    }
+    bail!("Header blob has no data")
+}
+
+/// Bounding box in nanodegrees, converted to min_lon, min_lat, max_lon, max_lat.
+fn parse_bbox(data: &[u8]) -> Result<[f64; 4]> {
+    let mut bbox = [0.0; 4];
+    for field in Fields::new(data) {
+        if let (idx @ 1..=4, Value::Varint(v)) = field? {
+            // left, right, top, bottom
+            let pos = [0, 2, 3, 1][idx as usize - 1];
+            bbox[pos] = zigzag_decode(v) as f64 / 1e9;
+        }
+    }
+    Ok(bbox)
+}
+
+fn to_string(data: &[u8]) -> String {
+    String::from_utf8_lossy(data).into_owned()
+}
+
+/// Days since the Unix epoch to a (year, month, day) date of the proleptic Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn format_timestamp(timestamp: i64) -> String {
+    let (year, month, day) = civil_from_days(timestamp.div_euclid(86400));
+    let secs = timestamp.rem_euclid(86400);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Value of a protobuf field. Fixed size fields are not used by the header messages.
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+/// Iterator over the (field number, value) pairs of a protobuf message.
+struct Fields<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn read_field(&mut self) -> Result<(u64, Value<'a>)> {
+        let key = read_varint(self.data, &mut self.pos)?;
+        let value = match key & 7 {
+            0 => Value::Varint(read_varint(self.data, &mut self.pos)?),
+            1 | 5 => {
+                self.pos += if key & 7 == 1 { 8 } else { 4 };
+                Value::Fixed
+            }
+            2 => {
+                let len = read_varint(self.data, &mut self.pos)? as usize;
+                let end = self.pos.saturating_add(len);
+                let value = self.data.get(self.pos..end).context("Field is truncated")?;
+                self.pos = end;
+                Value::Bytes(value)
+            }
+            wire_type => bail!("Unsupported protobuf wire type {wire_type}"),
+        };
+        ensure!(self.pos <= self.data.len(), "Field is truncated");
+        Ok((key >> 3, value))
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = Result<(u64, Value<'a>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.data.len() {
+            return None;
+        }
+        let field = self.read_field();
+        if field.is_err() {
+            // Stop after the first error
+            self.pos = self.data.len();
+        }
+        Some(field)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+
+    use super::*;
+    use crate::varint::{write_varint, zigzag_encode};
+
+    fn bytes_field(buf: &mut Vec<u8>, field: u64, value: &[u8]) {
+        write_varint(buf, field << 3 | 2);
+        write_varint(buf, value.len() as u64);
+        buf.extend_from_slice(value);
+    }
+
+    fn varint_field(buf: &mut Vec<u8>, field: u64, value: u64) {
+        write_varint(buf, field << 3);
+        write_varint(buf, value);
+    }
+
+    #[test]
+    fn test_read_header() {
+        let mut bbox = Vec::new();
+        for (field, degrees) in [(1, -10.5), (2, 20.0), (3, 50.25), (4, -5.0)] {
+            varint_field(&mut bbox, field, zigzag_encode((degrees * 1e9) as i64));
+        }
+        let mut block = Vec::new();
+        bytes_field(&mut block, 1, &bbox);
+        bytes_field(&mut block, 4, b"OsmSchema-V0.6");
+        bytes_field(&mut block, 4, b"DenseNodes");
+        bytes_field(&mut block, 5, SORT_TYPE_THEN_ID.as_bytes());
+        bytes_field(&mut block, 16, b"osmium/1.14.0");
+        varint_field(&mut block, 32, 1_644_872_400);
+        varint_field(&mut block, 33, 3_456);
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&block).unwrap();
+        let mut blob = Vec::new();
+        varint_field(&mut blob, 2, block.len() as u64);
+        bytes_field(&mut blob, 3, &encoder.finish().unwrap());
+        let mut blob_header = Vec::new();
+        bytes_field(&mut blob_header, 1, b"OSMHeader");
+        varint_field(&mut blob_header, 3, blob.len() as u64);
+        let mut file = (blob_header.len() as u32).to_be_bytes().to_vec();
+        file.extend(blob_header);
+        file.extend(blob);
+
+        let header = OsmHeader::from_reader(&file[..]).unwrap().unwrap();
+        assert_eq!(header.bbox, Some([-10.5, -5.0, 20.0, 50.25]));
+        assert_eq!(header.required_features, ["OsmSchema-V0.6", "DenseNodes"]);
+        assert!(header.is_sorted());
+        assert_eq!(header.writing_program.as_deref(), Some("osmium/1.14.0"));
+        assert_eq!(header.source, None);
+        assert_eq!(
+            header.replication.time().as_deref(),
+            Some("2022-02-14T21:00:00Z")
+        );
+        assert_eq!(header.replication.sequence, Some(3_456));
+        assert!(OsmHeader::from_reader(&file[..20]).is_err());
+
+        let mut data_header = Vec::new();
+        bytes_field(&mut data_header, 1, b"OSMData");
+        varint_field(&mut data_header, 3, 0);
+        let mut file = (data_header.len() as u32).to_be_bytes().to_vec();
+        file.extend(data_header);
+        assert_eq!(OsmHeader::from_reader(&file[..]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_timestamp(-1), "1969-12-31T23:59:59Z");
+    }
+}
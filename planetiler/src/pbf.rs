@@ -0,0 +1,55 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use osmpbf::{Blob, BlobDecode, PrimitiveBlock};
+
+use crate::osm_header::OsmHeader;
+
+/// Optional feature of the PBF header declaring that all nodes come before ways,
+/// all ways before relations, and that each of them is ordered by ID.
+pub const SORT_TYPE_THEN_ID: &str = "Sort.Type_then_ID";
+
+/// A decoded block of OSM data, with the offset of its blob in the file if it is known.
+pub struct DataBlock {
+    pub offset: Option<u64>,
+    pub block: PrimitiveBlock,
+}
+
+/// Decode a blob returned by `BlobReader` into a block of OSM data.
+/// Returns None for header and unknown blobs. Errors include the blob offset if it is known.
+pub fn decode_data_block(blob: osmpbf::Result<Blob>) -> Result<Option<DataBlock>> {
+    let blob = blob.context("Unable to read blob")?;
+    let offset = blob.offset().map(|v| v.0);
+    let decoded = blob.decode().with_context(|| match offset {
+        Some(offset) => format!("Unable to decode blob at offset {offset}"),
+        None => "Unable to decode blob".to_string(),
+    })?;
+    Ok(match decoded {
+        BlobDecode::OsmData(block) => Some(DataBlock { offset, block }),
+        _ => None,
+    })
+}
+
+/// Same as [`decode_data_block`], but with `skip_bad_blobs` set, errors are reported
+/// to stderr and counted in `bad_blobs` instead of being returned.
+pub fn decode_data_block_or_skip(
+    blob: osmpbf::Result<Blob>,
+    skip_bad_blobs: bool,
+    bad_blobs: &mut usize,
+) -> Result<Option<DataBlock>> {
+    match decode_data_block(blob) {
+        Err(err) if skip_bad_blobs => {
+            eprintln!("Skipping bad blob: {err:#}");
+            *bad_blobs += 1;
+            Ok(None)
+        }
+        result => result,
+    }
+}
+
+/// Check if the header block of a PBF file declares it sorted by type, then ID.
+/// Files without a header block or the [`SORT_TYPE_THEN_ID`] feature may still be sorted,
+/// use [`crate::blob_index::BlobIndex::check_sorted`] to find out.
+pub fn is_declared_sorted(pbf_file: impl AsRef<Path>) -> Result<bool> {
+    Ok(OsmHeader::read(pbf_file)?.is_some_and(|h| h.is_sorted()))
+}
@@ -0,0 +1,628 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::dedup::TileDeduplicator;
+use crate::mbtiles::MbtilesMetadata;
+use crate::osm_header::OsmHeader;
+use crate::tile_id::{TileID, MAX_ZOOM_64};
+use crate::tile_order::HilbertTileID;
+use crate::varint::{read_varint, write_varint};
+
+const MAGIC: &[u8; 7] = b"PMTiles";
+const VERSION: u8 = 3;
+const HEADER_SIZE: usize = 127;
+/// Header and root directory must fit into the first 16K of the file
+const MAX_ROOT_SIZE: usize = 16384 - HEADER_SIZE;
+
+const COMPRESSION_NONE: u8 = 1;
+const COMPRESSION_GZIP: u8 = 2;
+const TILE_TYPE_MVT: u8 = 1;
+
+/// One directory entry. A run_length of 0 means the entry points to a leaf directory.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct Entry {
+    tile_id: u64,
+    offset: u64,
+    length: u32,
+    run_length: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Header {
+    root_offset: u64,
+    root_length: u64,
+    metadata_offset: u64,
+    metadata_length: u64,
+    leaf_offset: u64,
+    leaf_length: u64,
+    data_offset: u64,
+    data_length: u64,
+    pub addressed_tiles: u64,
+    pub tile_entries: u64,
+    pub tile_contents: u64,
+    pub clustered: bool,
+    internal_compression: u8,
+    tile_compression: u8,
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    /// min_lon, min_lat, max_lon, max_lat
+    pub bounds: [f64; 4],
+    pub center_zoom: u8,
+    pub center: [f64; 2],
+}
+
+impl Header {
+    fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_SIZE);
+        buf.extend_from_slice(MAGIC);
+        buf.push(VERSION);
+        for v in [
+            self.root_offset,
+            self.root_length,
+            self.metadata_offset,
+            self.metadata_length,
+            self.leaf_offset,
+            self.leaf_length,
+            self.data_offset,
+            self.data_length,
+            self.addressed_tiles,
+            self.tile_entries,
+            self.tile_contents,
+        ] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        buf.push(self.clustered as u8);
+        buf.push(self.internal_compression);
+        buf.push(self.tile_compression);
+        buf.push(TILE_TYPE_MVT);
+        buf.push(self.min_zoom);
+        buf.push(self.max_zoom);
+        for v in self.bounds {
+            buf.extend_from_slice(&to_e7(v).to_le_bytes());
+        }
+        buf.push(self.center_zoom);
+        for v in self.center {
+            buf.extend_from_slice(&to_e7(v).to_le_bytes());
+        }
+        debug_assert_eq!(buf.len(), HEADER_SIZE);
+        buf
+    }
+
+    fn deserialize(buf: &[u8]) -> Result<Self> {
+        if buf.len() < HEADER_SIZE || &buf[0..7] != MAGIC {
+            bail!("Not a PMTiles file");
+        }
+        if buf[7] != VERSION {
+            bail!("Unsupported PMTiles version {}", buf[7]);
+        }
+        let u64_at = |pos: usize| u64::from_le_bytes(buf[pos..pos + 8].try_into().unwrap());
+        let e7_at = |pos: usize| {
+            i32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap()) as f64 / 10_000_000.0
+        };
+        Ok(Self {
+            root_offset: u64_at(8),
+            root_length: u64_at(16),
+            metadata_offset: u64_at(24),
+            metadata_length: u64_at(32),
+            leaf_offset: u64_at(40),
+            leaf_length: u64_at(48),
+            data_offset: u64_at(56),
+            data_length: u64_at(64),
+            addressed_tiles: u64_at(72),
+            tile_entries: u64_at(80),
+            tile_contents: u64_at(88),
+            clustered: buf[96] == 1,
+            internal_compression: buf[97],
+            tile_compression: buf[98],
+            min_zoom: buf[100],
+            max_zoom: buf[101],
+            bounds: [e7_at(102), e7_at(106), e7_at(110), e7_at(114)],
+            center_zoom: buf[118],
+            center: [e7_at(119), e7_at(123)],
+        })
+    }
+}
+
+fn to_e7(value: f64) -> i32 {
+    (value * 10_000_000.0).round() as i32
+}
+
+fn gzip(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+fn gunzip(data: &[u8]) -> Result<Vec<u8>> {
+    let mut result = Vec::new();
+    GzDecoder::new(data).read_to_end(&mut result)?;
+    Ok(result)
+}
+
+/// Serialize and gzip a directory: entry count followed by columns of
+/// delta-encoded tile IDs, run lengths, lengths, and offsets.
+fn serialize_directory(entries: &[Entry]) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    write_varint(&mut buf, entries.len() as u64);
+    let mut last_id = 0;
+    for e in entries {
+        write_varint(&mut buf, e.tile_id - last_id);
+        last_id = e.tile_id;
+    }
+    for e in entries {
+        write_varint(&mut buf, e.run_length as u64);
+    }
+    for e in entries {
+        write_varint(&mut buf, e.length as u64);
+    }
+    for (i, e) in entries.iter().enumerate() {
+        // Offset of 0 means "immediately after the previous entry"
+        if i > 0 && e.offset == entries[i - 1].offset + entries[i - 1].length as u64 {
+            write_varint(&mut buf, 0);
+        } else {
+            write_varint(&mut buf, e.offset + 1);
+        }
+    }
+    gzip(&buf)
+}
+
+fn deserialize_directory(data: &[u8]) -> Result<Vec<Entry>> {
+    let buf = gunzip(data)?;
+    let mut pos = 0;
+    let count = read_varint(&buf, &mut pos)?;
+    // Each entry takes at least one byte for each of its four values
+    if count > ((buf.len() - pos) / 4) as u64 {
+        bail!(
+            "PMTiles directory with {count} entries is only {} bytes",
+            buf.len()
+        );
+    }
+    let count = count as usize;
+    let mut entries = vec![
+        Entry {
+            tile_id: 0,
+            offset: 0,
+            length: 0,
+            run_length: 0,
+        };
+        count
+    ];
+    let mut last_id = 0_u64;
+    for e in entries.iter_mut() {
+        last_id = last_id
+            .checked_add(read_varint(&buf, &mut pos)?)
+            .context("Invalid tile ID in PMTiles directory")?;
+        e.tile_id = last_id;
+    }
+    for e in entries.iter_mut() {
+        e.run_length = u32::try_from(read_varint(&buf, &mut pos)?)
+            .context("Invalid run length in PMTiles directory")?;
+    }
+    for e in entries.iter_mut() {
+        e.length = u32::try_from(read_varint(&buf, &mut pos)?)
+            .context("Invalid tile length in PMTiles directory")?;
+    }
+    for i in 0..count {
+        let value = read_varint(&buf, &mut pos)?;
+        entries[i].offset = if value == 0 && i > 0 {
+            entries[i - 1]
+                .offset
+                .checked_add(u64::from(entries[i - 1].length))
+                .context("Invalid offset in PMTiles directory")?
+        } else {
+            value
+                .checked_sub(1)
+                .context("Invalid offset in PMTiles directory")?
+        };
+    }
+    Ok(entries)
+}
+
+/// Build the root directory, moving entries into leaf directories if the root would not fit.
+/// Returns (root, leaves).
+fn build_directories(entries: &[Entry], max_root_size: usize) -> Result<(Vec<u8>, Vec<u8>)> {
+    let root = serialize_directory(entries)?;
+    if root.len() <= max_root_size {
+        return Ok((root, Vec::new()));
+    }
+    let mut leaf_size = 4096.max(entries.len() / 3500);
+    loop {
+        let mut leaves = Vec::new();
+        let mut root_entries = Vec::new();
+        for chunk in entries.chunks(leaf_size) {
+            let leaf = serialize_directory(chunk)?;
+            root_entries.push(Entry {
+                tile_id: chunk[0].tile_id,
+                offset: leaves.len() as u64,
+                length: leaf.len() as u32,
+                run_length: 0,
+            });
+            leaves.extend_from_slice(&leaf);
+        }
+        let root = serialize_directory(&root_entries)?;
+        if root.len() <= max_root_size {
+            return Ok((root, leaves));
+        }
+        leaf_size += leaf_size / 5;
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct PmtilesStats {
+    pub tiles: u64,
+    pub entries: u64,
+    pub unique_tiles: u64,
+    pub bytes: u64,
+}
+
+/// Writes encoded vector tiles into a PMTiles v3 archive.
+/// Tile data is first written to a temporary file next to the output,
+/// and the archive is assembled in [`PmtilesWriter::finish`] once all directories are known.
+/// Tiles should be added in Hilbert order (see [`HilbertTileID`]) to produce
+/// a clustered archive, otherwise entries are sorted at the end.
+pub struct PmtilesWriter {
+    path: PathBuf,
+    data_path: PathBuf,
+    data: BufWriter<File>,
+    data_length: u64,
+    compress: bool,
+    entries: Vec<Entry>,
+    /// Tile data -> (offset, length) of already written small tiles
+    known_tiles: TileDeduplicator<(u64, u32)>,
+    osm_header: Option<OsmHeader>,
+    clustered: bool,
+    max_root_size: usize,
+    min_zoom: u8,
+    max_zoom: u8,
+    stats: PmtilesStats,
+}
+
+impl PmtilesWriter {
+    pub fn create(path: &Path) -> Result<Self> {
+        let mut data_path = path.as_os_str().to_owned();
+        data_path.push(".tiles.tmp");
+        let data_path = PathBuf::from(data_path);
+        let data = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&data_path)
+            .with_context(|| format!("Unable to create {}", data_path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            data_path,
+            data: BufWriter::new(data),
+            data_length: 0,
+            compress: true,
+            entries: Vec::new(),
+            known_tiles: TileDeduplicator::default(),
+            osm_header: None,
+            clustered: true,
+            max_root_size: MAX_ROOT_SIZE,
+            min_zoom: MAX_ZOOM_64,
+            max_zoom: 0,
+            stats: PmtilesStats::default(),
+        })
+    }
+
+    /// Gzip tile data before storing it (default). Disable if tiles are already compressed.
+    pub fn compress(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Header of the PBF file the tiles were built from. Its bounding box and replication
+    /// state replace the ones of the metadata, see [`MbtilesMetadata::set_osm_header`].
+    pub fn osm_header(mut self, header: OsmHeader) -> Self {
+        self.osm_header = Some(header);
+        self
+    }
+
+    pub fn write_tile(&mut self, tile: TileID, data: &[u8]) -> Result<()> {
+        let data = if self.compress {
+            gzip(data)?
+        } else {
+            data.to_vec()
+        };
+        let tile_id = HilbertTileID::from(tile).value();
+        self.min_zoom = self.min_zoom.min(tile.zoom);
+        self.max_zoom = self.max_zoom.max(tile.zoom);
+        self.stats.tiles += 1;
+
+        let (offset, length) = match self.known_tiles.get(&data) {
+            Some(v) => v,
+            None => {
+                let v = (self.data_length, data.len() as u32);
+                self.data.write_all(&data)?;
+                self.data_length += data.len() as u64;
+                self.known_tiles.insert(&data, v);
+                self.stats.unique_tiles += 1;
+                v
+            }
+        };
+
+        if let Some(last) = self.entries.last_mut() {
+            if tile_id < last.tile_id + last.run_length as u64 {
+                self.clustered = false;
+            }
+            // Consecutive identical tiles are stored as a single run
+            if last.offset == offset && last.tile_id + last.run_length as u64 == tile_id {
+                last.run_length += 1;
+                return Ok(());
+            }
+        }
+        self.entries.push(Entry {
+            tile_id,
+            offset,
+            length,
+            run_length: 1,
+        });
+        Ok(())
+    }
+
+    /// Write the header, directories, metadata, and tile data into the final file.
+    pub fn finish(mut self, metadata: &MbtilesMetadata) -> Result<PmtilesStats> {
+        self.data.flush()?;
+        if !self.clustered {
+            self.entries.sort_by_key(|e| e.tile_id);
+        }
+        let (root, leaves) = build_directories(&self.entries, self.max_root_size)?;
+        let metadata = metadata.with_osm_header(self.osm_header.as_ref());
+        let metadata_json = gzip(metadata.to_json().to_string().as_bytes())?;
+
+        let (lon, lat, zoom) = metadata.center();
+        let header = Header {
+            root_offset: HEADER_SIZE as u64,
+            root_length: root.len() as u64,
+            metadata_offset: (HEADER_SIZE + root.len()) as u64,
+            metadata_length: metadata_json.len() as u64,
+            leaf_offset: (HEADER_SIZE + root.len() + metadata_json.len()) as u64,
+            leaf_length: leaves.len() as u64,
+            data_offset: (HEADER_SIZE + root.len() + metadata_json.len() + leaves.len()) as u64,
+            data_length: self.data_length,
+            addressed_tiles: self.stats.tiles,
+            tile_entries: self.entries.len() as u64,
+            tile_contents: self.stats.unique_tiles,
+            clustered: self.clustered,
+            internal_compression: COMPRESSION_GZIP,
+            tile_compression: if self.compress {
+                COMPRESSION_GZIP
+            } else {
+                COMPRESSION_NONE
+            },
+            min_zoom: self.min_zoom.min(self.max_zoom),
+            max_zoom: self.max_zoom,
+            bounds: metadata.bounds,
+            center_zoom: zoom,
+            center: [lon, lat],
+        };
+
+        let mut out = BufWriter::new(
+            File::create(&self.path)
+                .with_context(|| format!("Unable to create {}", self.path.display()))?,
+        );
+        out.write_all(&header.serialize())?;
+        out.write_all(&root)?;
+        out.write_all(&metadata_json)?;
+        out.write_all(&leaves)?;
+        let mut data = self.data.into_inner()?;
+        data.seek(SeekFrom::Start(0))?;
+        std::io::copy(&mut data, &mut out)?;
+        out.flush()?;
+        drop(data);
+        std::fs::remove_file(&self.data_path)?;
+
+        self.stats.entries = self.entries.len() as u64;
+        self.stats.bytes = header.data_offset + header.data_length;
+        Ok(self.stats)
+    }
+}
+
+/// Reads tiles back from a PMTiles v3 archive.
+pub struct PmtilesReader {
+    file: BufReader<File>,
+    header: Header,
+    root: Vec<Entry>,
+}
+
+impl PmtilesReader {
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut file = BufReader::new(
+            File::open(path).with_context(|| format!("Unable to open {}", path.display()))?,
+        );
+        let mut buf = [0_u8; HEADER_SIZE];
+        file.read_exact(&mut buf)?;
+        let header = Header::deserialize(&buf)?;
+        if header.internal_compression != COMPRESSION_GZIP {
+            bail!(
+                "Unsupported internal compression {}",
+                header.internal_compression
+            );
+        }
+        let mut reader = Self {
+            file,
+            header,
+            root: Vec::new(),
+        };
+        let root = reader.read_at(reader.header.root_offset, reader.header.root_length)?;
+        reader.root = deserialize_directory(&root)?;
+        Ok(reader)
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn metadata(&mut self) -> Result<serde_json::Value> {
+        let data = self.read_at(self.header.metadata_offset, self.header.metadata_length)?;
+        Ok(serde_json::from_slice(&gunzip(&data)?)?)
+    }
+
+    /// Get decompressed tile data, or None if the tile is not in the archive.
+    pub fn get_tile(&mut self, tile: TileID) -> Result<Option<Vec<u8>>> {
+        let tile_id = HilbertTileID::from(tile).value();
+        let mut entry = find_entry(&self.root, tile_id);
+        // Leaf directories may in theory be nested
+        for _ in 0..4 {
+            match entry {
+                None => return Ok(None),
+                Some(e) if e.run_length > 0 => {
+                    let data = self.read_at(self.header.data_offset + e.offset, e.length as u64)?;
+                    return Ok(Some(if self.header.tile_compression == COMPRESSION_GZIP {
+                        gunzip(&data)?
+                    } else {
+                        data
+                    }));
+                }
+                Some(e) => {
+                    let leaf = self.read_at(self.header.leaf_offset + e.offset, e.length as u64)?;
+                    entry = find_entry(&deserialize_directory(&leaf)?, tile_id);
+                }
+            }
+        }
+        bail!("Too many nested PMTiles directories")
+    }
+
+    fn read_at(&mut self, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let mut buf = vec![0; length as usize];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// Find the entry covering the tile ID: either a tile run containing it, or a leaf directory.
+fn find_entry(entries: &[Entry], tile_id: u64) -> Option<Entry> {
+    let idx = match entries.binary_search_by_key(&tile_id, |e| e.tile_id) {
+        Ok(idx) => idx,
+        Err(0) => return None,
+        Err(idx) => idx - 1,
+    };
+    let entry = entries[idx];
+    if entry.run_length == 0 || tile_id < entry.tile_id + entry.run_length as u64 {
+        Some(entry)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::osm_header::Replication;
+    use crate::tile_id::max_dimension;
+
+    #[test]
+    fn test_directory_roundtrip() {
+        let entries = vec![
+            Entry {
+                tile_id: 0,
+                offset: 0,
+                length: 10,
+                run_length: 1,
+            },
+            Entry {
+                tile_id: 5,
+                offset: 10,
+                length: 20,
+                run_length: 3,
+            },
+            Entry {
+                tile_id: 9,
+                offset: 0,
+                length: 10,
+                run_length: 1,
+            },
+        ];
+        let data = serialize_directory(&entries).unwrap();
+        assert_eq!(deserialize_directory(&data).unwrap(), entries);
+    }
+
+    #[test]
+    fn test_invalid_directory() {
+        // The first entry cannot continue a previous one
+        let mut data = Vec::new();
+        for value in [1, 0, 1, 10, 0] {
+            write_varint(&mut data, value);
+        }
+        assert!(deserialize_directory(&gzip(&data).unwrap()).is_err());
+        // More entries than the directory has bytes for
+        let mut data = Vec::new();
+        write_varint(&mut data, u64::MAX);
+        data.extend([1; 8]);
+        assert!(deserialize_directory(&gzip(&data).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.pmtiles");
+        let mut tiles: Vec<_> = (0..=7)
+            .flat_map(|z| {
+                (0..max_dimension(z))
+                    .flat_map(move |x| (0..max_dimension(z)).map(move |y| TileID::new(z, x, y)))
+            })
+            .collect();
+        tiles.sort_by_key(|t| HilbertTileID::from(*t));
+        let content = |t: &TileID| -> Vec<u8> {
+            if t.x < max_dimension(t.zoom) / 2 {
+                b"ocean".to_vec()
+            } else {
+                format!("{}/{}/{}", t.zoom, t.x, t.y).into_bytes()
+            }
+        };
+
+        let header = OsmHeader {
+            bbox: Some([-10.5, -5.0, 20.0, 50.25]),
+            replication: Replication {
+                timestamp: Some(1_644_872_400),
+                sequence: Some(3_456),
+                base_url: Some("https://planet.osm.org/replication/hour/".to_string()),
+            },
+            ..OsmHeader::default()
+        };
+        let mut writer = PmtilesWriter::create(&path)
+            .unwrap()
+            .compress(false)
+            .osm_header(header);
+        // Force leaf directories without generating a huge archive
+        writer.max_root_size = 256;
+        for t in &tiles {
+            writer.write_tile(*t, &content(t)).unwrap();
+        }
+        let stats = writer.finish(&MbtilesMetadata::default()).unwrap();
+        assert_eq!(stats.tiles, tiles.len() as u64);
+        assert!(stats.unique_tiles < stats.tiles);
+
+        let mut reader = PmtilesReader::open(&path).unwrap();
+        assert!(reader.header().clustered);
+        assert_eq!(reader.header().max_zoom, 7);
+        assert!(reader.header().leaf_length > 0);
+        // Each lookup decompresses a whole leaf directory, so only check a sample
+        for t in tiles.iter().step_by(13) {
+            assert_eq!(reader.get_tile(*t).unwrap(), Some(content(t)));
+        }
+        assert_eq!(reader.get_tile(TileID::new(8, 0, 0)).unwrap(), None);
+        assert_eq!(reader.header().bounds, [-10.5, -5.0, 20.0, 50.25]);
+        let metadata = reader.metadata().unwrap();
+        assert_eq!(metadata["name"], "planetiler");
+        assert_eq!(
+            metadata["planetiler:osm:osmosisreplicationtime"],
+            "2022-02-14T21:00:00Z"
+        );
+        assert_eq!(metadata["planetiler:osm:osmosisreplicationseq"], "3456");
+        assert_eq!(
+            metadata["planetiler:osm:osmosisreplicationurl"],
+            "https://planet.osm.org/replication/hour/"
+        );
+    }
+}
@@ -0,0 +1,369 @@
+use geos::Geometry;
+
+use crate::mvt::Value;
+use crate::tile_id::{MAX_ZOOM, MAX_ZOOM_64};
+
+/// Kind of the OSM element a feature was created from.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum ElementKind {
+    Node,
+    Way,
+    Relation,
+}
+
+/// OSM tags of a single element.
+#[derive(Debug, Clone, Default)]
+pub struct Tags<'a>(Vec<(&'a str, &'a str)>);
+
+impl<'a> Tags<'a> {
+    pub fn new(tags: impl Iterator<Item = (&'a str, &'a str)>) -> Self {
+        Self(tags.collect())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&'a str> {
+        self.0.iter().find(|(k, _)| *k == key).map(|(_, v)| *v)
+    }
+
+    pub fn has(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// True if the tag exists and has the given value.
+    pub fn is(&self, key: &str, value: &str) -> bool {
+        self.get(key) == Some(value)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'a str, &'a str)> + '_ {
+        self.0.iter().copied()
+    }
+}
+
+/// An OSM element with its resolved geometry, passed to the [`Profile`] callbacks.
+/// Geometry is in WGS84 (x = longitude, y = latitude).
+pub struct SourceFeature<'a> {
+    pub id: i64,
+    pub kind: ElementKind,
+    pub tags: &'a Tags<'a>,
+    pub geometry: &'a Geometry<'a>,
+}
+
+/// What geometry should be rendered for the feature.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RenderType {
+    /// Use the source point as is
+    Point,
+    /// A single point in the center of the source geometry, e.g. a label for a polygon
+    Centroid,
+    Line,
+    Polygon,
+}
+
+/// One output feature emitted by a profile for a source element.
+#[derive(Debug, Clone)]
+pub struct Feature {
+    pub layer: String,
+    pub render_type: RenderType,
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    pub attrs: Vec<(String, Value)>,
+    /// Features smaller than this many pixels at a given zoom are not rendered at that zoom
+    pub min_pixel_size: f64,
+    /// Features are ordered by this key within a layer of a tile
+    pub sort_key: i32,
+}
+
+impl Feature {
+    fn new(layer: &str, render_type: RenderType) -> Self {
+        Self {
+            layer: layer.to_string(),
+            render_type,
+            min_zoom: 0,
+            // Higher zooms only get the features that ask for them
+            max_zoom: MAX_ZOOM,
+            attrs: Vec::new(),
+            min_pixel_size: 1.0,
+            sort_key: 0,
+        }
+    }
+
+    pub fn min_zoom(&mut self, zoom: u8) -> &mut Self {
+        self.min_zoom = zoom.min(MAX_ZOOM_64);
+        self
+    }
+
+    pub fn max_zoom(&mut self, zoom: u8) -> &mut Self {
+        self.max_zoom = zoom.min(MAX_ZOOM_64);
+        self
+    }
+
+    pub fn zoom_range(&mut self, min_zoom: u8, max_zoom: u8) -> &mut Self {
+        self.min_zoom(min_zoom).max_zoom(max_zoom)
+    }
+
+    /// Set an attribute. Empty string values are ignored, matching how OSM treats empty tags.
+    pub fn attr(&mut self, key: &str, value: impl Into<Value>) -> &mut Self {
+        let value = value.into();
+        if let Value::String(v) = &value {
+            if v.is_empty() {
+                return self;
+            }
+        }
+        self.attrs.push((key.to_string(), value));
+        self
+    }
+
+    /// Copy a tag of the source element into an attribute with the same name, if it exists.
+    pub fn tag_attr(&mut self, tags: &Tags, key: &str) -> &mut Self {
+        if let Some(value) = tags.get(key) {
+            self.attr(key, value);
+        }
+        self
+    }
+
+    pub fn min_pixel_size(&mut self, size: f64) -> &mut Self {
+        self.min_pixel_size = size;
+        self
+    }
+
+    pub fn sort_key(&mut self, key: i32) -> &mut Self {
+        self.sort_key = key;
+        self
+    }
+
+    /// True if the feature should be rendered at the given zoom.
+    pub fn visible_at(&self, zoom: u8) -> bool {
+        self.min_zoom <= zoom && zoom <= self.max_zoom
+    }
+}
+
+/// Collects features emitted by a profile for one source element.
+#[derive(Debug, Default)]
+pub struct FeatureCollector {
+    features: Vec<Feature>,
+}
+
+impl FeatureCollector {
+    pub fn point(&mut self, layer: &str) -> &mut Feature {
+        self.add(layer, RenderType::Point)
+    }
+
+    pub fn centroid(&mut self, layer: &str) -> &mut Feature {
+        self.add(layer, RenderType::Centroid)
+    }
+
+    pub fn line(&mut self, layer: &str) -> &mut Feature {
+        self.add(layer, RenderType::Line)
+    }
+
+    pub fn polygon(&mut self, layer: &str) -> &mut Feature {
+        self.add(layer, RenderType::Polygon)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.features.is_empty()
+    }
+
+    /// Take all collected features, leaving the collector empty for the next element.
+    pub fn drain(&mut self) -> std::vec::Drain<'_, Feature> {
+        self.features.drain(..)
+    }
+
+    fn add(&mut self, layer: &str, render_type: RenderType) -> &mut Feature {
+        self.features.push(Feature::new(layer, render_type));
+        self.features.last_mut().unwrap()
+    }
+}
+
+/// Maps OSM elements to vector tile features. Implementations decide which elements
+/// are rendered, into which layers, at which zooms, and with which attributes.
+/// The reader loops call these methods in parallel, so implementations must be `Sync`.
+pub trait Profile: Sync {
+    /// Name of the tileset, used in the output metadata.
+    fn name(&self) -> &str;
+
+    fn attribution(&self) -> &str {
+        "<a href=\"https://www.openstreetmap.org/copyright\" target=\"_blank\">&copy; OpenStreetMap contributors</a>"
+    }
+
+    /// Called for every node that has at least one tag.
+    fn process_node(&self, _node: &SourceFeature, _features: &mut FeatureCollector) {}
+
+    /// Called for every way after its nodes have been resolved.
+    fn process_way(&self, _way: &SourceFeature, _features: &mut FeatureCollector) {}
+
+    /// Called for every relation that could be assembled into a geometry.
+    fn process_relation(&self, _relation: &SourceFeature, _features: &mut FeatureCollector) {}
+}
+
+/// A tiny example profile rendering water, roads, and named places.
+pub struct SampleProfile;
+
+impl Profile for SampleProfile {
+    fn name(&self) -> &str {
+        "sample"
+    }
+
+    fn process_node(&self, node: &SourceFeature, features: &mut FeatureCollector) {
+        if let Some(place) = node.tags.get("place") {
+            let min_zoom = match place {
+                "country" => 2,
+                "state" => 4,
+                "city" => 5,
+                "town" => 7,
+                "village" => 10,
+                _ => return,
+            };
+            features
+                .point("place")
+                .min_zoom(min_zoom)
+                .attr("class", place)
+                .tag_attr(node.tags, "name")
+                .sort_key(min_zoom as i32);
+        }
+    }
+
+    fn process_way(&self, way: &SourceFeature, features: &mut FeatureCollector) {
+        if let Some(highway) = way.tags.get("highway") {
+            let (min_zoom, rank) = match highway {
+                "motorway" | "trunk" => (4, 1),
+                "primary" => (7, 2),
+                "secondary" => (9, 3),
+                "tertiary" => (11, 4),
+                "residential" | "unclassified" | "service" => (13, 5),
+                _ => return,
+            };
+            features
+                .line("transportation")
+                .min_zoom(min_zoom)
+                .attr("class", highway)
+                .tag_attr(way.tags, "name")
+                .min_pixel_size(0.5)
+                .sort_key(-rank);
+        } else if way.tags.is("natural", "water") {
+            self.water(way, features);
+        }
+    }
+
+    fn process_relation(&self, relation: &SourceFeature, features: &mut FeatureCollector) {
+        if relation.tags.is("natural", "water") {
+            self.water(relation, features);
+        }
+    }
+}
+
+impl SampleProfile {
+    fn water(&self, feature: &SourceFeature, features: &mut FeatureCollector) {
+        features
+            .polygon("water")
+            .min_zoom(6)
+            .attr("class", feature.tags.get("water").unwrap_or("lake"))
+            .min_pixel_size(2.0);
+        if feature.tags.has("name") {
+            features
+                .centroid("water_name")
+                .min_zoom(10)
+                .tag_attr(feature.tags, "name");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Features emitted by the sample profile for an element with these tags
+    fn render(kind: ElementKind, tags: &[(&str, &str)]) -> Vec<Feature> {
+        let tags = Tags::new(tags.iter().copied());
+        let geometry = Geometry::new_from_wkt("POINT (1 2)").unwrap();
+        let source = SourceFeature {
+            id: 1,
+            kind,
+            tags: &tags,
+            geometry: &geometry,
+        };
+        let mut features = FeatureCollector::default();
+        match kind {
+            ElementKind::Node => SampleProfile.process_node(&source, &mut features),
+            ElementKind::Way => SampleProfile.process_way(&source, &mut features),
+            ElementKind::Relation => SampleProfile.process_relation(&source, &mut features),
+        }
+        features.drain().collect()
+    }
+
+    #[test]
+    fn test_zoom_range() {
+        let mut collector = FeatureCollector::default();
+        assert_eq!(collector.point("poi").max_zoom, MAX_ZOOM);
+        let feature = collector.point("poi").zoom_range(3, MAX_ZOOM_64 + 5);
+        assert_eq!((feature.min_zoom, feature.max_zoom), (3, MAX_ZOOM_64));
+        assert!(!feature.visible_at(2));
+        assert!(feature.visible_at(3));
+        assert!(feature.visible_at(MAX_ZOOM_64));
+        feature.min_zoom(MAX_ZOOM_64 + 1);
+        assert_eq!(feature.min_zoom, MAX_ZOOM_64);
+        assert!(!feature.visible_at(MAX_ZOOM_64 - 1));
+        let building = collector.polygon("building").zoom_range(16, 18);
+        assert_eq!((building.min_zoom, building.max_zoom), (16, 18));
+    }
+
+    #[test]
+    fn test_attrs() {
+        let tags = Tags::new([("name", "Main St"), ("ref", "")].into_iter());
+        let mut collector = FeatureCollector::default();
+        collector
+            .line("road")
+            .attr("class", "primary")
+            .attr("note", "")
+            .tag_attr(&tags, "name")
+            .tag_attr(&tags, "ref")
+            .tag_attr(&tags, "missing");
+        let feature = collector.drain().next().unwrap();
+        let keys: Vec<_> = feature.attrs.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, ["class", "name"]);
+        assert!(collector.is_empty());
+    }
+
+    #[test]
+    fn test_sample_profile() {
+        let roads = render(ElementKind::Way, &[("highway", "primary"), ("name", "A")]);
+        assert_eq!(roads.len(), 1);
+        let road = &roads[0];
+        assert_eq!(road.layer, "transportation");
+        assert_eq!(road.render_type, RenderType::Line);
+        assert_eq!((road.min_zoom, road.sort_key), (7, -2));
+        assert!(render(ElementKind::Way, &[("highway", "footway")]).is_empty());
+        assert!(render(ElementKind::Way, &[("building", "yes")]).is_empty());
+
+        let lake = render(ElementKind::Way, &[("natural", "water"), ("name", "L")]);
+        let layers: Vec<_> = lake
+            .iter()
+            .map(|f| (f.layer.as_str(), f.render_type))
+            .collect();
+        assert_eq!(
+            layers,
+            [
+                ("water", RenderType::Polygon),
+                ("water_name", RenderType::Centroid)
+            ]
+        );
+        assert_eq!(
+            render(ElementKind::Relation, &[("natural", "water")]).len(),
+            1
+        );
+
+        let city = render(ElementKind::Node, &[("place", "city")]);
+        assert_eq!(city[0].render_type, RenderType::Point);
+        assert_eq!(city[0].min_zoom, 5);
+        assert!(render(ElementKind::Node, &[("place", "hamlet")]).is_empty());
+        // Nodes are only rendered as places
+        assert!(render(ElementKind::Node, &[("highway", "primary")]).is_empty());
+    }
+}
@@ -0,0 +1,32 @@
+use std::f64::consts::PI;
+
+/// Web Mercator cannot represent the poles, clamp latitudes to the square world
+pub const MAX_LATITUDE: f64 = 85.051_128_779_806_59;
+
+/// Width and height of the Web Mercator (EPSG:3857) world in meters.
+pub const WORLD_METERS: f64 = 2.0 * PI * 6_378_137.0;
+
+/// Project WGS84 longitude and latitude into world-normalized Web Mercator coordinates:
+/// x from 0 at the antimeridian in the west to 1 in the east, y from 0 in the north to 1.
+pub fn project(lon: f64, lat: f64) -> [f64; 2] {
+    let lat = lat.clamp(-MAX_LATITUDE, MAX_LATITUDE).to_radians();
+    let x = (lon + 180.0) / 360.0;
+    let y = (1.0 - (lat.tan() + 1.0 / lat.cos()).ln() / PI) / 2.0;
+    [x, y]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_project() {
+        assert_eq!(project(0.0, 0.0), [0.5, 0.5]);
+        let [x, y] = project(-180.0, MAX_LATITUDE);
+        assert_eq!(x, 0.0);
+        assert!(y.abs() < 1e-9);
+        let [x, y] = project(180.0, -90.0);
+        assert_eq!(x, 1.0);
+        assert!((y - 1.0).abs() < 1e-9);
+    }
+}
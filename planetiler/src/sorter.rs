@@ -0,0 +1,392 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{create_dir_all, remove_file, File};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::mem::size_of;
+use std::ops::AddAssign;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use rayon::slice::ParallelSliceMut;
+
+use crate::tile_id::PackedTileID64;
+
+/// Default amount of feature data kept in memory before spilling a sorted chunk to disk
+pub const DEFAULT_CHUNK_SIZE: usize = 512 * 1024 * 1024;
+
+const SORT_KEY_BITS: u32 = 24;
+const SORT_KEY_BIAS: i32 = 1 << (SORT_KEY_BITS - 1);
+
+/// 128 bit sort key of a rendered feature: 64 bits of the packed tile ID, 32 unused bits,
+/// 8 bits of the layer index, and 24 bits of the feature sort key.
+/// Sorting by this key groups features by tile, then by layer, then by their sort key.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct FeatureKey(u128);
+
+impl FeatureKey {
+    /// Sort keys outside of the 24 bit signed range are clamped.
+    pub fn new(tile: PackedTileID64, layer: u8, sort_key: i32) -> Self {
+        let sort_key = (sort_key.clamp(-SORT_KEY_BIAS, SORT_KEY_BIAS - 1) + SORT_KEY_BIAS) as u128;
+        Self((u128::from(tile.value()) << 64) | (u128::from(layer) << SORT_KEY_BITS) | sort_key)
+    }
+
+    pub fn tile(&self) -> PackedTileID64 {
+        PackedTileID64::from_value((self.0 >> 64) as u64)
+    }
+
+    pub fn layer(&self) -> u8 {
+        (self.0 >> SORT_KEY_BITS) as u8
+    }
+
+    pub fn sort_key(&self) -> i32 {
+        (self.0 & ((1 << SORT_KEY_BITS) - 1)) as i32 - SORT_KEY_BIAS
+    }
+}
+
+/// A rendered feature with its sort key. The data is opaque to the sorter,
+/// e.g. the encoded geometry and attributes of a feature in one tile.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SortableFeature {
+    pub key: FeatureKey,
+    pub data: Vec<u8>,
+}
+
+impl SortableFeature {
+    fn write(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        writer.write_all(&self.key.0.to_le_bytes())?;
+        writer.write_all(&(self.data.len() as u32).to_le_bytes())?;
+        writer.write_all(&self.data)
+    }
+
+    /// Returns None at the end of the stream
+    fn read(reader: &mut impl Read) -> std::io::Result<Option<Self>> {
+        let mut key = [0; 16];
+        match reader.read_exact(&mut key) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let mut len = [0; 4];
+        reader.read_exact(&mut len)?;
+        let mut data = vec![0; u32::from_le_bytes(len) as usize];
+        reader.read_exact(&mut data)?;
+        Ok(Some(Self {
+            key: FeatureKey(u128::from_le_bytes(key)),
+            data,
+        }))
+    }
+
+    /// Approximate memory used by this feature while buffered
+    fn mem_size(&self) -> usize {
+        size_of::<Self>() + self.data.len()
+    }
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct SorterStats {
+    pub features: usize,
+    pub bytes: usize,
+    pub chunks: usize,
+}
+
+impl AddAssign for SorterStats {
+    fn add_assign(&mut self, other: Self) {
+        self.features += other.features;
+        self.bytes += other.bytes;
+        self.chunks += other.chunks;
+    }
+}
+
+/// Sorts rendered features by [`FeatureKey`] without holding all of them in memory.
+/// Features are buffered until the chunk size is reached, then sorted and written
+/// to a temporary file. When finished, all chunks are merged into a single sorted stream.
+/// Features with the same key keep their insertion order.
+///
+/// The sorter is not thread safe: parallel renderers should send features to a single
+/// thread that owns the sorter, e.g. using a channel.
+pub struct FeatureSorter {
+    dir: PathBuf,
+    chunk_size: usize,
+    buffer: Vec<SortableFeature>,
+    buffer_size: usize,
+    chunks: Vec<PathBuf>,
+    stats: SorterStats,
+}
+
+impl FeatureSorter {
+    /// Create a sorter that spills chunks into the given directory, creating it if needed.
+    pub fn new(dir: &Path) -> Result<Self> {
+        create_dir_all(dir).with_context(|| format!("Unable to create {}", dir.display()))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            buffer: Vec::new(),
+            buffer_size: 0,
+            chunks: Vec::new(),
+            stats: SorterStats::default(),
+        })
+    }
+
+    /// Maximum number of bytes to buffer in memory before writing a sorted chunk.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    pub fn add(&mut self, feature: SortableFeature) -> Result<()> {
+        self.stats.features += 1;
+        self.stats.bytes += feature.data.len();
+        self.buffer_size += feature.mem_size();
+        self.buffer.push(feature);
+        if self.buffer_size >= self.chunk_size {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> &SorterStats {
+        &self.stats
+    }
+
+    /// Sort the current buffer and write it into a new chunk file
+    fn spill(&mut self) -> Result<()> {
+        self.buffer.par_sort_by_key(|f| f.key);
+        let path = self
+            .dir
+            .join(format!("features-{}.chunk", self.chunks.len()));
+        // Keep track of the file before writing it so that it is removed on errors too
+        self.chunks.push(path.clone());
+        let file = File::create(&path)
+            .with_context(|| format!("Unable to create chunk {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        for feature in self.buffer.drain(..) {
+            feature.write(&mut writer)?;
+        }
+        writer.flush()?;
+        self.buffer_size = 0;
+        self.stats.chunks += 1;
+        Ok(())
+    }
+
+    /// Finish adding features and return them as a sorted stream.
+    /// If everything fit into memory, no chunks are written at all.
+    pub fn finish(mut self) -> Result<SortedFeatures> {
+        let mut sources = Vec::new();
+        if self.chunks.is_empty() {
+            self.buffer.par_sort_by_key(|f| f.key);
+            let buffer = std::mem::take(&mut self.buffer);
+            sources.push(Source::Memory(buffer.into_iter()));
+        } else {
+            if !self.buffer.is_empty() {
+                self.spill()?;
+            }
+            for path in &self.chunks {
+                let file = File::open(path)
+                    .with_context(|| format!("Unable to open chunk {}", path.display()))?;
+                sources.push(Source::File(BufReader::new(file)));
+            }
+        }
+        let mut heap = BinaryHeap::new();
+        let mut pending = Vec::with_capacity(sources.len());
+        for (idx, source) in sources.iter_mut().enumerate() {
+            let feature = source.next()?;
+            if let Some(f) = &feature {
+                heap.push(Reverse((f.key, idx)));
+            }
+            pending.push(feature);
+        }
+        Ok(SortedFeatures {
+            sources,
+            pending,
+            heap,
+            chunks: std::mem::take(&mut self.chunks),
+        })
+    }
+}
+
+impl Drop for FeatureSorter {
+    fn drop(&mut self) {
+        for path in &self.chunks {
+            let _ = remove_file(path);
+        }
+    }
+}
+
+enum Source {
+    Memory(std::vec::IntoIter<SortableFeature>),
+    File(BufReader<File>),
+}
+
+impl Source {
+    fn next(&mut self) -> Result<Option<SortableFeature>> {
+        match self {
+            Source::Memory(iter) => Ok(iter.next()),
+            Source::File(reader) => {
+                Ok(SortableFeature::read(reader).context("Unable to read feature chunk")?)
+            }
+        }
+    }
+}
+
+/// K-way merge of sorted chunks. Chunk files are deleted when this is dropped.
+pub struct SortedFeatures {
+    sources: Vec<Source>,
+    /// The next feature of each source
+    pending: Vec<Option<SortableFeature>>,
+    /// Keys of the pending features. Ties are broken by the source index,
+    /// which keeps insertion order because chunks are written in order.
+    heap: BinaryHeap<Reverse<(FeatureKey, usize)>>,
+    chunks: Vec<PathBuf>,
+}
+
+impl SortedFeatures {
+    /// Group the sorted features by tile, e.g. to encode one tile at a time.
+    pub fn by_tile(self) -> TileFeatures {
+        TileFeatures {
+            features: self,
+            next: None,
+        }
+    }
+}
+
+impl Iterator for SortedFeatures {
+    type Item = Result<SortableFeature>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((_, idx)) = self.heap.pop()?;
+        let feature = self.pending[idx].take().unwrap();
+        match self.sources[idx].next() {
+            Ok(Some(next)) => {
+                self.heap.push(Reverse((next.key, idx)));
+                self.pending[idx] = Some(next);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                self.heap.clear();
+                return Some(Err(e));
+            }
+        }
+        Some(Ok(feature))
+    }
+}
+
+impl Drop for SortedFeatures {
+    fn drop(&mut self) {
+        // Close the files before removing them
+        self.sources.clear();
+        for path in &self.chunks {
+            let _ = remove_file(path);
+        }
+    }
+}
+
+/// All features of one tile, ordered by layer and sort key
+pub struct TileFeatures {
+    features: SortedFeatures,
+    next: Option<SortableFeature>,
+}
+
+impl Iterator for TileFeatures {
+    type Item = Result<(PackedTileID64, Vec<SortableFeature>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let first = match self.next.take() {
+            Some(f) => f,
+            None => match self.features.next()? {
+                Ok(f) => f,
+                Err(e) => return Some(Err(e)),
+            },
+        };
+        let tile = first.key.tile();
+        let mut result = vec![first];
+        for feature in &mut self.features {
+            match feature {
+                Ok(f) if f.key.tile() == tile => result.push(f),
+                Ok(f) => {
+                    self.next = Some(f);
+                    break;
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        Some(Ok((tile, result)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::tile_id::TileID;
+
+    fn feature(zoom: u8, x: u32, y: u32, layer: u8, sort_key: i32, data: u8) -> SortableFeature {
+        let tile = PackedTileID64::new(TileID::new(zoom, x, y));
+        SortableFeature {
+            key: FeatureKey::new(tile, layer, sort_key),
+            data: vec![data],
+        }
+    }
+
+    #[test]
+    fn test_key() {
+        let tile = PackedTileID64::new(TileID::new(14, 100, 200));
+        for sort_key in [-5, 0, 7, SORT_KEY_BIAS - 1, -SORT_KEY_BIAS] {
+            let key = FeatureKey::new(tile, 3, sort_key);
+            assert_eq!(key.tile(), tile);
+            assert_eq!(key.layer(), 3);
+            assert_eq!(key.sort_key(), sort_key);
+        }
+        assert_eq!(
+            FeatureKey::new(tile, 3, i32::MAX).sort_key(),
+            SORT_KEY_BIAS - 1
+        );
+        assert!(FeatureKey::new(tile, 0, 10) < FeatureKey::new(tile, 1, -10));
+        assert!(FeatureKey::new(tile, 0, -10) < FeatureKey::new(tile, 0, 10));
+        // Tiles above zoom 15 keep the zoom, then x, then y order
+        let detail = PackedTileID64::new(TileID::new(18, 70_000, 90_000));
+        assert_eq!(FeatureKey::new(detail, 3, 7).tile(), detail);
+        assert!(FeatureKey::new(tile, 255, 100) < FeatureKey::new(detail, 0, -100));
+    }
+
+    fn sort(chunk_size: usize) -> Vec<(PackedTileID64, Vec<u8>)> {
+        let dir = tempdir().unwrap();
+        let mut sorter = FeatureSorter::new(dir.path())
+            .unwrap()
+            .chunk_size(chunk_size);
+        let mut data = 0;
+        for x in (0..8).rev() {
+            for y in 0..8 {
+                for sort_key in [2, -1] {
+                    sorter.add(feature(3, x, y, 0, sort_key, data)).unwrap();
+                    data += 1;
+                }
+            }
+        }
+        // Same key as an existing feature, must stay after it
+        sorter.add(feature(3, 0, 0, 0, 2, 255)).unwrap();
+        let result = sorter
+            .finish()
+            .unwrap()
+            .by_tile()
+            .map(|v| {
+                let (tile, features) = v.unwrap();
+                (tile, features.into_iter().flat_map(|f| f.data).collect())
+            })
+            .collect();
+        assert_eq!(dir.path().read_dir().unwrap().count(), 0);
+        result
+    }
+
+    #[test]
+    fn test_sort() {
+        let in_memory = sort(DEFAULT_CHUNK_SIZE);
+        assert_eq!(in_memory.len(), 64);
+        assert_eq!(in_memory[0].0, PackedTileID64::new(TileID::new(3, 0, 0)));
+        assert_eq!(in_memory[0].1, vec![113, 112, 255]);
+        assert_eq!(in_memory[63].0, PackedTileID64::new(TileID::new(3, 7, 7)));
+        assert_eq!(in_memory[63].1, vec![15, 14]);
+        // Tiny chunks force a multi-way merge with the same result
+        assert_eq!(sort(100), in_memory);
+    }
+}
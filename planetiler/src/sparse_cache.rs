@@ -0,0 +1,197 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{ensure, Context, Result};
+
+use crate::node_cache::NodeLookup;
+use crate::varint::read_u64;
+
+/// First bytes of a sparse node cache file. The magic is followed by the node count,
+/// the sorted node IDs, and the packed node positions, all stored as little endian u64.
+pub const SPARSE_CACHE_MAGIC: &[u8; 8] = b"PTSPARS1";
+
+/// Pack latitude and longitude into a single value with 1e-7 degree precision, same as OSM.
+pub fn pack_lat_lon(lat: f64, lon: f64) -> u64 {
+    pack_e7((lat * 1e7).round() as i32, (lon * 1e7).round() as i32)
+}
+
+/// Reverse of [`pack_lat_lon`], returns (lat, lon).
+pub fn unpack_lat_lon(value: u64) -> (f64, f64) {
+    let (lat, lon) = unpack_e7(value);
+    (f64::from(lat) / 1e7, f64::from(lon) / 1e7)
+}
+
+/// Pack latitude and longitude given in 1e-7 degrees.
+pub fn pack_e7(lat: i32, lon: i32) -> u64 {
+    (lat as u32 as u64) | ((lon as u32 as u64) << 32)
+}
+
+/// Reverse of [`pack_e7`], returns (lat, lon) in 1e-7 degrees.
+pub fn unpack_e7(value: u64) -> (i32, i32) {
+    (value as u32 as i32, (value >> 32) as u32 as i32)
+}
+
+/// Node cache that only stores the nodes present in the PBF file, as a sorted ID array
+/// with packed positions next to it. Its size depends on the node count rather than on
+/// the maximum node ID, which makes it a much better fit for regional extracts.
+///
+/// The whole file is loaded into memory, and lookups use binary search.
+/// It is read-only, see [`NodeLookup`].
+#[derive(Clone, Debug)]
+pub struct SparseFileCache {
+    ids: Arc<Vec<u64>>,
+    values: Arc<Vec<u64>>,
+}
+
+/// Bytes read from the cache file at a time, converted straight into the ID and value arrays.
+const READ_CHUNK: usize = 1024 * 1024;
+
+impl SparseFileCache {
+    /// Write (node ID, packed position) pairs, which must be sorted by ID, to a sparse cache file.
+    /// The nodes are iterated twice, once for the IDs and once for the positions,
+    /// so they do not need to be collected first.
+    pub fn write(path: &Path, nodes: impl Iterator<Item = (u64, u64)> + Clone) -> Result<()> {
+        let file = File::create(path)
+            .with_context(|| format!("Unable to create sparse node cache {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(SPARSE_CACHE_MAGIC)?;
+        // The count is only known after writing the IDs
+        writer.write_all(&0_u64.to_le_bytes())?;
+        let mut count = 0_u64;
+        for (id, _) in nodes.clone() {
+            writer.write_all(&id.to_le_bytes())?;
+            count += 1;
+        }
+        for (_, value) in nodes {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        writer.seek(SeekFrom::Start(SPARSE_CACHE_MAGIC.len() as u64))?;
+        writer.write_all(&count.to_le_bytes())?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Check if the file starts with the sparse cache magic bytes.
+    pub fn is_sparse_file(path: &Path) -> Result<bool> {
+        let mut magic = [0_u8; 8];
+        let mut file = File::open(path)
+            .with_context(|| format!("Unable to open node cache {}", path.display()))?;
+        Ok(file.read_exact(&mut magic).is_ok() && &magic == SPARSE_CACHE_MAGIC)
+    }
+
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Unable to read sparse node cache {}", path.display()))?;
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let mut header = [0_u8; 16];
+        ensure!(
+            reader.read_exact(&mut header).is_ok() && header[..8] == SPARSE_CACHE_MAGIC[..],
+            "{} is not a sparse node cache",
+            path.display()
+        );
+        let count = read_u64(&header[8..]);
+        ensure!(
+            count.checked_mul(16).and_then(|size| size.checked_add(16)) == Some(file_len),
+            "Sparse node cache {} is truncated",
+            path.display()
+        );
+        let ids = read_u64s(&mut reader, count as usize)?;
+        ensure!(
+            ids.windows(2).all(|w| w[0] < w[1]),
+            "Node IDs of sparse node cache {} are not sorted",
+            path.display()
+        );
+        Ok(Self {
+            ids: Arc::new(ids),
+            values: Arc::new(read_u64s(&mut reader, count as usize)?),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Packed position of the node, or None if the node is not in the cache.
+    pub fn get(&self, id: u64) -> Option<u64> {
+        self.ids.binary_search(&id).ok().map(|idx| self.values[idx])
+    }
+}
+
+/// Read `count` little endian u64 values, without holding a copy of their bytes.
+fn read_u64s(reader: &mut impl Read, count: usize) -> Result<Vec<u64>> {
+    let mut values = Vec::with_capacity(count);
+    let mut buf = vec![0_u8; READ_CHUNK];
+    while values.len() < count {
+        let len = (count - values.len()).min(READ_CHUNK / 8) * 8;
+        reader.read_exact(&mut buf[..len])?;
+        values.extend(buf[..len].chunks_exact(8).map(read_u64));
+    }
+    Ok(values)
+}
+
+impl NodeLookup for SparseFileCache {
+    fn get_node(&self, id: i64) -> Option<(f64, f64)> {
+        let value = self.get(u64::try_from(id).ok()?)?;
+        Some(unpack_lat_lon(value))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_pack() {
+        for (lat, lon) in [(0.0, 0.0), (51.5, -0.1275), (-85.0511287, 179.9999999)] {
+            let (lat2, lon2) = unpack_lat_lon(pack_lat_lon(lat, lon));
+            assert!((lat - lat2).abs() < 1e-7, "{lat} {lat2}");
+            assert!((lon - lon2).abs() < 1e-7, "{lon} {lon2}");
+        }
+        assert_eq!(pack_lat_lon(0.0, 0.0), 0);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.cache");
+        let nodes = [
+            (3, pack_lat_lon(1.0, 2.0)),
+            (17, pack_lat_lon(-3.0, 4.5)),
+            (1_000_000_000, pack_lat_lon(60.0, -120.0)),
+        ];
+        SparseFileCache::write(&path, nodes.iter().copied()).unwrap();
+        assert!(SparseFileCache::is_sparse_file(&path).unwrap());
+
+        let cache = SparseFileCache::open(&path).unwrap();
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.get(17), Some(nodes[1].1));
+        assert_eq!(cache.get(4), None);
+
+        assert_eq!(cache.get_node(3), Some((1.0, 2.0)));
+        assert_eq!(cache.get_node(1_000_000_000), Some((60.0, -120.0)));
+        assert_eq!(cache.get_node(5), None);
+        assert_eq!(cache.get_node(-3), None);
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &data[..data.len() - 8]).unwrap();
+        assert!(SparseFileCache::open(&path).is_err());
+    }
+
+    #[test]
+    fn test_not_sparse() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.cache");
+        std::fs::write(&path, [0_u8; 64]).unwrap();
+        assert!(!SparseFileCache::is_sparse_file(&path).unwrap());
+        assert!(SparseFileCache::open(&path).is_err());
+    }
+}
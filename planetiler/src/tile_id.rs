@@ -0,0 +1,474 @@
+use std::f64::consts::PI;
+use std::ops::RangeInclusive;
+
+use anyhow::{bail, ensure, Result};
+
+use crate::projection::{project, WORLD_METERS};
+
+/// 32 bit encoding of the tile X,Y for zooms 0-15.
+/// First two bits: 11 = z15, 10 = z14, 00 = z0..13
+/// For z0..13, next 4 bits is the zoom value,
+/// followed by 2 13-bit values for x and y.
+///
+/// This table shows maximum possible value for each
+///  z00:  00:0000:·············:············· ( 0 bits for x,y)
+///  z01:  00:0001:············1:············1 ( 2 bits for x,y)
+///  z02:  00:0010:···········11:···········11 ( 4 bits for x,y)
+///  z03:  00:0011:··········111:··········111 ( 6 bits for x,y)
+///  z04:  00:0100:·········1111:·········1111 ( 8 bits for x,y)
+///  z05:  00:0101:········11111:········11111 (10 bits for x,y)
+///  z06:  00:0110:·······111111:·······111111 (12 bits for x,y)
+///  z07:  00:0111:······1111111:······1111111 (14 bits for x,y)
+///  z08:  00:1000:·····11111111:·····11111111 (16 bits for x,y)
+///  z09:  00:1001:····111111111:····111111111 (18 bits for x,y)
+///  z10:  00:1010:···1111111111:···1111111111 (20 bits for x,y)
+///  z11:  00:1011:··11111111111:··11111111111 (22 bits for x,y)
+///  z12:  00:1100:·111111111111:·111111111111 (24 bits for x,y)
+///  z13:  00:1101:1111111111111:1111111111111 (26 bits for x,y)
+///  z14:  10::·11111111111111:·11111111111111 (28 bits for x,y)
+///  z15:  11::111111111111111:111111111111111 (30 bits for x,y)
+/// Packed IDs sort by zoom, then by x, then by y.
+/// See [`PackedTileID64`] for zooms above 15.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct PackedTileID(u32);
+
+/// 64 bit encoding of the tile X,Y for zooms 0-24: 11 unused bits, 5 bits of zoom,
+/// followed by 2 24-bit values for x and y.
+/// Packed IDs sort by zoom, then by x, then by y, the same as [`PackedTileID`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct PackedTileID64(u64);
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TileID {
+    pub zoom: u8,
+    pub x: u32,
+    pub y: u32,
+}
+
+/// Largest zoom of [`PackedTileID`].
+pub const MAX_ZOOM: u8 = 15;
+
+/// Largest zoom of [`PackedTileID64`] and [`TileID`].
+pub const MAX_ZOOM_64: u8 = 24;
+
+impl TileID {
+    /// Tile of known valid coordinates, e.g. constants or the children of another tile.
+    ///
+    /// # Panics
+    ///
+    /// If the zoom is above [`MAX_ZOOM_64`] or x,y are outside of it,
+    /// use [`TileID::try_new`] for unchecked input.
+    #[track_caller]
+    pub fn new(zoom: u8, x: u32, y: u32) -> Self {
+        Self::try_new(zoom, x, y).unwrap()
+    }
+
+    pub fn try_new(zoom: u8, x: u32, y: u32) -> Result<Self> {
+        ensure!(zoom <= MAX_ZOOM_64, "Invalid zoom {zoom}");
+        let max = max_dimension(zoom);
+        ensure!(x < max && y < max, "Invalid tile x={x}, y={y}, max={max}");
+        Ok(Self { zoom, x, y })
+    }
+
+    /// Tile containing a WGS84 point at the zoom. Latitudes beyond the Web Mercator
+    /// limits are clamped, and points on the east or south edge of the world belong to
+    /// the last tile.
+    pub fn for_lon_lat(lon: f64, lat: f64, zoom: u8) -> Result<Self> {
+        ensure!(zoom <= MAX_ZOOM_64, "Invalid zoom {zoom}");
+        ensure!(
+            (-180.0..=180.0).contains(&lon) && lat.is_finite(),
+            "Invalid point {lon},{lat}"
+        );
+        let [x, y] = project(lon, lat);
+        let max = max_dimension(zoom);
+        let to_tile = |v: f64| ((v * max as f64) as u32).min(max - 1);
+        Ok(Self {
+            zoom,
+            x: to_tile(x),
+            y: to_tile(y),
+        })
+    }
+
+    /// min_lon, min_lat, max_lon, max_lat of the tile in WGS84 degrees.
+    pub fn bounds(&self) -> [f64; 4] {
+        let scale = max_dimension(self.zoom) as f64;
+        let lon = |x: u32| x as f64 / scale * 360.0 - 180.0;
+        let lat = |y: u32| {
+            (PI * (1.0 - 2.0 * y as f64 / scale))
+                .sinh()
+                .atan()
+                .to_degrees()
+        };
+        [lon(self.x), lat(self.y + 1), lon(self.x + 1), lat(self.y)]
+    }
+
+    /// min_x, min_y, max_x, max_y of the tile in Web Mercator (EPSG:3857) meters.
+    pub fn bounds_meters(&self) -> [f64; 4] {
+        let size = WORLD_METERS / max_dimension(self.zoom) as f64;
+        let half = WORLD_METERS / 2.0;
+        let (x, y) = (self.x as f64, self.y as f64);
+        [
+            x * size - half,
+            half - (y + 1.0) * size,
+            (x + 1.0) * size - half,
+            half - y * size,
+        ]
+    }
+
+    /// The tile one zoom lower containing this one, None at zoom 0.
+    pub fn parent(&self) -> Option<Self> {
+        match self.zoom {
+            0 => None,
+            zoom => Some(Self {
+                zoom: zoom - 1,
+                x: self.x / 2,
+                y: self.y / 2,
+            }),
+        }
+    }
+
+    /// The four tiles one zoom higher covering this one, None at [`MAX_ZOOM_64`].
+    pub fn children(&self) -> Option<[Self; 4]> {
+        if self.zoom >= MAX_ZOOM_64 {
+            return None;
+        }
+        let child = |dx, dy| Self {
+            zoom: self.zoom + 1,
+            x: self.x * 2 + dx,
+            y: self.y * 2 + dy,
+        };
+        Some([child(0, 0), child(1, 0), child(0, 1), child(1, 1)])
+    }
+
+    /// The other three children of the parent tile, none at zoom 0.
+    pub fn siblings(&self) -> Vec<Self> {
+        match self.parent().and_then(|p| p.children()) {
+            Some(children) => children.into_iter().filter(|t| t != self).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// All tiles of the zooms intersecting a min_lon, min_lat, max_lon, max_lat box,
+    /// ordered by zoom, then x, then y.
+    pub fn covering(
+        bbox: [f64; 4],
+        zooms: RangeInclusive<u8>,
+    ) -> Result<impl Iterator<Item = Self>> {
+        let [min_lon, min_lat, max_lon, max_lat] = bbox;
+        ensure!(
+            min_lon <= max_lon && min_lat <= max_lat,
+            "Invalid bounding box {min_lon},{min_lat},{max_lon},{max_lat}"
+        );
+        ensure!(*zooms.end() <= MAX_ZOOM_64, "Invalid zoom {}", zooms.end());
+        // Tiles containing the north-west and south-east corners, for each zoom
+        let corners = zooms
+            .map(|zoom| {
+                let nw = Self::for_lon_lat(min_lon, max_lat, zoom)?;
+                let se = Self::for_lon_lat(max_lon, min_lat, zoom)?;
+                Ok((nw, se))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(corners.into_iter().flat_map(|(nw, se)| {
+            (nw.x..=se.x).flat_map(move |x| {
+                (nw.y..=se.y).map(move |y| Self {
+                    zoom: nw.zoom,
+                    x,
+                    y,
+                })
+            })
+        }))
+    }
+
+    /// Bing Maps quadkey of the tile, one digit per zoom level, empty at zoom 0.
+    pub fn to_quadkey(&self) -> String {
+        (1..=self.zoom)
+            .rev()
+            .map(|bit| {
+                let mask = 1 << (bit - 1);
+                let digit = u8::from(self.x & mask != 0) + 2 * u8::from(self.y & mask != 0);
+                char::from(b'0' + digit)
+            })
+            .collect()
+    }
+
+    pub fn from_quadkey(quadkey: &str) -> Result<Self> {
+        ensure!(
+            quadkey.len() <= MAX_ZOOM_64 as usize,
+            "Quadkey {quadkey} is too long"
+        );
+        let (mut x, mut y) = (0, 0);
+        for digit in quadkey.bytes() {
+            let digit = match digit {
+                b'0'..=b'3' => u32::from(digit - b'0'),
+                _ => bail!("Invalid quadkey {quadkey}"),
+            };
+            x = x * 2 + (digit & 1);
+            y = y * 2 + (digit >> 1);
+        }
+        Self::try_new(quadkey.len() as u8, x, y)
+    }
+}
+
+impl PackedTileID {
+    /// # Panics
+    ///
+    /// If the zoom is above [`MAX_ZOOM`], use [`PackedTileID::try_new`] or
+    /// [`PackedTileID64`] for higher zooms.
+    #[track_caller]
+    pub fn new(id: TileID) -> Self {
+        Self::try_new(id).unwrap()
+    }
+
+    pub fn try_new(id: TileID) -> Result<Self> {
+        ensure!(
+            id.zoom <= MAX_ZOOM,
+            "Zoom {} does not fit a 32 bit tile ID",
+            id.zoom
+        );
+        let id = TileID::try_new(id.zoom, id.x, id.y)?;
+        Ok(match id.zoom {
+            0..=13 => Self(((id.zoom as u32) << 26) | (id.x << 13) | id.y),
+            14..=15 => Self((((id.zoom as u32) - 12) << 30) | (id.x << 15) | id.y),
+            _ => unreachable!(),
+        })
+    }
+
+    /// Create from a raw value previously returned by [`PackedTileID::value`].
+    pub fn from_value(value: u32) -> Self {
+        Self(value)
+    }
+
+    pub fn value(&self) -> u32 {
+        self.0
+    }
+
+    pub fn decode(&self) -> TileID {
+        let zoom = ((self.0 & 0b1111_1100_0000_0000_0000_0000_0000_0000) >> 26) as u8;
+        if zoom <= 13 {
+            TileID {
+                x: (self.0 & 0b0000_0011_1111_1111_1110_0000_0000_0000) >> 13,
+                y: (self.0 & 0b0000_0000_0000_0000_0001_1111_1111_1111),
+                zoom,
+            }
+        } else {
+            TileID {
+                x: (self.0 & 0b0011_1111_1111_1111_1000_0000_0000_0000) >> 15,
+                y: (self.0 & 0b0000_0000_0000_0000_0111_1111_1111_1111),
+                zoom: ((zoom & 0b110000) >> 4) + 12, // either 0b10 -> 14, or 0b11 -> 15
+            }
+        }
+    }
+}
+
+impl PackedTileID64 {
+    /// # Panics
+    ///
+    /// If the zoom is above [`MAX_ZOOM_64`] or x,y are outside of it,
+    /// use [`PackedTileID64::try_new`] for unchecked input.
+    #[track_caller]
+    pub fn new(id: TileID) -> Self {
+        Self::try_new(id).unwrap()
+    }
+
+    pub fn try_new(id: TileID) -> Result<Self> {
+        let id = TileID::try_new(id.zoom, id.x, id.y)?;
+        Ok(Self(
+            (u64::from(id.zoom) << 48) | (u64::from(id.x) << 24) | u64::from(id.y),
+        ))
+    }
+
+    /// Create from a raw value previously returned by [`PackedTileID64::value`].
+    pub fn from_value(value: u64) -> Self {
+        Self(value)
+    }
+
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+
+    pub fn decode(&self) -> TileID {
+        TileID {
+            zoom: (self.0 >> 48) as u8 & 0b1_1111,
+            x: (self.0 >> 24) as u32 & 0xFF_FFFF,
+            y: self.0 as u32 & 0xFF_FFFF,
+        }
+    }
+}
+
+impl From<PackedTileID> for PackedTileID64 {
+    fn from(id: PackedTileID) -> Self {
+        // Both use the same tile ranges, so this cannot fail
+        Self::new(id.decode())
+    }
+}
+
+impl TryFrom<PackedTileID64> for PackedTileID {
+    type Error = anyhow::Error;
+
+    fn try_from(id: PackedTileID64) -> Result<Self> {
+        Self::try_new(id.decode())
+    }
+}
+
+/// Number of tiles along each axis at the zoom.
+///
+/// # Panics
+///
+/// For zooms above [`MAX_ZOOM_64`], which no [`TileID`] can have.
+#[track_caller]
+pub fn max_dimension(zoom: u8) -> u32 {
+    // todo: perf test if this would be faster as a static lookup instead
+    if zoom <= MAX_ZOOM_64 {
+        1 << zoom
+    } else {
+        panic!("Invalid zoom {zoom}")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_one(zoom: u8, x: u32, y: u32) {
+        let id = TileID::new(zoom, x, y);
+        assert_eq!(PackedTileID::new(id).decode(), id);
+    }
+
+    fn test_range(zoom: u8, min: u32, max: u32) {
+        for x in min..max {
+            for y in min..max {
+                test_one(zoom, x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_packed() {
+        for z in 0..=MAX_ZOOM {
+            let max = max_dimension(z);
+            if z < 10 {
+                test_range(z, 0, max);
+            } else {
+                test_range(z, 0, 20);
+                test_range(z, max - 20, max);
+            }
+        }
+    }
+
+    #[test]
+    fn test_packed_64() {
+        for z in 0..=MAX_ZOOM_64 {
+            let max = max_dimension(z);
+            for (x, y) in [(0, 0), (max - 1, 0), (0, max - 1), (max - 1, max - 1)] {
+                let id = TileID::new(z, x, y);
+                let packed = PackedTileID64::new(id);
+                assert_eq!(PackedTileID64::from_value(packed.value()).decode(), id);
+                if z <= MAX_ZOOM {
+                    let packed32 = PackedTileID::new(id);
+                    assert_eq!(PackedTileID64::from(packed32), packed);
+                    assert_eq!(PackedTileID::try_from(packed).unwrap(), packed32);
+                } else {
+                    assert!(PackedTileID::try_from(packed).is_err());
+                }
+            }
+        }
+        assert!(TileID::try_new(MAX_ZOOM_64 + 1, 0, 0).is_err());
+        assert!(TileID::try_new(16, 1 << 16, 0).is_err());
+        assert!(PackedTileID64::try_new(TileID {
+            zoom: 2,
+            x: 4,
+            y: 0
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn test_lon_lat() {
+        let tile = |lon, lat, zoom| TileID::for_lon_lat(lon, lat, zoom).unwrap();
+        assert_eq!(tile(0.0, 0.0, 0), TileID::new(0, 0, 0));
+        assert_eq!(tile(0.0, 0.0, 1), TileID::new(1, 1, 1));
+        assert_eq!(tile(-180.0, 90.0, 3), TileID::new(3, 0, 0));
+        assert_eq!(tile(180.0, -90.0, 3), TileID::new(3, 7, 7));
+        assert!(TileID::for_lon_lat(200.0, 0.0, 3).is_err());
+        assert!(TileID::for_lon_lat(0.0, 0.0, MAX_ZOOM_64 + 1).is_err());
+
+        let id = TileID::new(14, 8800, 5373);
+        let [min_lon, min_lat, max_lon, max_lat] = id.bounds();
+        let center = tile((min_lon + max_lon) / 2.0, (min_lat + max_lat) / 2.0, 14);
+        assert_eq!(center, id);
+        let [min_x, min_y, max_x, max_y] = TileID::new(0, 0, 0).bounds_meters();
+        assert_eq!([min_x, max_x], [-WORLD_METERS / 2.0, WORLD_METERS / 2.0]);
+        assert_eq!([min_y, max_y], [-WORLD_METERS / 2.0, WORLD_METERS / 2.0]);
+        let [min_x, min_y, _, max_y] = TileID::new(1, 1, 0).bounds_meters();
+        assert_eq!([min_x, min_y], [0.0, 0.0]);
+        assert_eq!(max_y, WORLD_METERS / 2.0);
+        let [_, min_lat, _, max_lat] = TileID::new(0, 0, 0).bounds();
+        assert!((max_lat - 85.051_128_779_806_59).abs() < 1e-9 && min_lat == -max_lat);
+    }
+
+    #[test]
+    fn test_hierarchy() {
+        let id = TileID::new(5, 10, 21);
+        assert_eq!(id.parent(), Some(TileID::new(4, 5, 10)));
+        assert_eq!(TileID::new(0, 0, 0).parent(), None);
+        let children = id.children().unwrap();
+        assert!(children.iter().all(|c| c.parent() == Some(id)));
+        assert_eq!(children[3], TileID::new(6, 21, 43));
+        assert_eq!(TileID::new(MAX_ZOOM_64, 0, 0).children(), None);
+        let siblings = id.siblings();
+        assert_eq!(siblings.len(), 3);
+        assert!(!siblings.contains(&id) && siblings.iter().all(|s| s.parent() == id.parent()));
+        assert!(TileID::new(0, 0, 0).siblings().is_empty());
+    }
+
+    #[test]
+    fn test_covering() {
+        let tiles: Vec<_> = TileID::covering([-10.0, -10.0, 10.0, 10.0], 0..=2)
+            .unwrap()
+            .collect();
+        assert_eq!(tiles[0], TileID::new(0, 0, 0));
+        assert_eq!(tiles.len(), 1 + 4 + 4);
+        assert_eq!(tiles[5], TileID::new(2, 1, 1));
+        let world = TileID::covering([-180.0, -90.0, 180.0, 90.0], 3..=3).unwrap();
+        assert_eq!(world.count(), 64);
+        assert!(TileID::covering([10.0, 0.0, -10.0, 1.0], 0..=1).is_err());
+    }
+
+    #[test]
+    fn test_quadkey() {
+        assert_eq!(TileID::new(0, 0, 0).to_quadkey(), "");
+        assert_eq!(TileID::new(3, 3, 5).to_quadkey(), "213");
+        for id in [
+            TileID::new(0, 0, 0),
+            TileID::new(3, 3, 5),
+            TileID::new(17, 70_000, 1),
+        ] {
+            assert_eq!(TileID::from_quadkey(&id.to_quadkey()).unwrap(), id);
+        }
+        assert!(TileID::from_quadkey("0124").is_err());
+    }
+
+    #[test]
+    fn test_packed_order() {
+        // Same order as the 32 bit IDs: by zoom, then x, then y
+        let mut ids = Vec::new();
+        for z in 12..=MAX_ZOOM {
+            for (x, y) in [(0, 5), (3, 1), (3, 2), (max_dimension(z) - 1, 0)] {
+                ids.push(TileID::new(z, x, y));
+            }
+        }
+        let mut by_32: Vec<_> = ids.iter().map(|&id| PackedTileID::new(id)).collect();
+        let mut by_64: Vec<_> = ids.iter().map(|&id| PackedTileID64::new(id)).collect();
+        by_32.reverse();
+        by_32.sort();
+        by_64.reverse();
+        by_64.sort();
+        let by_32: Vec<_> = by_32.into_iter().map(|id| id.decode()).collect();
+        let by_64: Vec<_> = by_64.into_iter().map(|id| id.decode()).collect();
+        assert_eq!(by_32, ids);
+        assert_eq!(by_64, ids);
+        let max = max_dimension(MAX_ZOOM) - 1;
+        let last_32 = PackedTileID::new(TileID::new(MAX_ZOOM, max, max));
+        assert!(PackedTileID64::new(TileID::new(16, 0, 0)) > PackedTileID64::from(last_32));
+    }
+}
@@ -0,0 +1,302 @@
+use std::ops::Range;
+use std::path::Path;
+
+use anyhow::{bail, ensure, Error, Result};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::{Batch, Db, Transactional, Tree};
+
+use crate::profile::ElementKind;
+use crate::tile_id::{PackedTileID64, MAX_ZOOM_64};
+use crate::tile_order::{zoom_base, HilbertTileID};
+use crate::tile_set::TileSet;
+
+/// An OSM element, identified by its kind and ID.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct ElementId {
+    pub kind: ElementKind,
+    pub id: i64,
+}
+
+const ELEMENT_KEY_SIZE: usize = 9;
+
+impl ElementId {
+    pub fn new(kind: ElementKind, id: i64) -> Self {
+        Self { kind, id }
+    }
+
+    /// Kind byte followed by the big-endian ID.
+    fn to_key(self) -> [u8; ELEMENT_KEY_SIZE] {
+        let mut key = [0; ELEMENT_KEY_SIZE];
+        key[0] = match self.kind {
+            ElementKind::Node => 0,
+            ElementKind::Way => 1,
+            ElementKind::Relation => 2,
+        };
+        key[1..].copy_from_slice(&self.id.to_be_bytes());
+        key
+    }
+
+    fn from_key(key: &[u8]) -> Result<Self> {
+        ensure!(key.len() == ELEMENT_KEY_SIZE, "Invalid element key {key:?}");
+        let kind = match key[0] {
+            0 => ElementKind::Node,
+            1 => ElementKind::Way,
+            2 => ElementKind::Relation,
+            v => bail!("Invalid element kind {v}"),
+        };
+        Ok(Self::new(kind, i64::from_be_bytes(key[1..].try_into()?)))
+    }
+}
+
+/// Tiles of a single zoom, made of all descendants at that zoom of one tile `level`
+/// zooms above, stored as the Hilbert index of that tile within its own zoom.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct TileBlock {
+    zoom: u8,
+    level: u8,
+    index: u64,
+}
+
+const BLOCK_KEY_SIZE: usize = 10;
+
+impl TileBlock {
+    /// The single tile block containing the tile at the given level.
+    fn of_tile(tile: HilbertTileID, zoom: u8, level: u8) -> Self {
+        let index = (tile.value() - zoom_base(zoom)) >> (u32::from(level) * 2);
+        Self { zoom, level, index }
+    }
+
+    /// Split the Hilbert ranges of a tile set into the largest aligned blocks.
+    /// Each range becomes at most a few blocks per zoom and per level.
+    fn split(ranges: &[Range<u64>]) -> Vec<TileBlock> {
+        let mut blocks = Vec::new();
+        for range in ranges {
+            for zoom in 0..=MAX_ZOOM_64 {
+                let base = zoom_base(zoom);
+                let (mut start, end) = (range.start.max(base), range.end.min(zoom_base(zoom + 1)));
+                while start < end {
+                    let index = start - base;
+                    let mut level = 0;
+                    while level < zoom {
+                        let size = 1 << ((u32::from(level) + 1) * 2);
+                        if index & (size - 1) != 0 || start + size > end {
+                            break;
+                        }
+                        level += 1;
+                    }
+                    let shift = u32::from(level) * 2;
+                    blocks.push(TileBlock {
+                        zoom,
+                        level,
+                        index: index >> shift,
+                    });
+                    start += 1 << shift;
+                }
+            }
+        }
+        blocks
+    }
+
+    fn to_key(self) -> [u8; BLOCK_KEY_SIZE] {
+        let mut key = [0; BLOCK_KEY_SIZE];
+        key[0] = self.zoom;
+        key[1] = self.level;
+        key[2..].copy_from_slice(&self.index.to_be_bytes());
+        key
+    }
+
+    fn element_key(self, element: ElementId) -> Vec<u8> {
+        [&self.to_key()[..], &element.to_key()].concat()
+    }
+}
+
+/// Persistent index of the tiles each OSM element was rendered into, and of the elements
+/// rendered into each tile, so that changed elements only require re-rendering their tiles.
+/// Elements map to their encoded [`TileSet`]. The reverse mapping stores one key per
+/// aligned Hilbert block of the element's tiles, so a polygon covering millions of tiles
+/// only needs a few keys per zoom.
+#[derive(Clone)]
+pub struct TileIndex {
+    db: Db,
+    elements: Tree,
+    tiles: Tree,
+}
+
+impl TileIndex {
+    /// Open or create the index database in the given directory.
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::from_db(sled::open(path)?)
+    }
+
+    /// Use an already configured database, e.g. with a larger cache or another flush interval.
+    /// Compression also requires the `compression` feature of `sled`.
+    pub fn from_db(db: Db) -> Result<Self> {
+        Ok(Self {
+            elements: db.open_tree("elements")?,
+            tiles: db.open_tree("tiles")?,
+            db,
+        })
+    }
+
+    /// Number of indexed elements.
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    /// Record the tiles an element was rendered into, replacing any tiles recorded
+    /// for it before. An empty tile set removes the element. Both mappings are updated
+    /// in one transaction, so they stay consistent if the process stops in between.
+    pub fn insert(&self, element: ElementId, tiles: &TileSet) -> Result<()> {
+        let key = &element.to_key()[..];
+        let encoded = tiles.encode();
+        let new_blocks = TileBlock::split(tiles.ranges());
+        let result = (&self.elements, &self.tiles).transaction(|(elements, tile_tree)| {
+            let old = if tiles.is_empty() {
+                elements.remove(key)?
+            } else {
+                elements.insert(key, encoded.as_slice())?
+            };
+            let mut batch = Batch::default();
+            if let Some(old) = old {
+                let old = TileSet::decode(&old).map_err(ConflictableTransactionError::Abort)?;
+                for block in TileBlock::split(old.ranges()) {
+                    batch.remove(block.element_key(element));
+                }
+            }
+            // Batch keeps the last operation for each key, so unchanged blocks are kept
+            for block in &new_blocks {
+                batch.insert(block.element_key(element), Vec::new());
+            }
+            tile_tree.apply_batch(&batch)?;
+            Ok(())
+        });
+        match result {
+            Ok(()) => Ok(()),
+            Err(TransactionError::Abort(err)) => Err(err),
+            Err(TransactionError::Storage(err)) => Err(Error::from(err)),
+        }
+    }
+
+    /// Remove an element from the index, returning its tiles.
+    pub fn remove(&self, element: ElementId) -> Result<Option<TileSet>> {
+        let tiles = self.get(element)?;
+        if tiles.is_some() {
+            self.insert(element, &TileSet::new())?;
+        }
+        Ok(tiles)
+    }
+
+    /// Tiles the element was rendered into, if it is in the index.
+    pub fn get(&self, element: ElementId) -> Result<Option<TileSet>> {
+        match self.elements.get(element.to_key())? {
+            Some(data) => Ok(Some(TileSet::decode(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Elements rendered into the tile.
+    pub fn elements(&self, tile: PackedTileID64) -> Result<Vec<ElementId>> {
+        let zoom = tile.decode().zoom;
+        let id = HilbertTileID::from(tile.decode());
+        let mut elements = Vec::new();
+        for level in 0..=zoom {
+            let prefix = TileBlock::of_tile(id, zoom, level).to_key();
+            for entry in self.tiles.scan_prefix(prefix) {
+                let (key, _) = entry?;
+                elements.push(ElementId::from_key(&key[BLOCK_KEY_SIZE..])?);
+            }
+        }
+        Ok(elements)
+    }
+
+    /// All tiles that need re-rendering when the given elements change, based on the tiles
+    /// they were rendered into before. Elements not in the index are ignored.
+    pub fn affected_tiles(&self, elements: impl IntoIterator<Item = ElementId>) -> Result<TileSet> {
+        let mut result = TileSet::new();
+        for element in elements {
+            if let Some(tiles) = self.get(element)? {
+                result = result.union(&tiles);
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tile_id::TileID;
+
+    fn tile(zoom: u8, x: u32, y: u32) -> PackedTileID64 {
+        PackedTileID64::new(TileID::new(zoom, x, y))
+    }
+
+    #[test]
+    fn test_split() {
+        let mut set = TileSet::new();
+        set.insert_descendants(tile(0, 0, 0), MAX_ZOOM_64).unwrap();
+        let blocks = TileBlock::split(set.ranges());
+        // One block with all tiles of each zoom
+        assert_eq!(blocks.len(), usize::from(MAX_ZOOM_64) + 1);
+        assert!(blocks.iter().all(|b| b.level == b.zoom && b.index == 0));
+
+        let set: TileSet = (0..100).map(|x| tile(12, x, 7)).collect();
+        let blocks = TileBlock::split(set.ranges());
+        let count: u64 = blocks.iter().map(|b| 1 << (u32::from(b.level) * 2)).sum();
+        assert_eq!(count, 100);
+        for t in set.iter() {
+            let id = HilbertTileID::from(t.decode());
+            let containing = (0..=12)
+                .filter(|&level| blocks.contains(&TileBlock::of_tile(id, 12, level)))
+                .count();
+            assert_eq!(containing, 1);
+        }
+    }
+
+    #[test]
+    fn test_index() {
+        let index =
+            TileIndex::from_db(sled::Config::new().temporary(true).open().unwrap()).unwrap();
+        let way = ElementId::new(ElementKind::Way, 10);
+        let node = ElementId::new(ElementKind::Node, 10);
+        let mut ocean = TileSet::new();
+        ocean
+            .insert_descendants(tile(2, 1, 1), MAX_ZOOM_64)
+            .unwrap();
+        index.insert(way, &ocean).unwrap();
+        let point: TileSet = [tile(14, 4100, 4100), tile(13, 2050, 2050)]
+            .into_iter()
+            .collect();
+        index.insert(node, &point).unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.get(way).unwrap(), Some(ocean.clone()));
+
+        assert_eq!(index.elements(tile(2, 1, 1)).unwrap(), [way]);
+        let mut both = index.elements(tile(14, 4100, 4100)).unwrap();
+        both.sort_by_key(|e| e.kind == ElementKind::Way);
+        assert_eq!(both, [node, way]);
+        assert!(index.elements(tile(14, 0, 0)).unwrap().is_empty());
+        let affected = index.affected_tiles([way, node]).unwrap();
+        assert_eq!(affected, ocean.union(&point));
+
+        // Re-rendering a changed element replaces its tiles
+        let moved: TileSet = [tile(14, 0, 0)].into_iter().collect();
+        index.insert(node, &moved).unwrap();
+        assert_eq!(index.elements(tile(14, 4100, 4100)).unwrap(), [way]);
+        assert_eq!(index.elements(tile(14, 0, 0)).unwrap(), [node]);
+
+        assert_eq!(index.remove(way).unwrap(), Some(ocean));
+        assert_eq!(index.remove(way).unwrap(), None);
+        assert!(index.elements(tile(2, 1, 1)).unwrap().is_empty());
+        assert_eq!(index.len(), 1);
+    }
+}
@@ -0,0 +1,261 @@
+use anyhow::{ensure, Result};
+
+use crate::tile_id::{max_dimension, PackedTileID64, TileID, MAX_ZOOM_64};
+
+/// Position of a tile on the zoom-ordered Hilbert curve used by PMTiles.
+/// All tiles of zoom N come after all tiles of zooms 0..N, and within a zoom,
+/// consecutive IDs are always adjacent tiles.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct HilbertTileID(u64);
+
+/// Position of a tile on the zoom-ordered Z-order (Morton) curve: 5 bits of zoom
+/// followed by the interleaved bits of y and x. Within a zoom, tiles sort the same
+/// as their quadkeys, so the descendants of any tile are contiguous.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct MortonTileID(u64);
+
+/// Number of tiles of all zooms before this one.
+pub(crate) fn zoom_base(zoom: u8) -> u64 {
+    ((1_u64 << (u32::from(zoom) * 2)) - 1) / 3
+}
+
+impl HilbertTileID {
+    /// Create from a raw value previously returned by [`HilbertTileID::value`].
+    pub fn from_value(value: u64) -> Result<Self> {
+        ensure!(
+            value < zoom_base(MAX_ZOOM_64 + 1),
+            "Hilbert tile ID {value} is beyond zoom {MAX_ZOOM_64}"
+        );
+        Ok(Self(value))
+    }
+
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+
+    pub fn decode(&self) -> TileID {
+        let zoom = (0..=MAX_ZOOM_64)
+            .take_while(|&zoom| zoom_base(zoom) <= self.0)
+            .last()
+            .unwrap();
+        let n = u64::from(max_dimension(zoom));
+        let mut t = self.0 - zoom_base(zoom);
+        let (mut x, mut y) = (0, 0);
+        let mut s = 1;
+        while s < n {
+            let rx = 1 & (t / 2);
+            let ry = 1 & (t ^ rx);
+            hilbert_rotate(s, &mut x, &mut y, rx, ry);
+            x += s * rx;
+            y += s * ry;
+            t /= 4;
+            s *= 2;
+        }
+        TileID::new(zoom, x as u32, y as u32)
+    }
+}
+
+impl From<TileID> for HilbertTileID {
+    fn from(tile: TileID) -> Self {
+        let n = u64::from(max_dimension(tile.zoom));
+        let (mut x, mut y) = (u64::from(tile.x), u64::from(tile.y));
+        let mut d = 0;
+        let mut s = n / 2;
+        while s > 0 {
+            let rx = ((x & s) > 0) as u64;
+            let ry = ((y & s) > 0) as u64;
+            d += s * s * ((3 * rx) ^ ry);
+            hilbert_rotate(n, &mut x, &mut y, rx, ry);
+            s /= 2;
+        }
+        Self(zoom_base(tile.zoom) + d)
+    }
+}
+
+impl From<HilbertTileID> for TileID {
+    fn from(id: HilbertTileID) -> Self {
+        id.decode()
+    }
+}
+
+fn hilbert_rotate(n: u64, x: &mut u64, y: &mut u64, rx: u64, ry: u64) {
+    if ry == 0 {
+        if rx == 1 {
+            *x = n - 1 - *x;
+            *y = n - 1 - *y;
+        }
+        std::mem::swap(x, y);
+    }
+}
+
+impl MortonTileID {
+    /// Create from a raw value previously returned by [`MortonTileID::value`].
+    pub fn from_value(value: u64) -> Result<Self> {
+        let zoom = (value >> 48) as u8;
+        ensure!(
+            zoom <= MAX_ZOOM_64 && value & ((1 << 48) - 1) < 1 << (u32::from(zoom) * 2),
+            "Invalid Morton tile ID {value}"
+        );
+        Ok(Self(value))
+    }
+
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+
+    pub fn decode(&self) -> TileID {
+        TileID::new(
+            (self.0 >> 48) as u8,
+            compact_bits(self.0),
+            compact_bits(self.0 >> 1),
+        )
+    }
+}
+
+impl From<TileID> for MortonTileID {
+    fn from(tile: TileID) -> Self {
+        let xy = spread_bits(tile.x) | (spread_bits(tile.y) << 1);
+        Self((u64::from(tile.zoom) << 48) | xy)
+    }
+}
+
+impl From<MortonTileID> for TileID {
+    fn from(id: MortonTileID) -> Self {
+        id.decode()
+    }
+}
+
+/// Move the lower 24 bits of the value to the even bits of the result.
+fn spread_bits(value: u32) -> u64 {
+    let mut v = u64::from(value) & 0xFF_FFFF;
+    v = (v | (v << 16)) & 0x0000_FFFF_0000_FFFF;
+    v = (v | (v << 8)) & 0x00FF_00FF_00FF_00FF;
+    v = (v | (v << 4)) & 0x0F0F_0F0F_0F0F_0F0F;
+    v = (v | (v << 2)) & 0x3333_3333_3333_3333;
+    v = (v | (v << 1)) & 0x5555_5555_5555_5555;
+    v
+}
+
+/// Inverse of [`spread_bits`], ignoring the odd bits of the value.
+fn compact_bits(value: u64) -> u32 {
+    let mut v = value & 0x5555_5555_5555_5555;
+    v = (v | (v >> 1)) & 0x3333_3333_3333_3333;
+    v = (v | (v >> 2)) & 0x0F0F_0F0F_0F0F_0F0F;
+    v = (v | (v >> 4)) & 0x00FF_00FF_00FF_00FF;
+    v = (v | (v >> 8)) & 0x0000_FFFF_0000_FFFF;
+    v = (v | (v >> 16)) & 0x0000_0000_FFFF_FFFF;
+    (v & 0xFF_FFFF) as u32
+}
+
+/// Ordering of tiles encoded as u64 keys, e.g. for the sort order of features,
+/// database keys, or archive layouts. All orderings are zoom-major.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TileOrder {
+    /// By zoom, then x, then y, see [`PackedTileID64`]
+    Zoom,
+    /// Along the Hilbert curve, see [`HilbertTileID`]
+    Hilbert,
+    /// Along the Z-order curve, see [`MortonTileID`]
+    Morton,
+}
+
+impl TileOrder {
+    pub fn encode(self, tile: TileID) -> u64 {
+        match self {
+            TileOrder::Zoom => PackedTileID64::new(tile).value(),
+            TileOrder::Hilbert => HilbertTileID::from(tile).value(),
+            TileOrder::Morton => MortonTileID::from(tile).value(),
+        }
+    }
+
+    /// Inverse of [`TileOrder::encode`], failing for values that are not valid tiles.
+    pub fn decode(self, value: u64) -> Result<TileID> {
+        Ok(match self {
+            TileOrder::Zoom => {
+                let tile = PackedTileID64::from_value(value).decode();
+                ensure!(
+                    PackedTileID64::try_new(tile)?.value() == value,
+                    "Invalid tile ID {value}"
+                );
+                tile
+            }
+            TileOrder::Hilbert => HilbertTileID::from_value(value)?.decode(),
+            TileOrder::Morton => MortonTileID::from_value(value)?.decode(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn all_tiles(zoom: u8) -> impl Iterator<Item = TileID> {
+        let max = max_dimension(zoom);
+        (0..max).flat_map(move |x| (0..max).map(move |y| TileID::new(zoom, x, y)))
+    }
+
+    #[test]
+    fn test_hilbert() {
+        let hilbert = |zoom, x, y| HilbertTileID::from(TileID::new(zoom, x, y)).value();
+        assert_eq!(hilbert(0, 0, 0), 0);
+        assert_eq!(hilbert(1, 0, 0), 1);
+        assert_eq!(hilbert(1, 0, 1), 2);
+        assert_eq!(hilbert(1, 1, 1), 3);
+        assert_eq!(hilbert(1, 1, 0), 4);
+        assert_eq!(hilbert(2, 0, 0), 5);
+        for zoom in 0..=8 {
+            for tile in all_tiles(zoom) {
+                assert_eq!(HilbertTileID::from(tile).decode(), tile);
+            }
+        }
+        let max = max_dimension(MAX_ZOOM_64) - 1;
+        let last = TileID::new(MAX_ZOOM_64, max, 0);
+        let id = HilbertTileID::from(last);
+        assert_eq!(id.value(), zoom_base(MAX_ZOOM_64 + 1) - 1);
+        assert_eq!(
+            HilbertTileID::from_value(id.value()).unwrap().decode(),
+            last
+        );
+        assert!(HilbertTileID::from_value(id.value() + 1).is_err());
+    }
+
+    #[test]
+    fn test_morton() {
+        let mut sorted: Vec<_> = all_tiles(2).collect();
+        sorted.sort_by_key(|&t| MortonTileID::from(t));
+        // Z-order is the quadkey order
+        assert!(sorted
+            .windows(2)
+            .all(|w| w[0].to_quadkey() < w[1].to_quadkey()));
+        for zoom in [0, 1, 7, 15, MAX_ZOOM_64] {
+            let max = max_dimension(zoom) - 1;
+            for tile in [TileID::new(zoom, max, 0), TileID::new(zoom, 0, max)] {
+                assert_eq!(MortonTileID::from(tile).decode(), tile);
+            }
+        }
+        assert!(MortonTileID::from_value(1 << 48 | 4).is_err());
+        assert!(MortonTileID::from_value(25 << 48).is_err());
+    }
+
+    #[test]
+    fn test_tile_order() {
+        for order in [TileOrder::Zoom, TileOrder::Hilbert, TileOrder::Morton] {
+            let mut values: Vec<_> = (0..=4)
+                .flat_map(all_tiles)
+                .map(|tile| {
+                    let value = order.encode(tile);
+                    assert_eq!(order.decode(value).unwrap(), tile);
+                    value
+                })
+                .collect();
+            // Zoom-major in every order
+            values.sort_unstable();
+            let zooms: Vec<_> = values
+                .iter()
+                .map(|&v| order.decode(v).unwrap().zoom)
+                .collect();
+            assert!(zooms.windows(2).all(|w| w[0] <= w[1]), "{order:?}");
+        }
+        assert!(TileOrder::Zoom.decode(3 << 48 | 8 << 24).is_err());
+    }
+}
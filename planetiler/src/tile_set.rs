@@ -0,0 +1,318 @@
+use std::cmp::Ordering;
+use std::ops::{Range, RangeInclusive};
+
+use anyhow::{ensure, Result};
+
+use crate::tile_id::{PackedTileID64, TileID, MAX_ZOOM_64};
+use crate::tile_order::{zoom_base, HilbertTileID};
+use crate::varint::{read_varint, write_varint};
+
+/// A set of tiles of zooms 0..=[`MAX_ZOOM_64`], e.g. all tiles a feature touches.
+/// Tiles are stored as sorted, non-overlapping ranges of [`HilbertTileID`] values.
+/// The descendants of a tile at any zoom form a single range, so large polygons
+/// like oceans or countries take a few ranges per zoom instead of millions of tiles.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TileSet {
+    /// Sorted and merged, so that no two ranges overlap or touch
+    ranges: Vec<Range<u64>>,
+}
+
+impl TileSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn from_sorted_ranges(ranges: impl IntoIterator<Item = Range<u64>>) -> Self {
+        let mut set = Self::new();
+        for range in ranges {
+            set.push_range(range);
+        }
+        set
+    }
+
+    /// Append a range that does not start before the last one, merging them if they touch.
+    fn push_range(&mut self, range: Range<u64>) {
+        if range.is_empty() {
+            return;
+        }
+        match self.ranges.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => self.ranges.push(range),
+        }
+    }
+
+    /// Number of tiles in the set.
+    pub fn len(&self) -> u64 {
+        self.ranges.iter().map(|r| r.end - r.start).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Number of Hilbert ranges the set is stored as.
+    pub fn range_count(&self) -> usize {
+        self.ranges.len()
+    }
+
+    /// Sorted ranges of [`HilbertTileID`] values the set is stored as.
+    pub fn ranges(&self) -> &[Range<u64>] {
+        &self.ranges
+    }
+
+    pub fn insert(&mut self, tile: PackedTileID64) {
+        let id = HilbertTileID::from(tile.decode()).value();
+        self.insert_range(id..id + 1);
+    }
+
+    /// Add the tile and all of its descendants up to `max_zoom`, e.g. for a polygon
+    /// that covers the whole tile.
+    pub fn insert_descendants(&mut self, tile: PackedTileID64, max_zoom: u8) -> Result<()> {
+        self.insert_descendants_at(tile, tile.decode().zoom..=max_zoom)
+    }
+
+    /// Add the descendants of the tile at the given zooms only, e.g. for a
+    /// [`crate::tiler::FillArea`], whose tiles start below the filled tile.
+    pub fn insert_descendants_at(
+        &mut self,
+        tile: PackedTileID64,
+        zooms: RangeInclusive<u8>,
+    ) -> Result<()> {
+        let tile = tile.decode();
+        let (min_zoom, max_zoom) = zooms.into_inner();
+        ensure!(max_zoom <= MAX_ZOOM_64, "Invalid zoom {max_zoom}");
+        ensure!(
+            min_zoom >= tile.zoom,
+            "Zoom {min_zoom} is above the tile of zoom {}",
+            tile.zoom
+        );
+        let index = HilbertTileID::from(tile).value() - zoom_base(tile.zoom);
+        for zoom in min_zoom..=max_zoom {
+            // Children of the Hilbert tile with index i have the indexes 4*i..4*i+4
+            let shift = u32::from(zoom - tile.zoom) * 2;
+            let start = zoom_base(zoom) + (index << shift);
+            self.insert_range(start..start + (1 << shift));
+        }
+        Ok(())
+    }
+
+    fn insert_range(&mut self, range: Range<u64>) {
+        // First range that ends at or after the start of the new one, and may be merged
+        let first = self.ranges.partition_point(|r| r.end < range.start);
+        // First range that starts after the end of the new one, and is kept as is
+        let last = self.ranges.partition_point(|r| r.start <= range.end);
+        let merged = match self.ranges[first..last] {
+            [] => range,
+            ref overlapping => {
+                overlapping[0].start.min(range.start)
+                    ..overlapping[overlapping.len() - 1].end.max(range.end)
+            }
+        };
+        self.ranges.splice(first..last, [merged]);
+    }
+
+    pub fn contains(&self, tile: PackedTileID64) -> bool {
+        let id = HilbertTileID::from(tile.decode()).value();
+        let idx = self.ranges.partition_point(|r| r.end <= id);
+        idx < self.ranges.len() && self.ranges[idx].start <= id
+    }
+
+    /// Tiles that are in either of the sets.
+    pub fn union(&self, other: &TileSet) -> TileSet {
+        let (mut a, mut b) = (
+            self.ranges.iter().peekable(),
+            other.ranges.iter().peekable(),
+        );
+        let mut result = TileSet::new();
+        loop {
+            let next = match (a.peek(), b.peek()) {
+                (Some(ra), Some(rb)) if ra.start <= rb.start => a.next(),
+                (Some(_), Some(_)) => b.next(),
+                (Some(_), None) => a.next(),
+                (None, _) => b.next(),
+            };
+            match next {
+                Some(range) => result.push_range(range.clone()),
+                None => return result,
+            }
+        }
+    }
+
+    /// Tiles that are in both sets.
+    pub fn intersection(&self, other: &TileSet) -> TileSet {
+        let mut result = TileSet::new();
+        let (mut i, mut j) = (0, 0);
+        while i < self.ranges.len() && j < other.ranges.len() {
+            let (a, b) = (&self.ranges[i], &other.ranges[j]);
+            result.push_range(a.start.max(b.start)..a.end.min(b.end));
+            match a.end.cmp(&b.end) {
+                Ordering::Less => i += 1,
+                Ordering::Greater => j += 1,
+                Ordering::Equal => {
+                    i += 1;
+                    j += 1;
+                }
+            }
+        }
+        result
+    }
+
+    /// All tiles of the set, ordered by their Hilbert IDs.
+    pub fn iter(&self) -> impl Iterator<Item = PackedTileID64> + '_ {
+        self.ranges.iter().flat_map(|r| r.clone()).map(|id| {
+            // Ranges never go beyond MAX_ZOOM_64, see insert_descendants and decode
+            let tile: TileID = HilbertTileID::from_value(id).unwrap().into();
+            PackedTileID64::new(tile)
+        })
+    }
+
+    /// Encode as the range count, followed by the varint distance of each range
+    /// from the end of the previous one, and the varint length of the range.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_varint(&mut buf, self.ranges.len() as u64);
+        let mut last_end = 0;
+        for range in &self.ranges {
+            write_varint(&mut buf, range.start - last_end);
+            write_varint(&mut buf, range.end - range.start);
+            last_end = range.end;
+        }
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        let mut pos = 0;
+        let count = read_varint(data, &mut pos)? as usize;
+        // Each range takes at least two bytes
+        ensure!(count <= data.len() / 2, "Tile set is truncated");
+        let max_id = zoom_base(MAX_ZOOM_64 + 1);
+        let mut ranges = Vec::with_capacity(count);
+        let mut last_end = 0_u64;
+        for idx in 0..count {
+            let start = last_end.checked_add(read_varint(data, &mut pos)?);
+            let end = start.and_then(|s| s.checked_add(read_varint(data, &mut pos).ok()?));
+            let (start, end) = match (start, end) {
+                (Some(start), Some(end)) if end <= max_id => (start, end),
+                _ => anyhow::bail!("Range {idx} of the tile set is out of bounds"),
+            };
+            ensure!(
+                start < end && (idx == 0 || start > last_end),
+                "Range {idx} of the tile set is empty or touches the previous one"
+            );
+            ranges.push(start..end);
+            last_end = end;
+        }
+        ensure!(
+            pos == data.len(),
+            "Unexpected data at the end of a tile set"
+        );
+        Ok(Self { ranges })
+    }
+}
+
+impl FromIterator<PackedTileID64> for TileSet {
+    fn from_iter<T: IntoIterator<Item = PackedTileID64>>(iter: T) -> Self {
+        let mut ids: Vec<u64> = iter
+            .into_iter()
+            .map(|tile| HilbertTileID::from(tile.decode()).value())
+            .collect();
+        ids.sort_unstable();
+        Self::from_sorted_ranges(ids.into_iter().map(|id| id..id + 1))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tile(zoom: u8, x: u32, y: u32) -> PackedTileID64 {
+        PackedTileID64::new(TileID::new(zoom, x, y))
+    }
+
+    #[test]
+    fn test_insert() {
+        let mut set = TileSet::new();
+        assert!(set.is_empty());
+        // Hilbert IDs 1, 2, 3, and 4 are the tiles of zoom 1
+        set.insert(tile(1, 1, 1));
+        set.insert(tile(1, 0, 0));
+        assert_eq!(set.range_count(), 2);
+        set.insert(tile(1, 0, 1));
+        assert_eq!(set.range_count(), 1);
+        set.insert(tile(1, 0, 1));
+        assert_eq!(set.len(), 3);
+        assert!(set.contains(tile(1, 0, 1)));
+        assert!(!set.contains(tile(1, 1, 0)));
+        assert!(!set.contains(tile(0, 0, 0)));
+        let from_iter: TileSet = [tile(1, 0, 1), tile(1, 1, 1), tile(1, 0, 0), tile(1, 0, 0)]
+            .into_iter()
+            .collect();
+        assert_eq!(from_iter, set);
+        let mut tiles: Vec<_> = set.iter().collect();
+        tiles.sort();
+        assert_eq!(tiles, [tile(1, 0, 0), tile(1, 0, 1), tile(1, 1, 1)]);
+    }
+
+    #[test]
+    fn test_descendants() {
+        let mut set = TileSet::new();
+        set.insert_descendants(tile(0, 0, 0), MAX_ZOOM_64).unwrap();
+        // The whole pyramid is a single range
+        assert_eq!(set.range_count(), 1);
+        assert_eq!(set.len(), zoom_base(MAX_ZOOM_64 + 1));
+
+        let parent = tile(3, 5, 2);
+        let mut set = TileSet::new();
+        set.insert_descendants(parent, 10).unwrap();
+        assert_eq!(set.range_count(), 8);
+        assert_eq!(set.len(), (0..8).map(|z| 1 << (2 * z)).sum::<u64>());
+        for t in set.iter() {
+            let mut t = t.decode();
+            while t.zoom > 3 {
+                t = t.parent().unwrap();
+            }
+            assert_eq!(PackedTileID64::new(t), parent);
+        }
+        assert!(set.insert_descendants(parent, MAX_ZOOM_64 + 1).is_err());
+
+        // Only the zooms 5 and 6, e.g. an area filled from zoom 5 on
+        let mut fill = TileSet::new();
+        fill.insert_descendants_at(parent, 5..=6).unwrap();
+        assert_eq!(fill.len(), 16 + 64);
+        assert!(!fill.contains(parent));
+        assert!(set
+            .iter()
+            .filter(|t| (5..=6).contains(&t.decode().zoom))
+            .all(|t| fill.contains(t)));
+        assert!(fill.insert_descendants_at(parent, 2..=6).is_err());
+    }
+
+    #[test]
+    fn test_union_intersection() {
+        let a: TileSet = (0..8).map(|x| tile(3, x, 0)).collect();
+        let b: TileSet = (0..8).map(|y| tile(3, 3, y)).collect();
+        let union = a.union(&b);
+        assert_eq!(union.len(), 15);
+        assert!((0..8).all(|i| union.contains(tile(3, i, 0)) && union.contains(tile(3, 3, i))));
+        let both = a.intersection(&b);
+        assert_eq!(both.iter().collect::<Vec<_>>(), [tile(3, 3, 0)]);
+        assert_eq!(a.union(&TileSet::new()), a);
+        assert!(a.intersection(&TileSet::new()).is_empty());
+        assert_eq!(union.intersection(&a), a);
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut set: TileSet = (0..100).map(|x| tile(12, x, 7)).collect();
+        set.insert_descendants(tile(5, 17, 9), MAX_ZOOM_64).unwrap();
+        let data = set.encode();
+        assert_eq!(TileSet::decode(&data).unwrap(), set);
+        assert!(TileSet::decode(&data[..data.len() - 1]).is_err());
+        assert_eq!(
+            TileSet::decode(&TileSet::new().encode()).unwrap(),
+            TileSet::new()
+        );
+        // Touching ranges must have been merged
+        assert!(TileSet::decode(&[2, 0, 1, 0, 1]).is_err());
+    }
+}
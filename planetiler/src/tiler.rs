@@ -0,0 +1,561 @@
+use geos::{GResult, Geom, GeometryTypes};
+
+use crate::mvt::{Coord, TileGeometry, DEFAULT_BUFFER, DEFAULT_EXTENT};
+use crate::profile::{Feature, RenderType};
+use crate::projection::project;
+use crate::tile_id::{max_dimension, PackedTileID64, TileID, MAX_ZOOM_64};
+
+/// Size of a tile in pixels, used to evaluate the minimum feature size
+const TILE_PIXELS: f64 = 256.0;
+
+/// Convert a WGS84 GEOS geometry into world-normalized coordinates.
+/// Returns None for empty geometries and unsupported types like collections.
+pub fn from_geos<'a, G: Geom<'a>>(geometry: &G) -> GResult<Option<TileGeometry>> {
+    if geometry.is_empty()? {
+        return Ok(None);
+    }
+    Ok(match geometry.geometry_type() {
+        GeometryTypes::Point => Some(TileGeometry::Point(read_coords(geometry)?)),
+        GeometryTypes::MultiPoint => {
+            let mut points = Vec::new();
+            for idx in 0..geometry.get_num_geometries()? {
+                points.extend(read_coords(&geometry.get_geometry_n(idx)?)?);
+            }
+            Some(TileGeometry::Point(points))
+        }
+        GeometryTypes::LineString | GeometryTypes::LinearRing => {
+            Some(TileGeometry::LineString(vec![read_coords(geometry)?]))
+        }
+        GeometryTypes::MultiLineString => {
+            let mut lines = Vec::new();
+            for idx in 0..geometry.get_num_geometries()? {
+                lines.push(read_coords(&geometry.get_geometry_n(idx)?)?);
+            }
+            Some(TileGeometry::LineString(lines))
+        }
+        GeometryTypes::Polygon => Some(TileGeometry::Polygon(vec![read_polygon(geometry)?])),
+        GeometryTypes::MultiPolygon => {
+            let mut polygons = Vec::new();
+            for idx in 0..geometry.get_num_geometries()? {
+                polygons.push(read_polygon(&geometry.get_geometry_n(idx)?)?);
+            }
+            Some(TileGeometry::Polygon(polygons))
+        }
+        _ => None,
+    })
+}
+
+fn read_coords<'a, G: Geom<'a>>(geometry: &G) -> GResult<Vec<Coord>> {
+    let seq = geometry.get_coord_seq()?;
+    (0..seq.size()?)
+        .map(|idx| Ok(project(seq.get_x(idx)?, seq.get_y(idx)?)))
+        .collect()
+}
+
+fn read_polygon<'a, G: Geom<'a>>(geometry: &G) -> GResult<Vec<Vec<Coord>>> {
+    let mut rings = vec![read_coords(&geometry.get_exterior_ring()?)?];
+    for idx in 0..geometry.get_num_interior_rings()? {
+        rings.push(read_coords(&geometry.get_interior_ring_n(idx as u32)?)?);
+    }
+    Ok(rings)
+}
+
+/// Axis-aligned rectangle in world-normalized coordinates
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Bounds {
+    min: Coord,
+    max: Coord,
+}
+
+impl Bounds {
+    fn area(&self) -> f64 {
+        (self.max[0] - self.min[0]) * (self.max[1] - self.min[1])
+    }
+
+    fn ring(&self) -> Vec<Coord> {
+        let ([x1, y1], [x2, y2]) = (self.min, self.max);
+        vec![[x1, y1], [x2, y1], [x2, y2], [x1, y2], [x1, y1]]
+    }
+}
+
+/// All descendants of a tile at zooms `min_zoom..=max_zoom`, completely covered by a polygon.
+/// Their geometry is just the tile square, see [`Tiler::fill_geometry`], so they are
+/// kept as a single area instead of one clipped piece per tile.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct FillArea {
+    pub tile: TileID,
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+}
+
+impl FillArea {
+    /// Number of filled tiles.
+    pub fn tile_count(&self) -> u64 {
+        (self.min_zoom..=self.max_zoom)
+            .map(|zoom| 1_u64 << (u32::from(zoom - self.tile.zoom) * 2))
+            .sum()
+    }
+
+    /// Iterate over all filled tiles without collecting them.
+    pub fn tiles(&self) -> impl Iterator<Item = TileID> {
+        let tile = self.tile;
+        (self.min_zoom..=self.max_zoom).flat_map(move |zoom| {
+            let scale = 1 << (zoom - tile.zoom);
+            (tile.x * scale..(tile.x + 1) * scale).flat_map(move |x| {
+                (tile.y * scale..(tile.y + 1) * scale).map(move |y| TileID::new(zoom, x, y))
+            })
+        })
+    }
+}
+
+/// A geometry cut into tiles: the clipped piece in each tile it touches,
+/// plus the areas where it covers whole tiles.
+#[derive(Debug, Clone, Default)]
+pub struct SlicedGeometry {
+    pub tiles: Vec<(PackedTileID64, TileGeometry)>,
+    pub fills: Vec<FillArea>,
+}
+
+impl SlicedGeometry {
+    /// Number of tiles touched by the geometry, including the filled ones.
+    pub fn tile_count(&self) -> u64 {
+        self.tiles.len() as u64 + self.fills.iter().map(FillArea::tile_count).sum::<u64>()
+    }
+}
+
+/// Cuts geometries into all tiles they intersect within a zoom range.
+#[derive(Debug, Clone)]
+pub struct Tiler {
+    min_zoom: u8,
+    max_zoom: u8,
+    /// Buffer around each tile, as a fraction of the tile size
+    buffer: f64,
+    /// Features smaller than this are not emitted at a zoom
+    min_pixel_size: f64,
+}
+
+impl Tiler {
+    pub fn new(min_zoom: u8, max_zoom: u8) -> Self {
+        assert!(min_zoom <= max_zoom && max_zoom <= MAX_ZOOM_64);
+        Self {
+            min_zoom,
+            max_zoom,
+            buffer: DEFAULT_BUFFER as f64 / DEFAULT_EXTENT as f64,
+            min_pixel_size: 0.0,
+        }
+    }
+
+    /// Tiler for the zoom range and minimum size of a feature emitted by a profile.
+    pub fn for_feature(feature: &Feature) -> Self {
+        Self::new(feature.min_zoom, feature.max_zoom).min_pixel_size(feature.min_pixel_size)
+    }
+
+    /// Buffer around each tile in tile units, assuming the given tile extent.
+    pub fn buffer(mut self, buffer: u32, extent: u32) -> Self {
+        self.buffer = buffer as f64 / extent as f64;
+        self
+    }
+
+    pub fn min_pixel_size(mut self, size: f64) -> Self {
+        self.min_pixel_size = size;
+        self
+    }
+
+    /// Clip a world-normalized geometry into every tile it touches.
+    /// Tiles are visited top-down, so each child only clips its parent's already clipped piece.
+    /// Once a polygon covers a whole tile, its descendants become a single [`FillArea`].
+    pub fn slice(&self, geometry: &TileGeometry) -> SlicedGeometry {
+        let mut result = SlicedGeometry::default();
+        let size = match geometry {
+            TileGeometry::Point(_) => f64::INFINITY,
+            _ => geometry_size(geometry),
+        };
+        self.descend(TileID::new(0, 0, 0), geometry.clone(), size, &mut result);
+        result
+    }
+
+    /// Geometry of a tile inside of a [`FillArea`]: the tile square including the buffer.
+    pub fn fill_geometry(&self, tile: TileID) -> TileGeometry {
+        TileGeometry::Polygon(vec![vec![self.bounds(tile).ring()]])
+    }
+
+    fn descend(
+        &self,
+        tile: TileID,
+        geometry: TileGeometry,
+        size: f64,
+        result: &mut SlicedGeometry,
+    ) {
+        if tile.zoom >= self.min_zoom && self.is_visible(size, tile.zoom) {
+            result
+                .tiles
+                .push((PackedTileID64::new(tile), geometry.clone()));
+        }
+        if tile.zoom >= self.max_zoom {
+            return;
+        }
+        if is_fill(&geometry, self.bounds(tile)) {
+            // Visibility only grows with the zoom, so the visible zooms are a single range
+            let min_zoom = (self.min_zoom.max(tile.zoom + 1)..=self.max_zoom)
+                .find(|&zoom| self.is_visible(size, zoom));
+            if let Some(min_zoom) = min_zoom {
+                result.fills.push(FillArea {
+                    tile,
+                    min_zoom,
+                    max_zoom: self.max_zoom,
+                });
+            }
+            return;
+        }
+        for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            let child = TileID::new(tile.zoom + 1, tile.x * 2 + dx, tile.y * 2 + dy);
+            if let Some(clipped) = clip(&geometry, self.bounds(child)) {
+                self.descend(child, clipped, size, result);
+            }
+        }
+    }
+
+    fn is_visible(&self, size: f64, zoom: u8) -> bool {
+        size * TILE_PIXELS * max_dimension(zoom) as f64 >= self.min_pixel_size
+    }
+
+    /// Tile bounds including the buffer
+    fn bounds(&self, tile: TileID) -> Bounds {
+        let scale = max_dimension(tile.zoom) as f64;
+        Bounds {
+            min: [
+                (tile.x as f64 - self.buffer) / scale,
+                (tile.y as f64 - self.buffer) / scale,
+            ],
+            max: [
+                (tile.x as f64 + 1.0 + self.buffer) / scale,
+                (tile.y as f64 + 1.0 + self.buffer) / scale,
+            ],
+        }
+    }
+}
+
+/// Slice the source geometry of a feature emitted by a profile, based on its render type.
+/// The source geometry is in WGS84, e.g. as passed to the profile.
+pub fn slice_feature<'a, G: Geom<'a>>(feature: &Feature, source: &G) -> GResult<SlicedGeometry> {
+    let geometry = match feature.render_type {
+        RenderType::Centroid => from_geos(&source.get_centroid()?)?,
+        _ => from_geos(source)?,
+    };
+    let geometry = match (feature.render_type, geometry) {
+        (RenderType::Point | RenderType::Centroid, Some(g @ TileGeometry::Point(_)))
+        | (RenderType::Line, Some(g @ TileGeometry::LineString(_)))
+        | (RenderType::Polygon, Some(g @ TileGeometry::Polygon(_))) => g,
+        // Polygon outlines can be rendered as lines
+        (RenderType::Line, Some(TileGeometry::Polygon(polygons))) => {
+            TileGeometry::LineString(polygons.into_iter().flatten().collect())
+        }
+        _ => return Ok(SlicedGeometry::default()),
+    };
+    Ok(Tiler::for_feature(feature).slice(&geometry))
+}
+
+/// Largest side of the geometry's bounding box, in world-normalized units
+fn geometry_size(geometry: &TileGeometry) -> f64 {
+    let mut min = [f64::MAX, f64::MAX];
+    let mut max = [f64::MIN, f64::MIN];
+    let mut add = |p: &Coord| {
+        min = [min[0].min(p[0]), min[1].min(p[1])];
+        max = [max[0].max(p[0]), max[1].max(p[1])];
+    };
+    match geometry {
+        TileGeometry::Point(points) => points.iter().for_each(&mut add),
+        TileGeometry::LineString(lines) => lines.iter().flatten().for_each(&mut add),
+        TileGeometry::Polygon(polygons) => polygons.iter().flatten().flatten().for_each(&mut add),
+    }
+    (max[0] - min[0]).max(max[1] - min[1])
+}
+
+/// True if the geometry is a single polygon without holes that covers the whole bounds
+fn is_fill(geometry: &TileGeometry, bounds: Bounds) -> bool {
+    match geometry {
+        TileGeometry::Polygon(polygons) if polygons.len() == 1 && polygons[0].len() == 1 => {
+            ring_area(&polygons[0][0]) >= bounds.area() * (1.0 - 1e-9)
+        }
+        _ => false,
+    }
+}
+
+fn ring_area(ring: &[Coord]) -> f64 {
+    (ring
+        .windows(2)
+        .map(|w| w[0][0] * w[1][1] - w[1][0] * w[0][1])
+        .sum::<f64>()
+        / 2.0)
+        .abs()
+}
+
+fn clip(geometry: &TileGeometry, bounds: Bounds) -> Option<TileGeometry> {
+    let result = match geometry {
+        TileGeometry::Point(points) => {
+            let points: Vec<_> = points
+                .iter()
+                .filter(|[x, y]| {
+                    bounds.min[0] <= *x
+                        && *x <= bounds.max[0]
+                        && bounds.min[1] <= *y
+                        && *y <= bounds.max[1]
+                })
+                .copied()
+                .collect();
+            if points.is_empty() {
+                return None;
+            }
+            TileGeometry::Point(points)
+        }
+        TileGeometry::LineString(lines) => {
+            let lines: Vec<_> = lines
+                .iter()
+                .flat_map(|line| clip_line(line, bounds))
+                .collect();
+            if lines.is_empty() {
+                return None;
+            }
+            TileGeometry::LineString(lines)
+        }
+        TileGeometry::Polygon(polygons) => {
+            let mut result = Vec::new();
+            for polygon in polygons {
+                let exterior = clip_ring(&polygon[0], bounds);
+                if exterior.is_empty() {
+                    continue;
+                }
+                let mut rings = vec![exterior];
+                for hole in &polygon[1..] {
+                    let hole = clip_ring(hole, bounds);
+                    if !hole.is_empty() {
+                        rings.push(hole);
+                    }
+                }
+                result.push(rings);
+            }
+            if result.is_empty() {
+                return None;
+            }
+            TileGeometry::Polygon(result)
+        }
+    };
+    Some(result)
+}
+
+/// Liang-Barsky clipping of a single segment
+fn clip_segment(a: Coord, b: Coord, bounds: Bounds) -> Option<(Coord, Coord)> {
+    let (dx, dy) = (b[0] - a[0], b[1] - a[1]);
+    let (mut t0, mut t1) = (0.0_f64, 1.0_f64);
+    for (p, q) in [
+        (-dx, a[0] - bounds.min[0]),
+        (dx, bounds.max[0] - a[0]),
+        (-dy, a[1] - bounds.min[1]),
+        (dy, bounds.max[1] - a[1]),
+    ] {
+        if p == 0.0 {
+            if q < 0.0 {
+                return None;
+            }
+        } else {
+            let r = q / p;
+            if p < 0.0 {
+                if r > t1 {
+                    return None;
+                }
+                t0 = t0.max(r);
+            } else {
+                if r < t0 {
+                    return None;
+                }
+                t1 = t1.min(r);
+            }
+        }
+    }
+    let start = if t0 > 0.0 {
+        [a[0] + t0 * dx, a[1] + t0 * dy]
+    } else {
+        a
+    };
+    let end = if t1 < 1.0 {
+        [a[0] + t1 * dx, a[1] + t1 * dy]
+    } else {
+        b
+    };
+    Some((start, end))
+}
+
+/// Clip a line, splitting it into several lines if it leaves and re-enters the bounds
+fn clip_line(line: &[Coord], bounds: Bounds) -> Vec<Vec<Coord>> {
+    let mut result = Vec::new();
+    let mut current: Vec<Coord> = Vec::new();
+    for w in line.windows(2) {
+        match clip_segment(w[0], w[1], bounds) {
+            Some((start, end)) => {
+                if current.is_empty() {
+                    current.push(start);
+                }
+                current.push(end);
+                if end != w[1] {
+                    // The segment exits the bounds
+                    result.push(std::mem::take(&mut current));
+                }
+            }
+            None => {
+                if !current.is_empty() {
+                    result.push(std::mem::take(&mut current));
+                }
+            }
+        }
+    }
+    if !current.is_empty() {
+        result.push(current);
+    }
+    result.retain(|l| l.len() >= 2);
+    result
+}
+
+/// Sutherland-Hodgman clipping of a closed ring. Returns an empty vector if nothing is left.
+fn clip_ring(ring: &[Coord], bounds: Bounds) -> Vec<Coord> {
+    let mut output: Vec<Coord> = ring.to_vec();
+    if output.len() > 1 && output.first() == output.last() {
+        output.pop();
+    }
+    // Each edge is (axis, boundary value, keep values greater than the boundary)
+    for (axis, value, keep_greater) in [
+        (0, bounds.min[0], true),
+        (0, bounds.max[0], false),
+        (1, bounds.min[1], true),
+        (1, bounds.max[1], false),
+    ] {
+        let input = std::mem::take(&mut output);
+        let inside = |p: &Coord| {
+            if keep_greater {
+                p[axis] >= value
+            } else {
+                p[axis] <= value
+            }
+        };
+        let intersect = |a: Coord, b: Coord| {
+            let t = (value - a[axis]) / (b[axis] - a[axis]);
+            let mut p = [a[0] + t * (b[0] - a[0]), a[1] + t * (b[1] - a[1])];
+            p[axis] = value;
+            p
+        };
+        let mut prev = match input.last() {
+            Some(p) => *p,
+            None => break,
+        };
+        for cur in input {
+            match (inside(&prev), inside(&cur)) {
+                (true, true) => output.push(cur),
+                (true, false) => output.push(intersect(prev, cur)),
+                (false, true) => {
+                    output.push(intersect(prev, cur));
+                    output.push(cur);
+                }
+                (false, false) => {}
+            }
+            prev = cur;
+        }
+    }
+    if output.len() < 3 {
+        return Vec::new();
+    }
+    output.push(output[0]);
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const UNIT: Bounds = Bounds {
+        min: [0.0, 0.0],
+        max: [1.0, 1.0],
+    };
+
+    #[test]
+    fn test_clip_line() {
+        // Enters, exits, and re-enters the unit square
+        let line = [[-1.0, 0.5], [0.5, 0.5], [0.5, 2.0], [0.8, 2.0], [0.8, 0.2]];
+        assert_eq!(
+            clip_line(&line, UNIT),
+            vec![
+                vec![[0.0, 0.5], [0.5, 0.5], [0.5, 1.0]],
+                vec![[0.8, 1.0], [0.8, 0.2]],
+            ]
+        );
+        assert!(clip_line(&[[2.0, 2.0], [3.0, 3.0]], UNIT).is_empty());
+    }
+
+    #[test]
+    fn test_clip_ring() {
+        let ring = [
+            [-1.0, -1.0],
+            [0.5, -1.0],
+            [0.5, 0.5],
+            [-1.0, 0.5],
+            [-1.0, -1.0],
+        ];
+        let clipped = clip_ring(&ring, UNIT);
+        assert_eq!(clipped.first(), clipped.last());
+        assert!((ring_area(&clipped) - 0.25).abs() < 1e-12);
+        let outside = [[2.0, 2.0], [3.0, 2.0], [3.0, 3.0], [2.0, 2.0]];
+        assert!(clip_ring(&outside, UNIT).is_empty());
+    }
+
+    #[test]
+    fn test_fill() {
+        // A polygon covering the whole world is filled in every tile without clipping
+        let world = TileGeometry::Polygon(vec![vec![vec![
+            [-0.1, -0.1],
+            [1.1, -0.1],
+            [1.1, 1.1],
+            [-0.1, 1.1],
+            [-0.1, -0.1],
+        ]]]);
+        let sliced = Tiler::new(1, 3).slice(&world);
+        assert!(sliced.tiles.is_empty());
+        let fill = FillArea {
+            tile: TileID::new(0, 0, 0),
+            min_zoom: 1,
+            max_zoom: 3,
+        };
+        assert_eq!(sliced.fills, [fill]);
+        assert_eq!(sliced.tile_count(), 4 + 16 + 64);
+        assert_eq!(fill.tiles().count(), 4 + 16 + 64);
+        assert!(fill.tiles().all(|t| (1..=3).contains(&t.zoom)));
+
+        // A polygon covering one z2 tile is clipped down to z2, then filled below it
+        let tiler = Tiler::new(0, 12).buffer(0, DEFAULT_EXTENT);
+        let sliced = tiler.slice(&tiler.fill_geometry(TileID::new(2, 1, 2)));
+        let fill = FillArea {
+            tile: TileID::new(2, 1, 2),
+            min_zoom: 3,
+            max_zoom: 12,
+        };
+        assert!(sliced.fills.contains(&fill));
+        assert_eq!(
+            fill.tile_count(),
+            (1..=10).map(|z| 1 << (2 * z)).sum::<u64>()
+        );
+    }
+
+    #[test]
+    fn test_slice_line() {
+        // Horizontal line through the middle of the world touches both top and bottom tiles at z1
+        let line = TileGeometry::LineString(vec![vec![project(-170.0, 0.0), project(-100.0, 0.0)]]);
+        let tiles: Vec<_> = Tiler::new(1, 1)
+            .slice(&line)
+            .tiles
+            .into_iter()
+            .map(|(id, _)| id.decode())
+            .collect();
+        assert_eq!(tiles, vec![TileID::new(1, 0, 0), TileID::new(1, 0, 1)]);
+
+        // The line is only about 50 pixels long at z0, so it is dropped there
+        let tiles = Tiler::new(0, 2).min_pixel_size(100.0).slice(&line).tiles;
+        assert!(tiles.iter().all(|(id, _)| id.decode().zoom > 0));
+    }
+}
@@ -0,0 +1,72 @@
+use anyhow::{bail, Result};
+
+/// Append `value` to `buf` as a LEB128 varint, same as protobuf.
+pub fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Read a LEB128 varint from `buf` starting at `pos`, and move `pos` past it.
+pub fn read_varint(buf: &[u8], pos: &mut usize) -> Result<u64> {
+    let mut value = 0_u64;
+    for shift in (0..64).step_by(7) {
+        let byte = match buf.get(*pos) {
+            Some(&byte) => byte,
+            None => bail!("Unexpected end of data while reading a varint at {pos}"),
+        };
+        *pos += 1;
+        value |= u64::from(byte & 0x7F) << shift;
+        if byte < 0x80 {
+            return Ok(value);
+        }
+    }
+    bail!("Varint ending at {pos} is too long")
+}
+
+/// Read a little endian u64 from the first 8 bytes of `data`, which must not be shorter.
+pub fn read_u64(data: &[u8]) -> u64 {
+    u64::from_le_bytes(data[..8].try_into().unwrap())
+}
+
+/// Map signed values to unsigned so that small negative numbers stay small.
+pub fn zigzag_encode(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+pub fn zigzag_decode(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_varint() {
+        let values = [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX];
+        let mut buf = Vec::new();
+        for value in values {
+            write_varint(&mut buf, value);
+        }
+        assert_eq!(buf[..5], [0, 1, 127, 0x80, 1]);
+        let mut pos = 0;
+        for value in values {
+            assert_eq!(read_varint(&buf, &mut pos).unwrap(), value);
+        }
+        assert_eq!(pos, buf.len());
+        assert!(read_varint(&buf, &mut pos).is_err());
+        assert!(read_varint(&[0x80, 0x80], &mut 0).is_err());
+    }
+
+    #[test]
+    fn test_zigzag() {
+        for (value, encoded) in [(0, 0), (-1, 1), (1, 2), (-2, 3), (i64::MAX, u64::MAX - 1)] {
+            assert_eq!(zigzag_encode(value), encoded);
+            assert_eq!(zigzag_decode(encoded), value);
+        }
+        assert_eq!(zigzag_decode(zigzag_encode(i64::MIN)), i64::MIN);
+    }
+}
@@ -0,0 +1,343 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::remove_file;
+use std::path::{Path, PathBuf};
+
+use anyhow::{ensure, Context, Result};
+
+use crate::block_file::{BlockFileReader, BlockFileWriter};
+use crate::varint::{read_varint, write_varint, zigzag_decode, zigzag_encode};
+
+/// First bytes of a way parts file.
+pub const WAY_PARTS_MAGIC: &[u8; 8] = b"PTWAYPT1";
+
+/// The nodes of a way that were resolved in one pass of a multi-pass resolver,
+/// when the rest of its nodes belong to the node ID ranges of other passes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WayPart {
+    pub way_id: i64,
+    /// Number of nodes in the complete way
+    pub node_count: u32,
+    /// Position in the way and [lon, lat] of each resolved node, ordered by position
+    pub nodes: Vec<(u32, [f64; 2])>,
+}
+
+/// Encode parts sorted by way ID. The block starts with the part count, followed by
+/// the varint delta of each way ID (first one is relative to itself), the node count,
+/// the number of resolved nodes, and for each node the varint delta of its position,
+/// and zigzag varint deltas of longitude and latitude in 1e-7 degrees.
+fn encode_block(parts: &[WayPart]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    write_varint(&mut buf, parts.len() as u64);
+    let (mut last_id, mut last_lon, mut last_lat) = (parts[0].way_id, 0_i64, 0_i64);
+    for part in parts {
+        write_varint(&mut buf, (part.way_id - last_id) as u64);
+        write_varint(&mut buf, u64::from(part.node_count));
+        write_varint(&mut buf, part.nodes.len() as u64);
+        let mut last_pos = 0;
+        for &(pos, [lon, lat]) in &part.nodes {
+            let (lon, lat) = ((lon * 1e7).round() as i64, (lat * 1e7).round() as i64);
+            write_varint(&mut buf, u64::from(pos - last_pos));
+            write_varint(&mut buf, zigzag_encode(lon - last_lon));
+            write_varint(&mut buf, zigzag_encode(lat - last_lat));
+            last_pos = pos;
+            last_lon = lon;
+            last_lat = lat;
+        }
+        last_id = part.way_id;
+    }
+    buf
+}
+
+fn decode_block(first_id: i64, data: &[u8]) -> Result<Vec<WayPart>> {
+    let mut pos = 0;
+    let count = read_varint(data, &mut pos)? as usize;
+    let mut parts = Vec::with_capacity(count);
+    let (mut way_id, mut lon, mut lat) = (first_id, 0_i64, 0_i64);
+    for _ in 0..count {
+        way_id += read_varint(data, &mut pos)? as i64;
+        let node_count = read_varint(data, &mut pos)? as u32;
+        let len = read_varint(data, &mut pos)? as usize;
+        let mut nodes = Vec::with_capacity(len);
+        let mut node_pos = 0_u32;
+        for _ in 0..len {
+            node_pos += read_varint(data, &mut pos)? as u32;
+            lon += zigzag_decode(read_varint(data, &mut pos)?);
+            lat += zigzag_decode(read_varint(data, &mut pos)?);
+            ensure!(node_pos < node_count, "Node position is out of range");
+            nodes.push((node_pos, [lon as f64 / 1e7, lat as f64 / 1e7]));
+        }
+        parts.push(WayPart {
+            way_id,
+            node_count,
+            nodes,
+        });
+    }
+    ensure!(
+        pos == data.len(),
+        "Unexpected data at the end of a way parts block"
+    );
+    Ok(parts)
+}
+
+/// Writes the way parts of one pass, one block per call of [`WayPartsWriter::add_block`].
+/// See [`BlockFileWriter`] for the file layout.
+pub struct WayPartsWriter {
+    writer: BlockFileWriter,
+}
+
+impl WayPartsWriter {
+    pub fn create(path: &Path) -> Result<Self> {
+        Ok(Self {
+            writer: BlockFileWriter::create(path, WAY_PARTS_MAGIC)
+                .context("Unable to create way parts file")?,
+        })
+    }
+
+    /// Add the parts of the ways from one PBF blob.
+    /// Blocks may come from multiple threads in any order, but their way ID ranges must not overlap.
+    /// Way IDs must not be negative, e.g. the IDs of unsaved ways of an editor.
+    pub fn add_block(&self, mut parts: Vec<WayPart>) -> Result<()> {
+        if parts.is_empty() {
+            return Ok(());
+        }
+        parts.sort_unstable_by_key(|p| p.way_id);
+        ensure!(
+            parts[0].way_id >= 0,
+            "Way parts of negative way ID {} are not supported",
+            parts[0].way_id
+        );
+        let data = encode_block(&parts);
+        self.writer.add_block(
+            parts[0].way_id as u64,
+            parts[parts.len() - 1].way_id as u64,
+            &data,
+        )
+    }
+
+    /// Write the block index and close the file. Returns the number of blocks.
+    pub fn finish(self) -> Result<usize> {
+        self.writer
+            .finish("Way parts overlap, the PBF file is not sorted")
+            .context("Unable to finish way parts")
+    }
+}
+
+/// Reads the way parts of one pass, ordered by way ID.
+pub struct WayPartsReader {
+    blocks: BlockFileReader,
+    next_block: usize,
+    parts: std::vec::IntoIter<WayPart>,
+}
+
+impl WayPartsReader {
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            blocks: BlockFileReader::open(path, WAY_PARTS_MAGIC)
+                .with_context(|| format!("Unable to open way parts {}", path.display()))?,
+            next_block: 0,
+            parts: Vec::new().into_iter(),
+        })
+    }
+
+    fn next_part(&mut self) -> Result<Option<WayPart>> {
+        loop {
+            if let Some(part) = self.parts.next() {
+                return Ok(Some(part));
+            }
+            if self.next_block >= self.blocks.len() {
+                return Ok(None);
+            }
+            let info = self.blocks.index()[self.next_block];
+            let data = self.blocks.read(self.next_block)?;
+            self.parts = decode_block(info.first_id as i64, &data)
+                .with_context(|| format!("Unable to decode way parts at offset {}", info.offset))?
+                .into_iter();
+            self.next_block += 1;
+        }
+    }
+}
+
+impl Iterator for WayPartsReader {
+    type Item = Result<WayPart>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_part().transpose()
+    }
+}
+
+/// A way put together from the parts resolved by all passes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MergedWay {
+    pub way_id: i64,
+    /// [lon, lat] of each node, None if no pass could resolve it
+    pub nodes: Vec<Option<[f64; 2]>>,
+}
+
+/// K-way merge of the way parts written by each pass, yielding complete ways ordered
+/// by way ID. The way parts files are deleted when this is dropped.
+pub struct MergedWays {
+    readers: Vec<WayPartsReader>,
+    /// The next part of each reader
+    pending: Vec<Option<WayPart>>,
+    heap: BinaryHeap<Reverse<(i64, usize)>>,
+    files: Vec<PathBuf>,
+}
+
+impl MergedWays {
+    pub fn open(files: Vec<PathBuf>) -> Result<Self> {
+        let mut merged = Self {
+            readers: Vec::with_capacity(files.len()),
+            pending: Vec::with_capacity(files.len()),
+            heap: BinaryHeap::new(),
+            files,
+        };
+        for (idx, path) in merged.files.iter().enumerate() {
+            let mut reader = WayPartsReader::open(path)?;
+            let part = reader.next_part()?;
+            if let Some(p) = &part {
+                merged.heap.push(Reverse((p.way_id, idx)));
+            }
+            merged.readers.push(reader);
+            merged.pending.push(part);
+        }
+        Ok(merged)
+    }
+
+    /// Take the pending part of a reader and queue its next one.
+    fn take(&mut self, idx: usize) -> Result<WayPart> {
+        let part = self.pending[idx].take().unwrap();
+        if let Some(next) = self.readers[idx].next_part()? {
+            ensure!(
+                next.way_id > part.way_id,
+                "Way parts of way {} are not sorted",
+                next.way_id
+            );
+            self.heap.push(Reverse((next.way_id, idx)));
+            self.pending[idx] = Some(next);
+        }
+        Ok(part)
+    }
+
+    fn next_way(&mut self) -> Result<Option<MergedWay>> {
+        let Reverse((way_id, idx)) = match self.heap.pop() {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let part = self.take(idx)?;
+        let mut nodes = vec![None; part.node_count as usize];
+        let mut add = |part: WayPart| -> Result<()> {
+            ensure!(
+                part.node_count as usize == nodes.len(),
+                "Way {way_id} has a different node count in each pass"
+            );
+            for (pos, coord) in part.nodes {
+                nodes[pos as usize] = Some(coord);
+            }
+            Ok(())
+        };
+        add(part)?;
+        while let Some(&Reverse((next_id, idx))) = self.heap.peek() {
+            if next_id != way_id {
+                break;
+            }
+            self.heap.pop();
+            add(self.take(idx)?)?;
+        }
+        Ok(Some(MergedWay { way_id, nodes }))
+    }
+}
+
+impl Iterator for MergedWays {
+    type Item = Result<MergedWay>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_way() {
+            Ok(way) => way.map(Ok),
+            Err(e) => {
+                self.heap.clear();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl Drop for MergedWays {
+    fn drop(&mut self) {
+        // Close the files before removing them
+        self.readers.clear();
+        for path in &self.files {
+            let _ = remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn part(way_id: i64, node_count: u32, positions: &[u32]) -> WayPart {
+        WayPart {
+            way_id,
+            node_count,
+            nodes: positions
+                .iter()
+                .map(|&pos| (pos, [f64::from(pos) + 0.5, -(way_id as f64) / 10.0]))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_block_round_trip() {
+        let parts = vec![part(7, 3, &[0, 2]), part(9, 5, &[1]), part(100, 2, &[])];
+        let data = encode_block(&parts);
+        assert_eq!(decode_block(7, &data).unwrap(), parts);
+        assert!(decode_block(7, &data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        let dir = tempdir().unwrap();
+        let files: Vec<_> = (0..2)
+            .map(|pass| dir.path().join(format!("pass-{pass}.bin")))
+            .collect();
+        let first = WayPartsWriter::create(&files[0]).unwrap();
+        // Blocks are added out of order, the way parallel readers produce them
+        first.add_block(vec![part(20, 2, &[1])]).unwrap();
+        first.add_block(vec![part(5, 3, &[0])]).unwrap();
+        assert_eq!(first.finish().unwrap(), 2);
+        let second = WayPartsWriter::create(&files[1]).unwrap();
+        second
+            .add_block(vec![part(12, 1, &[0]), part(5, 3, &[2])])
+            .unwrap();
+        assert_eq!(second.finish().unwrap(), 1);
+        let negative = WayPartsWriter::create(&dir.path().join("negative.bin")).unwrap();
+        assert!(negative.add_block(vec![part(-3, 2, &[0])]).is_err());
+
+        let ways: Vec<_> = MergedWays::open(files.clone())
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        let coord = |way_id: i64, pos: u32| Some([f64::from(pos) + 0.5, -(way_id as f64) / 10.0]);
+        assert_eq!(
+            ways,
+            vec![
+                MergedWay {
+                    way_id: 5,
+                    nodes: vec![coord(5, 0), None, coord(5, 2)],
+                },
+                MergedWay {
+                    way_id: 12,
+                    nodes: vec![coord(12, 0)],
+                },
+                MergedWay {
+                    way_id: 20,
+                    nodes: vec![None, coord(20, 1)],
+                },
+            ]
+        );
+        assert!(files.iter().all(|path| !path.exists()));
+    }
+}
@@ -0,0 +1,406 @@
+use std::ops::AddAssign;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::Result;
+use osmnodecache::Advice;
+use osmpbf::{Blob, BlobReader, ByteOffset, PrimitiveBlock, Way};
+use rayon::iter::{ParallelBridge, ParallelIterator};
+
+use crate::blob_index::read_blobs_at;
+use crate::cache_meta::NodeCacheMeta;
+use crate::node_cache::{open_cache, NodeLookup};
+use crate::pbf::decode_data_block_or_skip;
+use crate::profile::Tags;
+
+/// Number of way IDs with missing nodes kept as samples in [`MissingNodeStats`].
+pub const MAX_MISSING_SAMPLES: usize = 10;
+
+/// A way with its nodes resolved via the node cache.
+pub struct ResolvedWay<'a> {
+    pub id: i64,
+    pub tags: Tags<'a>,
+    /// (lon, lat) of each node, in the way order
+    pub coords: Vec<[f64; 2]>,
+    /// Number of nodes that were not in the cache, see [`MissingNodes`]
+    pub missing_nodes: usize,
+}
+
+/// What to do with ways that use nodes missing from the node cache,
+/// e.g. ways crossing the boundary of an extract clipped by a bounding box.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MissingNodes {
+    /// Keep the way, resolving missing nodes to (0, 0)
+    Keep,
+    /// Skip the whole way
+    Drop,
+    /// Remove missing nodes from the way, and skip it if less than two nodes remain
+    Truncate,
+}
+
+/// Coordinates of a way, resolved according to a [`MissingNodes`] policy.
+pub struct WayCoords {
+    /// (lon, lat) of each node, in the way order, or None if the way should be skipped
+    pub coords: Option<Vec<[f64; 2]>>,
+    /// Number of nodes that were not in the cache
+    pub missing: usize,
+}
+
+impl MissingNodes {
+    /// Resolve node IDs of a way into (lon, lat) coordinates.
+    pub fn resolve(self, cache: &dyn NodeLookup, refs: impl Iterator<Item = i64>) -> WayCoords {
+        self.apply(refs.map(|id| cache.get_node(id).map(|(lat, lng)| [lng, lat])))
+    }
+
+    /// Apply the policy to (lon, lat) coordinates of a way, None for each missing node.
+    pub fn apply(self, nodes: impl Iterator<Item = Option<[f64; 2]>>) -> WayCoords {
+        let mut coords = Vec::with_capacity(nodes.size_hint().0);
+        let (mut found, mut missing) = (0, 0);
+        for node in nodes {
+            match node {
+                Some(coord) => {
+                    found += 1;
+                    coords.push(coord);
+                }
+                None => {
+                    missing += 1;
+                    if self == MissingNodes::Keep {
+                        coords.push([0.0, 0.0]);
+                    }
+                }
+            }
+        }
+        WayCoords {
+            coords: if self.skips(found, missing) {
+                None
+            } else {
+                Some(coords)
+            },
+            missing,
+        }
+    }
+
+    /// Whether a way with `found` nodes in the cache and `missing` nodes not in it is skipped.
+    pub fn skips(self, found: usize, missing: usize) -> bool {
+        missing > 0
+            && match self {
+                MissingNodes::Keep => false,
+                MissingNodes::Drop => true,
+                MissingNodes::Truncate => found < 2,
+            }
+    }
+}
+
+/// Counts of ways with nodes missing from the node cache.
+#[derive(Clone, Debug, Default)]
+pub struct MissingNodeStats {
+    /// Ways with at least one missing node
+    pub incomplete_ways: usize,
+    /// Incomplete ways that were skipped
+    pub skipped_ways: usize,
+    /// Some of the incomplete way IDs
+    pub samples: Vec<i64>,
+}
+
+impl MissingNodeStats {
+    /// Count the way if it has missing nodes.
+    pub fn add_way(&mut self, way_id: i64, missing: usize, skipped: bool) {
+        if missing > 0 {
+            self.incomplete_ways += 1;
+            if skipped {
+                self.skipped_ways += 1;
+            }
+            if self.samples.len() < MAX_MISSING_SAMPLES {
+                self.samples.push(way_id);
+            }
+        }
+    }
+}
+
+impl AddAssign for MissingNodeStats {
+    fn add_assign(&mut self, other: Self) {
+        self.incomplete_ways += other.incomplete_ways;
+        self.skipped_ways += other.skipped_ways;
+        let room = MAX_MISSING_SAMPLES.saturating_sub(self.samples.len());
+        self.samples.extend(other.samples.into_iter().take(room));
+    }
+}
+
+/// Totals of a [`WayReader`] run.
+#[derive(Clone, Debug, Default)]
+pub struct WayReaderStats {
+    /// Blobs that could not be decoded and were skipped
+    pub bad_blobs: usize,
+    pub missing: MissingNodeStats,
+}
+
+impl AddAssign for WayReaderStats {
+    fn add_assign(&mut self, other: Self) {
+        self.bad_blobs += other.bad_blobs;
+        self.missing += other.missing;
+    }
+}
+
+/// The ways of one decoded blob, passed to [`WayReader::for_each_blob_with`], resolving them
+/// with the node cache and the policies of the reader.
+pub struct WayBlob<'a> {
+    /// Byte offset of the blob in the PBF file, if known
+    pub offset: Option<u64>,
+    block: &'a PrimitiveBlock,
+    cache: &'a dyn NodeLookup,
+    reader: &'a WayReader,
+    stats: &'a mut WayReaderStats,
+}
+
+impl<'a> WayBlob<'a> {
+    /// All ways of the blob, including the untagged ones.
+    pub fn ways(&self) -> impl Iterator<Item = Way<'a>> {
+        self.block.groups().flat_map(|group| group.ways())
+    }
+
+    /// Node cache, e.g. to resolve only some of the nodes of a way.
+    pub fn cache(&self) -> &'a dyn NodeLookup {
+        self.cache
+    }
+
+    /// Resolve the nodes of a way and count its missing nodes. None if the way is skipped,
+    /// because it is untagged and [`WayReader::skip_untagged`] is set,
+    /// or because of its missing nodes, see [`WayReader::missing_nodes`].
+    pub fn resolve(&mut self, way: &Way<'a>) -> Option<ResolvedWay<'a>> {
+        let tags = Tags::new(way.tags());
+        if self.reader.skip_untagged && tags.is_empty() {
+            return None;
+        }
+        let resolved = self.reader.missing_nodes.resolve(self.cache, way.refs());
+        self.stats
+            .missing
+            .add_way(way.id(), resolved.missing, resolved.coords.is_none());
+        Some(ResolvedWay {
+            id: way.id(),
+            tags,
+            coords: resolved.coords?,
+            missing_nodes: resolved.missing,
+        })
+    }
+
+    /// Count a way whose nodes were looked up by the caller, e.g. without collecting them,
+    /// and return whether the [`MissingNodes`] policy skips it.
+    pub fn count_way(&mut self, way_id: i64, found: usize, missing: usize) -> bool {
+        let skipped = self.reader.missing_nodes.skips(found, missing);
+        self.stats.missing.add_way(way_id, missing, skipped);
+        skipped
+    }
+}
+
+/// Reads all ways of a PBF file in parallel, resolving node positions with a node cache
+/// created by [`crate::node_cache::NodeCacheBuilder`].
+///
+/// ```no_run
+/// use planetiler::node_cache::NodeCacheBuilder;
+/// use planetiler::ways::WayReader;
+///
+/// NodeCacheBuilder::new("planet.osm.pbf", "nodes.cache").build()?;
+/// // Node blocks are skipped using the offset stored in the node cache metadata
+/// WayReader::new("planet.osm.pbf", "nodes.cache")
+///     .for_each(|way| println!("{} has {} nodes", way.id, way.coords.len()))?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct WayReader {
+    pbf_file: PathBuf,
+    cache_file: PathBuf,
+    start_offset: Option<u64>,
+    blobs: Option<Vec<u64>>,
+    skip_untagged: bool,
+    skip_bad_blobs: bool,
+    missing_nodes: MissingNodes,
+    advice: Vec<Advice>,
+}
+
+impl WayReader {
+    pub fn new(pbf_file: impl Into<PathBuf>, cache_file: impl Into<PathBuf>) -> Self {
+        Self {
+            pbf_file: pbf_file.into(),
+            cache_file: cache_file.into(),
+            start_offset: None,
+            blobs: None,
+            skip_untagged: false,
+            skip_bad_blobs: false,
+            missing_nodes: MissingNodes::Drop,
+            advice: vec![Advice::Random],
+        }
+    }
+
+    /// Skip to this offset before reading, e.g. the first way block found while caching nodes.
+    /// By default, the offset stored in the [`NodeCacheMeta`] of the node cache is used.
+    pub fn start_offset(mut self, offset: u64) -> Self {
+        self.start_offset = Some(offset);
+        self
+    }
+
+    /// Only read the blobs at these offsets, e.g. the blobs with ways of a
+    /// [`crate::blob_index::BlobIndex`]. The start offset is not used then.
+    pub fn blobs(mut self, offsets: Vec<u64>) -> Self {
+        self.blobs = Some(offsets);
+        self
+    }
+
+    /// Do not resolve ways without tags, e.g. ways that only exist as relation members.
+    pub fn skip_untagged(mut self, skip: bool) -> Self {
+        self.skip_untagged = skip;
+        self
+    }
+
+    /// Skip blobs that cannot be decoded instead of failing.
+    pub fn skip_bad_blobs(mut self, skip: bool) -> Self {
+        self.skip_bad_blobs = skip;
+        self
+    }
+
+    /// How to handle ways with nodes missing from the cache, [`MissingNodes::Drop`] by default.
+    pub fn missing_nodes(mut self, missing_nodes: MissingNodes) -> Self {
+        self.missing_nodes = missing_nodes;
+        self
+    }
+
+    /// Replace the default random access memmap advice of the node cache.
+    pub fn advice(mut self, advice: Vec<Advice>) -> Self {
+        self.advice = advice;
+        self
+    }
+
+    /// Call `op` for every way. Ways are processed on multiple threads in no particular order.
+    pub fn for_each<F>(&self, op: F) -> Result<WayReaderStats>
+    where
+        F: Fn(ResolvedWay) + Sync + Send,
+    {
+        self.for_each_with((), |_, way| op(way))
+    }
+
+    /// Call `op` for every way, with a per-thread copy of `init`, e.g. a channel sender.
+    /// Fails if the metadata of the node cache shows it was built from a different PBF file.
+    pub fn for_each_with<T, F>(&self, init: T, op: F) -> Result<WayReaderStats>
+    where
+        T: Send + Clone,
+        F: Fn(&mut T, ResolvedWay) + Sync + Send,
+    {
+        self.for_each_blob_with(init, |state, blob| {
+            for way in blob.ways() {
+                if let Some(way) = blob.resolve(&way) {
+                    op(state, way);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Call `op` for every blob with ways, with a per-thread copy of `init`, e.g. to collect
+    /// statistics per blob or to handle some of the ways differently. Ways are only resolved
+    /// when `op` calls [`WayBlob::resolve`], and the first error of `op` stops the reader.
+    pub fn for_each_blob_with<T, F>(&self, init: T, op: F) -> Result<WayReaderStats>
+    where
+        T: Send + Clone,
+        F: Fn(&mut T, &mut WayBlob) -> Result<()> + Sync + Send,
+    {
+        let meta = NodeCacheMeta::read_for(&self.cache_file, &self.pbf_file)?;
+        let blobs: Box<dyn Iterator<Item = osmpbf::Result<Blob>> + Send> = match &self.blobs {
+            Some(offsets) => Box::new(read_blobs_at(&self.pbf_file, offsets.clone())?),
+            None => {
+                let start_offset = match (self.start_offset, meta) {
+                    (Some(offset), _) => offset,
+                    (None, Some(meta)) => meta.first_way_block,
+                    (None, None) => 0,
+                };
+                if start_offset == u64::MAX {
+                    // The file has no ways
+                    return Ok(WayReaderStats::default());
+                }
+                let mut reader = BlobReader::from_path(&self.pbf_file)?;
+                if start_offset > 0 {
+                    reader.seek(ByteOffset(start_offset))?;
+                }
+                Box::new(reader)
+            }
+        };
+        let cache = open_cache(self.cache_file.clone(), &self.advice)?;
+
+        let totals = Mutex::new(WayReaderStats::default());
+        blobs.par_bridge().try_for_each_with(
+            (cache, init),
+            |(dfc, state), blob| -> Result<()> {
+                let cache = dfc.reader();
+                let mut stats = WayReaderStats::default();
+                let data =
+                    decode_data_block_or_skip(blob, self.skip_bad_blobs, &mut stats.bad_blobs)?;
+                let result = match data {
+                    Some(data) => op(
+                        state,
+                        &mut WayBlob {
+                            offset: data.offset,
+                            block: &data.block,
+                            cache: &*cache,
+                            reader: self,
+                            stats: &mut stats,
+                        },
+                    ),
+                    None => Ok(()),
+                };
+                *totals.lock().unwrap() += stats;
+                result
+            },
+        )?;
+        Ok(totals.into_inner().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::sparse_cache::{pack_lat_lon, SparseFileCache};
+
+    #[test]
+    fn test_missing_nodes() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.cache");
+        let nodes = [(1, pack_lat_lon(1.0, 2.0)), (2, pack_lat_lon(3.0, 4.0))];
+        SparseFileCache::write(&path, nodes.iter().copied()).unwrap();
+        let cache = SparseFileCache::open(&path).unwrap();
+        let resolve = |policy: MissingNodes, refs: &[i64]| {
+            let way = policy.resolve(&cache, refs.iter().copied());
+            (way.coords, way.missing)
+        };
+
+        let complete = vec![[2.0, 1.0], [4.0, 3.0]];
+        for policy in [
+            MissingNodes::Keep,
+            MissingNodes::Drop,
+            MissingNodes::Truncate,
+        ] {
+            assert_eq!(resolve(policy, &[1, 2]), (Some(complete.clone()), 0));
+        }
+        let keep = vec![[2.0, 1.0], [0.0, 0.0], [4.0, 3.0]];
+        assert_eq!(resolve(MissingNodes::Keep, &[1, 3, 2]), (Some(keep), 1));
+        assert_eq!(resolve(MissingNodes::Drop, &[1, 3, 2]), (None, 1));
+        assert_eq!(
+            resolve(MissingNodes::Truncate, &[1, 3, 2]),
+            (Some(complete), 1)
+        );
+        assert_eq!(resolve(MissingNodes::Truncate, &[1, 3, 4]), (None, 2));
+        assert!(!MissingNodes::Keep.skips(0, 3));
+        assert!(MissingNodes::Drop.skips(5, 1));
+        assert!(!MissingNodes::Drop.skips(5, 0));
+        assert!(MissingNodes::Truncate.skips(1, 1));
+        assert!(!MissingNodes::Truncate.skips(2, 1));
+
+        let mut stats = MissingNodeStats::default();
+        for id in 0..MAX_MISSING_SAMPLES as i64 + 2 {
+            stats.add_way(id, 1, id % 2 == 0);
+        }
+        stats.add_way(100, 0, false);
+        assert_eq!(stats.incomplete_ways, MAX_MISSING_SAMPLES + 2);
+        assert_eq!(stats.skipped_ways, MAX_MISSING_SAMPLES / 2 + 1);
+        assert_eq!(stats.samples.len(), MAX_MISSING_SAMPLES);
+    }
+}